@@ -0,0 +1,538 @@
+use crate::args::{self, Config, Method};
+use crate::create_protocol;
+use crate::dns::try_hostname_to_ip;
+use crate::packet_io::PacketIo;
+use crate::traceroute::{trace_with_progress, Trace};
+use log::{info, warn};
+use pnet::transport::TransportChannelType;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Cursor, Read, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{HTTPVersion, Header, Request, Response, Server};
+
+/// Largest accepted request body.
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+/// Number of traces kept for `GET /traces/{id}`, the oldest finished ones are dropped first.
+const MAX_KEPT_TRACES: usize = 1000;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Opens the packet I/O for a requested trace.
+pub type OpenIo =
+    dyn Fn(TransportChannelType, TransportChannelType) -> Box<dyn PacketIo> + Send + Sync;
+
+/// Body of `POST /traces`, unset options fall back to the server's command line.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TraceRequest {
+    target: String,
+    method: Option<String>,
+    /// IP protocol number of raw probes, instead of a method.
+    protocol: Option<u8>,
+    max_hops: Option<u8>,
+    tries: Option<u8>,
+    wait_secs: Option<f64>,
+    port: Option<u16>,
+    first_hop: Option<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize)]
+struct JobState {
+    id: u64,
+    status: Status,
+    #[serde(flatten)]
+    trace: Trace,
+}
+
+struct Job {
+    state: Mutex<JobState>,
+    changed: Condvar,
+}
+
+impl Job {
+    fn update(&self, update: impl FnOnce(&mut JobState)) {
+        update(&mut self.state.lock().unwrap());
+        self.changed.notify_all();
+    }
+}
+
+/// Publishes the result of a job once tracing ends, marking it as failed without a result,
+/// e.g. on a panic.
+struct RunningJob<'a> {
+    api: &'a Api,
+    job: &'a Job,
+    result: Option<Trace>,
+}
+
+impl Drop for RunningJob<'_> {
+    fn drop(&mut self) {
+        // free the slot first, so clients seeing the result can start the next trace
+        self.api.running.fetch_sub(1, Ordering::SeqCst);
+
+        let result = self.result.take();
+        self.job.update(|state| match result {
+            Some(trace) => {
+                state.trace = trace;
+                state.status = Status::Done;
+            }
+            None => state.status = Status::Failed,
+        });
+    }
+}
+
+/// HTTP API which runs traces on request and keeps their results.
+pub struct Api {
+    config: Config,
+    open_io: Box<OpenIo>,
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+    next_id: AtomicU64,
+    running: AtomicUsize,
+    requests_by_client: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+}
+
+impl Api {
+    pub fn new(config: Config, open_io: Box<OpenIo>) -> Self {
+        Api {
+            config,
+            open_io,
+            jobs: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(1),
+            running: AtomicUsize::new(0),
+            requests_by_client: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Answers requests until the server fails, each one on its own thread.
+    pub fn serve(self: Arc<Self>, server: Server) {
+        info!("Serving API on {}", server.server_addr());
+
+        for request in server.incoming_requests() {
+            let api = Arc::clone(&self);
+            thread::spawn(move || api.handle(request));
+        }
+    }
+
+    fn handle(self: &Arc<Self>, mut request: Request) {
+        let url = request.url().to_string();
+        let path = url.split('?').next().unwrap_or_default();
+        let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+        let response = match (request.method(), segments.as_slice()) {
+            (tiny_http::Method::Post, ["traces"]) => self.start_trace(&mut request),
+            (tiny_http::Method::Get, ["traces", id]) => match self.find_job(id) {
+                Some(job) => json_response(200, &*job.state.lock().unwrap()),
+                None => error_response(404, "no such trace"),
+            },
+            (tiny_http::Method::Get, ["traces", id, "events"]) => match self.find_job(id) {
+                Some(job) => return stream_events(request, &job),
+                None => error_response(404, "no such trace"),
+            },
+            _ => error_response(404, "not found"),
+        };
+
+        if let Err(e) = request.respond(response) {
+            warn!("Could not answer API request: {}", e);
+        }
+    }
+
+    fn start_trace(self: &Arc<Self>, request: &mut Request) -> Response<Cursor<Vec<u8>>> {
+        let client = request.remote_addr().map(|addr| addr.ip());
+        if !client.is_some_and(|client| self.allow_request(client)) {
+            return error_response(429, "too many requests").with_header(retry_after());
+        }
+
+        let mut body = String::new();
+        let read = request
+            .as_reader()
+            .take(MAX_BODY_BYTES)
+            .read_to_string(&mut body);
+        if let Err(e) = read {
+            return error_response(400, &format!("could not read request: {}", e));
+        }
+        let trace_request = match serde_json::from_str::<TraceRequest>(&body) {
+            Ok(trace_request) => trace_request,
+            Err(e) => return error_response(400, &format!("invalid request: {}", e)),
+        };
+        let config = match self.job_config(trace_request) {
+            Ok(config) => config,
+            Err(message) => return error_response(400, &message),
+        };
+
+        let started = self
+            .running
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| {
+                (running < self.config.max_traces).then_some(running + 1)
+            });
+        if started.is_err() {
+            return error_response(503, "too many traces running").with_header(retry_after());
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let job = Arc::new(Job {
+            state: Mutex::new(JobState {
+                id,
+                status: Status::Running,
+                trace: Trace::new(&config),
+            }),
+            changed: Condvar::new(),
+        });
+        self.keep_job(id, Arc::clone(&job));
+        info!("Tracing {} as trace {}", config.host, id);
+
+        let api = Arc::clone(self);
+        let running_job = Arc::clone(&job);
+        thread::spawn(move || api.run_job(&running_job, &config));
+
+        let location = Header::from_bytes("Location", format!("/traces/{}", id)).unwrap();
+        let response = json_response(202, &*job.state.lock().unwrap());
+        response.with_header(location)
+    }
+
+    /// Counts a trace request of `client`, false if it exceeds the rate limit.
+    fn allow_request(&self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut requests_by_client = self.requests_by_client.lock().unwrap();
+        requests_by_client.retain(|_, requests| {
+            requests.retain(|time| now - *time < RATE_LIMIT_WINDOW);
+            !requests.is_empty()
+        });
+
+        let requests = requests_by_client.entry(client).or_default();
+        if requests.len() >= self.config.rate_limit {
+            return false;
+        }
+        requests.push(now);
+        true
+    }
+
+    fn job_config(&self, request: TraceRequest) -> Result<Config, String> {
+        let mut config = self.config.clone();
+
+        match try_hostname_to_ip(&request.target) {
+            // later lookups of the name could fail or differ, so trace the address
+            Some(IpAddr::V4(addr)) => config.host = addr.to_string(),
+            _ => return Err(format!("'{}' is no resolvable IPv4 host", request.target)),
+        }
+        if request.method.is_some() && request.protocol.is_some() {
+            return Err("only one of method and protocol may be given".to_string());
+        }
+        if let Some(method) = request.method {
+            config.method = Method::parse(&method)?;
+        }
+        if let Some(protocol) = request.protocol {
+            config.method = Method::Raw(protocol);
+        }
+        config.hops = request.max_hops.unwrap_or(config.hops);
+        config.tries = request.tries.unwrap_or(config.tries);
+        if let Some(wait_secs) = request.wait_secs {
+            config.wait = args::parse_wait(wait_secs)
+                .ok_or_else(|| "wait_secs must be a positive number of seconds".to_string())?;
+        }
+        config.port = request.port.unwrap_or(config.port);
+        config.first_hop_ttl = request.first_hop.unwrap_or(config.first_hop_ttl);
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn keep_job(&self, id: u64, job: Arc<Job>) {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        if jobs.len() >= MAX_KEPT_TRACES {
+            let finished = jobs
+                .iter()
+                .find(|(_, job)| job.state.lock().unwrap().status != Status::Running)
+                .map(|(id, _)| *id);
+            if let Some(finished) = finished {
+                jobs.remove(&finished);
+            }
+        }
+        jobs.insert(id, job);
+    }
+
+    fn find_job(&self, id: &str) -> Option<Arc<Job>> {
+        let id = id.parse::<u64>().ok()?;
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    fn run_job(&self, job: &Job, config: &Config) {
+        let mut running = RunningJob {
+            api: self,
+            job,
+            result: None,
+        };
+
+        let mut protocol = create_protocol(config);
+        protocol.set_io((self.open_io)(
+            protocol.get_protocol(),
+            protocol.get_reply_protocol(),
+        ));
+
+        let trace = trace_with_progress(config, protocol, &mut io::sink(), &mut |trace| {
+            job.update(|state| {
+                state.trace.hops.extend(trace.hops.last().cloned());
+                state.trace.destination_reached = trace.destination_reached;
+                state.trace.end_reason = trace.end_reason;
+            })
+        });
+        running.result = Some(trace);
+    }
+}
+
+fn json_response(status: u16, body: &impl Serialize) -> Response<Cursor<Vec<u8>>> {
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+
+    Response::from_data(serde_json::to_vec(body).unwrap())
+        .with_status_code(status)
+        .with_header(content_type)
+}
+
+fn error_response(status: u16, message: &str) -> Response<Cursor<Vec<u8>>> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
+
+fn retry_after() -> Header {
+    Header::from_bytes("Retry-After", RATE_LIMIT_WINDOW.as_secs().to_string()).unwrap()
+}
+
+/// Streams the progress of `job` as server-sent events: one `hop` event per probed hop and
+/// a final `done` event with the complete trace.
+fn stream_events(request: Request, job: &Job) {
+    let chunked = *request.http_version() >= HTTPVersion(1, 1);
+    let mut writer = request.into_writer();
+
+    if let Err(e) = write_events(&mut writer, job, chunked) {
+        info!("Event stream closed: {}", e);
+    }
+}
+
+fn write_events(writer: &mut dyn Write, job: &Job, chunked: bool) -> io::Result<()> {
+    // tiny_http would buffer a streamed response, so write it directly
+    write!(
+        writer,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n{}\r\n",
+        if chunked {
+            "Transfer-Encoding: chunked\r\n"
+        } else {
+            "Connection: close\r\n"
+        }
+    )?;
+
+    let mut sent = 0;
+    loop {
+        let mut events = String::new();
+        let finished = {
+            let state = job
+                .changed
+                .wait_while(job.state.lock().unwrap(), |state| {
+                    state.status == Status::Running && state.trace.hops.len() == sent
+                })
+                .unwrap();
+
+            for hop in &state.trace.hops[sent..] {
+                events += &format!("event: hop\ndata: {}\n\n", serde_json::to_string(hop)?);
+            }
+            sent = state.trace.hops.len();
+
+            if state.status != Status::Running {
+                events += &format!("event: done\ndata: {}\n\n", serde_json::to_string(&*state)?);
+            }
+            state.status != Status::Running
+        };
+
+        if chunked {
+            write!(writer, "{:x}\r\n{}\r\n", events.len(), events)?;
+        } else {
+            writer.write_all(events.as_bytes())?;
+        }
+        if finished {
+            if chunked {
+                writer.write_all(b"0\r\n\r\n")?;
+            }
+            return writer.flush();
+        }
+        writer.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::start_server;
+    use crate::simulation::{test_config, SimulatedDestination, SimulatedNetwork, SimulatedRouter};
+    use std::net::{Ipv4Addr, TcpStream};
+    use std::sync::mpsc;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(10, 0, 9, 1);
+
+    fn network(protocol: TransportChannelType, _reply: TransportChannelType) -> Box<dyn PacketIo> {
+        let routers = (1..=2)
+            .map(|i| SimulatedRouter::new(Ipv4Addr::new(10, 0, 1, i), Duration::from_millis(1)))
+            .collect();
+        let destination = SimulatedDestination::new(DESTINATION, Duration::from_millis(5));
+
+        Box::new(SimulatedNetwork::linear(
+            protocol,
+            SOURCE,
+            routers,
+            destination,
+        ))
+    }
+
+    fn start(config: Config, open_io: Box<OpenIo>) -> String {
+        let server = start_server("127.0.0.1:0");
+        let addr = server.server_addr().to_string();
+        let api = Arc::new(Api::new(config, open_io));
+        thread::spawn(move || api.serve(server));
+        addr
+    }
+
+    /// Sends an HTTP/1.0 request, returns status code and body.
+    fn request(addr: &str, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            addr,
+            body.len(),
+            body
+        )
+        .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap().to_string();
+        (status, body)
+    }
+
+    fn post_trace(addr: &str, body: &str) -> (u16, serde_json::Value) {
+        let (status, body) = request(addr, "POST", "/traces", body);
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    fn wait_for_trace(addr: &str, id: u64) -> serde_json::Value {
+        loop {
+            let (status, body) = request(addr, "GET", &format!("/traces/{}", id), "");
+            assert_eq!(status, 200);
+            let trace = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+            if trace["status"] != "running" {
+                return trace;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn runs_requested_traces() {
+        let addr = start(test_config(DESTINATION, Method::Icmp), Box::new(network));
+
+        let bodies = ["icmp", "udp", "tcp"]
+            .iter()
+            .map(|method| format!(r#"{{"target": "10.0.9.1", "method": "{}"}}"#, method))
+            .chain([r#"{"target": "10.0.9.1", "protocol": 47}"#.to_string()]);
+        for body in bodies {
+            let (status, started) = post_trace(&addr, &body);
+            assert_eq!(status, 202);
+
+            let trace = wait_for_trace(&addr, started["id"].as_u64().unwrap());
+            assert_eq!(trace["status"], "done");
+            assert_eq!(trace["destination_reached"], true);
+            let hops = trace["hops"].as_array().unwrap();
+            assert_eq!(hops.len(), 3);
+            assert_eq!(hops[0]["probes"][0]["addr"], "10.0.1.1");
+        }
+
+        assert_eq!(request(&addr, "GET", "/traces/99", "").0, 404);
+        assert_eq!(request(&addr, "GET", "/other", "").0, 404);
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let addr = start(test_config(DESTINATION, Method::Icmp), Box::new(network));
+
+        for body in &[
+            "not json",
+            r#"{"target": "10.0.9.1", "colour": "blue"}"#,
+            r#"{"target": "10.0.9.1", "method": "carrier-pigeon"}"#,
+            r#"{"target": "10.0.9.1", "method": "udp", "protocol": 47}"#,
+            r#"{"target": "10.0.9.1", "first_hop": 64}"#,
+            r#"{"target": "10.0.9.1", "tries": 0}"#,
+            r#"{"target": "10.0.9.1", "max_hops": 0}"#,
+        ] {
+            let (status, response) = post_trace(&addr, body);
+            assert_eq!(status, 400, "accepted {}", body);
+            assert!(response["error"].is_string());
+        }
+    }
+
+    #[test]
+    fn streams_progress_events() {
+        let addr = start(test_config(DESTINATION, Method::Icmp), Box::new(network));
+        let (_, started) = post_trace(&addr, r#"{"target": "10.0.9.1"}"#);
+        let path = format!("/traces/{}/events", started["id"]);
+
+        let (status, events) = request(&addr, "GET", &path, "");
+        assert_eq!(status, 200);
+        let names = events
+            .lines()
+            .filter_map(|line| line.strip_prefix("event: "))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["hop", "hop", "hop", "done"]);
+        assert!(events.contains(r#""status":"done""#));
+    }
+
+    #[test]
+    fn limits_requests_per_client() {
+        let mut config = test_config(DESTINATION, Method::Icmp);
+        config.rate_limit = 2;
+        let addr = start(config, Box::new(network));
+        let body = r#"{"target": "10.0.9.1"}"#;
+
+        assert_eq!(post_trace(&addr, body).0, 202);
+        assert_eq!(post_trace(&addr, body).0, 202);
+        assert_eq!(post_trace(&addr, body).0, 429);
+    }
+
+    #[test]
+    fn limits_concurrent_traces() {
+        let mut config = test_config(DESTINATION, Method::Icmp);
+        config.max_traces = 1;
+        // traces block until the test lets them open their packet I/O
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let addr = start(
+            config,
+            Box::new(move |protocol, reply_protocol| {
+                released.lock().unwrap().recv().unwrap();
+                network(protocol, reply_protocol)
+            }),
+        );
+        let body = r#"{"target": "10.0.9.1"}"#;
+
+        let (status, started) = post_trace(&addr, body);
+        assert_eq!(status, 202);
+        assert_eq!(post_trace(&addr, body).0, 503);
+
+        release.send(()).unwrap();
+        wait_for_trace(&addr, started["id"].as_u64().unwrap());
+        release.send(()).unwrap();
+        assert_eq!(post_trace(&addr, body).0, 202);
+    }
+}
@@ -1,20 +1,122 @@
-use clap::{App, Arg};
+use crate::config_file;
+use crate::dns::hostname_to_ip;
+use crate::geo::GeoDatabase;
+use crate::ip_options::{IpOptions, MAX_ROUTE_ADDRESSES};
+use crate::protocols::application::Application;
+use crate::protocols::raw::parse_payload;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
+#[derive(Clone)]
 pub enum Method {
-    ICMP,
-    UDP,
-    TCP,
+    Icmp,
+    Udp,
+    Tcp,
+    /// Keep-alives inside an established TCP connection.
+    TcpConnection,
+    Sctp,
+    Dccp,
+    /// Packets of the given IP protocol number, selected with `-P`.
+    Raw(u8),
 }
 
+impl Method {
+    /// Command line names of the methods, raw IP packets are selected by protocol number.
+    pub const NAMES: &'static [&'static str] = &["icmp", "udp", "tcp", "tcpconn", "sctp", "dccp"];
+
+    /// Method by its command line name, e.g. "icmp".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "icmp" => Some(Method::Icmp),
+            "udp" => Some(Method::Udp),
+            "tcp" => Some(Method::Tcp),
+            "tcpconn" => Some(Method::TcpConnection),
+            "sctp" => Some(Method::Sctp),
+            "dccp" => Some(Method::Dccp),
+            _ => None,
+        }
+    }
+
+    /// Like `from_name`, with a message listing the names if `name` is none of them.
+    pub fn parse(name: &str) -> Result<Self, String> {
+        Method::from_name(name).ok_or_else(|| {
+            let quoted = Method::NAMES
+                .iter()
+                .map(|name| format!("'{}'", name))
+                .collect::<Vec<_>>();
+            let (last, others) = quoted.split_last().unwrap();
+            format!(
+                "method must be {} or {}, not '{}'",
+                others.join(", "),
+                last,
+                name
+            )
+        })
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Output {
+    Text,
+    Json,
+    Dot,
+    Graphml,
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub host: String,
     pub hops: u8,
     pub method: Method,
     pub tries: u8,
-    pub wait_secs: u8,
+    pub wait: Duration,
     pub port: u16,
     pub first_hop_ttl: u8,
+    /// Stop after this many hops in a row without any reply, 0 never stops early.
+    pub gap_limit: u8,
     pub resolve_hostnames: bool,
+    pub show_return_hops: bool,
+    /// Print RTT aggregates per hop instead of every probe.
+    pub stats: bool,
+    /// IPv4 options added to every probe.
+    pub ip_options: IpOptions,
+    pub pcap_out: Option<String>,
+    pub replay: Option<String>,
+    pub output: Output,
+    pub targets: Option<String>,
+    pub workers: usize,
+    pub doubletree: Option<u8>,
+    pub stop_set: Option<String>,
+    pub stateless: bool,
+    pub serve_metrics: Option<String>,
+    pub interval_secs: u64,
+    pub serve_api: Option<String>,
+    pub max_traces: usize,
+    pub rate_limit: usize,
+    pub monitor: bool,
+    pub hysteresis: usize,
+    pub webhook: Option<String>,
+    pub asn_table: Option<String>,
+    /// Geolocation database to annotate hops with, loaded once for all traces.
+    pub geo: Option<Arc<GeoDatabase>>,
+    /// Paths of the before and after traces to compare with the `diff` subcommand.
+    pub diff: Option<(String, String)>,
+    pub rtt_threshold_ms: f64,
+    pub merge: bool,
+    /// Maximal number of probes sent per second, across all traces.
+    pub max_pps: Option<f64>,
+    /// Payload of raw IP probes.
+    pub payload: Vec<u8>,
+    /// IP protocol of answers which show that raw IP probes reached the destination.
+    pub reply_protocol: Option<u8>,
+    /// Application protocol spoken by UDP probes.
+    pub udp_template: Option<Application>,
+    /// Local port of the existing connection to trace in with the `tcpconn` method.
+    pub local_port: Option<u16>,
 }
 
 impl Config {
@@ -22,7 +124,7 @@ impl Config {
         Arg::with_name("HOST")
             .takes_value(true)
             .help("The host to perform traceroute to.")
-            .required(true)
+            .required_unless_one(&["REPLAY", "TARGETS", "SERVE_API"])
             .index(1)
     }
 
@@ -31,7 +133,7 @@ impl Config {
             .short("m")
             .long("max-hop")
             .takes_value(true)
-            .help("set maximal hop count")
+            .help("set maximal hop count, i.e., the last TTL probed")
             .default_value("64")
     }
 
@@ -40,10 +142,51 @@ impl Config {
             .short("M")
             .long("type")
             .takes_value(true)
-            .help("method ('icmp', 'udp' or 'tcp') for traceroute operations")
+            .help("method ('icmp', 'udp', 'tcp', 'tcpconn', 'sctp' or 'dccp') for traceroute operations")
             .default_value("icmp")
     }
 
+    fn protocol_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("PROTOCOL")
+            .short("P")
+            .long("protocol")
+            .takes_value(true)
+            .conflicts_with("METHOD")
+            .help("send raw IP packets of protocol number PROTOCOL, e.g. 47 for GRE, instead of using a method")
+    }
+
+    fn payload_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("PAYLOAD")
+            .long("payload")
+            .takes_value(true)
+            .requires("PROTOCOL")
+            .help("payload of raw IP probes as hex digits, e.g. '0000 0800'")
+    }
+
+    fn reply_protocol_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("REPLY_PROTOCOL")
+            .long("reply-protocol")
+            .takes_value(true)
+            .requires("PROTOCOL")
+            .help("also treat packets of protocol number REPLY_PROTOCOL from the destination as reaching it")
+    }
+
+    fn template_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("TEMPLATE")
+            .long("template")
+            .takes_value(true)
+            .conflicts_with("stateless")
+            .help("with '-M udp', send well-formed 'dns', 'ntp' or 'quic' requests, to port 53, 123 or 443 unless PORT is given")
+    }
+
+    fn local_port_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("LOCAL_PORT")
+            .long("local-port")
+            .takes_value(true)
+            .conflicts_with_all(&["TARGETS", "SERVE_API"])
+            .help("with '-M tcpconn', trace inside the established connection from LOCAL_PORT to HOST instead of opening one")
+    }
+
     fn tries_arg<'a, 'b>() -> Arg<'a, 'b> {
         Arg::with_name("TRIES")
             .short("q")
@@ -58,7 +201,7 @@ impl Config {
             .short("w")
             .long("wait")
             .takes_value(true)
-            .help("wait WAIT_SECS seconds for response")
+            .help("wait WAIT_SECS seconds for response, may be fractional like 0.5")
             .default_value("3")
     }
 
@@ -67,7 +210,7 @@ impl Config {
             .short("p")
             .long("port")
             .takes_value(true)
-            .help("use destination PORT port (UDP, TCP)")
+            .help("use destination PORT port (UDP, TCP, SCTP, DCCP)")
             .default_value("33434")
     }
 
@@ -80,50 +223,670 @@ impl Config {
             .default_value("1")
     }
 
+    fn gap_limit_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("GAP_LIMIT")
+            .long("gap-limit")
+            .takes_value(true)
+            .help("stop after GAP_LIMIT hops in a row without any reply, 0 disables the limit")
+            .default_value("5")
+    }
+
     fn resolve_hostnames_arg<'a, 'b>() -> Arg<'a, 'b> {
         Arg::with_name("resolve-hostnames")
             .long("resolve-hostnames")
             .help("resolve hostnames")
     }
 
+    fn back_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("back")
+            .long("back")
+            .help("print the number of backward hops when it differs from the forward direction")
+    }
+
+    fn stats_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("stats")
+            .long("stats")
+            .help("print min/avg/max/stddev, median, p95 and loss of the RTTs per hop instead of every probe, also added to JSON output")
+    }
+
+    fn record_route_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("record-route")
+            .long("record-route")
+            .help("send IPv4 probes with the Record Route option and print the up to 9 addresses recorded in quoted probes and echo replies")
+    }
+
+    fn gateway_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("GATEWAY")
+            .short("g")
+            .long("gateway")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("route IPv4 probes through GATEWAY with the loose source route option, repeat for up to 9 gateways in order")
+    }
+
+    fn pcap_out_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("PCAP_OUT")
+            .long("pcap-out")
+            .takes_value(true)
+            .help("write all sent probes and received replies to pcap file PCAP_OUT")
+    }
+
+    fn replay_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("REPLAY")
+            .long("replay")
+            .takes_value(true)
+            .help("reconstruct the trace from the pcap file REPLAY instead of sending probes")
+    }
+
+    fn output_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("OUTPUT")
+            .long("output")
+            .takes_value(true)
+            .help("output format ('text', 'json', 'dot' or 'graphml'), JSON prints one object per trace")
+            .default_value("text")
+    }
+
+    fn merge_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("merge")
+            .long("merge")
+            .help("with '--output dot' or 'graphml', merge all traces into one topology graph")
+    }
+
+    fn targets_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("TARGETS")
+            .long("targets")
+            .takes_value(true)
+            .conflicts_with_all(&["HOST", "REPLAY"])
+            .help("trace all hosts listed in file TARGETS, one per line ('-' reads from stdin)")
+    }
+
+    fn workers_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("WORKERS")
+            .long("workers")
+            .takes_value(true)
+            .help("trace at most WORKERS targets at the same time")
+            .default_value("16")
+    }
+
+    fn doubletree_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("DOUBLETREE")
+            .long("doubletree")
+            .takes_value(true)
+            .conflicts_with("REPLAY")
+            .help("probe forwards and backwards from TTL DOUBLETREE, skipping path segments already known from the stop sets")
+    }
+
+    fn stop_set_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("STOP_SET")
+            .long("stop-set")
+            .takes_value(true)
+            .requires("DOUBLETREE")
+            .help("load and save the global Doubletree stop set in file STOP_SET")
+    }
+
+    fn stateless_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("stateless")
+            .long("stateless")
+            .conflicts_with_all(&["REPLAY", "DOUBLETREE"])
+            .help("probe all (target, TTL) pairs in random order without keeping state per target, targets may be prefixes like 192.0.2.0/24")
+    }
+
+    fn serve_metrics_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("SERVE_METRICS")
+            .long("serve-metrics")
+            .takes_value(true)
+            .conflicts_with_all(&["REPLAY", "stateless"])
+            .help("keep tracing the targets every INTERVAL seconds and serve Prometheus metrics on SERVE_METRICS, e.g. ':9100'")
+    }
+
+    fn interval_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("INTERVAL")
+            .long("interval")
+            .takes_value(true)
+            .help("start a new round of traces every INTERVAL seconds")
+            .default_value("60")
+    }
+
+    fn serve_api_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("SERVE_API")
+            .long("serve-api")
+            .takes_value(true)
+            .conflicts_with_all(&["HOST", "TARGETS", "REPLAY", "stateless", "SERVE_METRICS"])
+            .help("serve an HTTP API on SERVE_API, e.g. ':8080', which runs traces on request")
+    }
+
+    fn max_traces_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("MAX_TRACES")
+            .long("max-traces")
+            .takes_value(true)
+            .help("run at most MAX_TRACES traces requested through the API at the same time")
+            .default_value("4")
+    }
+
+    fn rate_limit_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("RATE_LIMIT")
+            .long("rate-limit")
+            .takes_value(true)
+            .help("accept at most RATE_LIMIT trace requests per client and minute")
+            .default_value("10")
+    }
+
+    fn monitor_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("monitor")
+            .long("monitor")
+            .conflicts_with_all(&["REPLAY", "stateless", "SERVE_METRICS", "SERVE_API"])
+            .help("keep tracing the targets every INTERVAL seconds and print an event as JSON whenever a path changes")
+    }
+
+    fn hysteresis_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("HYSTERESIS")
+            .long("hysteresis")
+            .takes_value(true)
+            .help("report a path change only after HYSTERESIS traces in a row differ from the known path in the same way")
+            .default_value("2")
+    }
+
+    fn webhook_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("WEBHOOK")
+            .long("webhook")
+            .takes_value(true)
+            .requires("monitor")
+            .help("also post path change events to the http:// URL WEBHOOK")
+    }
+
+    fn asn_table_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("ASN_TABLE")
+            .long("asn-table")
+            .takes_value(true)
+            .help("map hop addresses to AS numbers with the prefix table ASN_TABLE ('prefix length asn' per line)")
+    }
+
+    fn geo_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("GEO")
+            .long("geo")
+            .takes_value(true)
+            .help("annotate hops with country, city and coordinates from the MaxMind DB (mmdb) file GEO, flagging hops reached faster than light with '!FTL'")
+    }
+
+    fn pps_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("PPS")
+            .long("pps")
+            .takes_value(true)
+            .help("send at most PPS probes per second, across all TTLs and targets")
+    }
+
+    fn send_wait_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("SEND_WAIT")
+            .long("send-wait")
+            .takes_value(true)
+            .help("wait at least SEND_WAIT seconds between probes, across all TTLs and targets")
+    }
+
+    fn config_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("CONFIG")
+            .long("config")
+            .takes_value(true)
+            .help("read options from the TOML file CONFIG after /etc/traceroute-rust/config.toml and ~/.config/traceroute-rust/config.toml, flags take precedence")
+    }
+
+    fn profile_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("PROFILE")
+            .long("profile")
+            .takes_value(true)
+            .help("apply the options of table [profile.PROFILE] in the config files")
+    }
+
+    fn diff_subcommand<'a, 'b>() -> App<'a, 'b> {
+        SubCommand::with_name("diff")
+            .about("Compares two traces saved with '--output json'")
+            .arg(
+                Arg::with_name("BEFORE")
+                    .required(true)
+                    .index(1)
+                    .help("The trace before the change."),
+            )
+            .arg(
+                Arg::with_name("AFTER")
+                    .required(true)
+                    .index(2)
+                    .help("The trace after the change."),
+            )
+            .arg(Config::rtt_threshold_arg())
+    }
+
+    fn rtt_threshold_arg<'a, 'b>() -> Arg<'a, 'b> {
+        Arg::with_name("RTT_THRESHOLD")
+            .long("rtt-threshold")
+            .takes_value(true)
+            .help("flag hops whose median RTT changed by at least RTT_THRESHOLD milliseconds")
+            .default_value("10")
+    }
+
     pub fn parse() -> Self {
+        Config::parse_from(std::env::args_os(), config_file::default_config_files())
+    }
+
+    /// Parses `args`, reading `default_config_files` if they exist.
+    fn parse_from<I, T>(args: I, default_config_files: Vec<PathBuf>) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
         let app = App::new("traceroute-rust")
             .about("Simple traceroute implementation in Rust using pnet")
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .subcommand(Config::diff_subcommand())
             .arg(Config::host_arg())
             .arg(Config::hops_arg())
             .arg(Config::mode_arg())
+            .arg(Config::protocol_arg())
+            .arg(Config::payload_arg())
+            .arg(Config::reply_protocol_arg())
+            .arg(Config::template_arg())
+            .arg(Config::local_port_arg())
             .arg(Config::tries_arg())
             .arg(Config::wait_arg())
             .arg(Config::port_arg())
             .arg(Config::first_hop_arg())
-            .arg(Config::resolve_hostnames_arg());
-
-        let matches = app.get_matches();
-        let host = matches.value_of("HOST").expect("Please specify a host.");
-        let hops = matches.value_of("MAX_HOPS").unwrap();
-        let method = match matches.value_of("METHOD").unwrap() {
-            "icmp" => Method::ICMP,
-            "udp" => Method::UDP,
-            "tcp" => Method::TCP,
-            _ => panic!("Not an available method."),
+            .arg(Config::gap_limit_arg())
+            .arg(Config::resolve_hostnames_arg())
+            .arg(Config::back_arg())
+            .arg(Config::stats_arg())
+            .arg(Config::record_route_arg())
+            .arg(Config::gateway_arg())
+            .arg(Config::pcap_out_arg())
+            .arg(Config::replay_arg())
+            .arg(Config::output_arg())
+            .arg(Config::merge_arg())
+            .arg(Config::targets_arg())
+            .arg(Config::workers_arg())
+            .arg(Config::doubletree_arg())
+            .arg(Config::stop_set_arg())
+            .arg(Config::stateless_arg())
+            .arg(Config::serve_metrics_arg())
+            .arg(Config::interval_arg())
+            .arg(Config::serve_api_arg())
+            .arg(Config::max_traces_arg())
+            .arg(Config::rate_limit_arg())
+            .arg(Config::monitor_arg())
+            .arg(Config::hysteresis_arg())
+            .arg(Config::webhook_arg())
+            .arg(Config::asn_table_arg())
+            .arg(Config::geo_arg())
+            .arg(Config::pps_arg())
+            .arg(Config::send_wait_arg())
+            .arg(Config::config_arg())
+            .arg(Config::profile_arg());
+
+        let matches = app.get_matches_from(args);
+        let settings = config_file::load_settings(
+            default_config_files,
+            matches.value_of("CONFIG"),
+            matches.value_of("PROFILE"),
+        );
+        let host = matches.value_of("HOST").unwrap_or_default();
+        let hops = value_of(&matches, "MAX_HOPS", settings.max_hop);
+        let method = match matches.value_of("PROTOCOL") {
+            Some(protocol) => match protocol.parse::<u8>() {
+                Ok(protocol) => Method::Raw(protocol),
+                Err(_) => panic!("The protocol must be an IP protocol number."),
+            },
+            None => match Method::parse(&value_of(&matches, "METHOD", settings.method)) {
+                Ok(method) => method,
+                Err(e) => panic!("{}", e),
+            },
+        };
+        let local_port =
+            matches
+                .value_of("LOCAL_PORT")
+                .map(|port| match (port.parse::<u16>(), &method) {
+                    (Ok(port), Method::TcpConnection) => port,
+                    (Ok(_), _) => panic!("A local port is only used with '-M tcpconn'."),
+                    (Err(_), _) => panic!("The local port must be a port number."),
+                });
+        let payload = match matches.value_of("PAYLOAD").map(parse_payload) {
+            Some(Some(payload)) => payload,
+            Some(None) => panic!("The payload must be given as pairs of hex digits."),
+            None => Vec::new(),
+        };
+        let reply_protocol =
+            matches
+                .value_of("REPLY_PROTOCOL")
+                .map(|protocol| match protocol.parse::<u8>() {
+                    Ok(protocol) => protocol,
+                    Err(_) => panic!("The reply protocol must be an IP protocol number."),
+                });
+        let tries = value_of(&matches, "TRIES", settings.tries);
+        let wait_secs = value_of(&matches, "WAIT_SECS", settings.wait);
+        let wait = match wait_secs.parse::<f64>().ok().and_then(parse_wait) {
+            Some(wait) => wait,
+            None => panic!("Wait must be a positive number of seconds."),
         };
-        let tries = matches.value_of("TRIES").unwrap();
-        let wait_secs = matches.value_of("WAIT_SECS").unwrap();
-        let port = matches.value_of("PORT").unwrap();
-        let first_hop = matches.value_of("FIRST_HOP").unwrap();
-        let resolve_hostnames = matches.is_present("resolve-hostnames");
+        let udp_template =
+            matches
+                .value_of("TEMPLATE")
+                .map(|name| match Application::from_name(name) {
+                    Some(application) if matches!(method, Method::Udp) => application,
+                    Some(_) => panic!("Probe templates are only available with '-M udp'."),
+                    None => panic!("Not an available probe template."),
+                });
+        let port = match udp_template {
+            Some(application) if matches.occurrences_of("PORT") == 0 && settings.port.is_none() => {
+                application.default_port().to_string()
+            }
+            _ => value_of(&matches, "PORT", settings.port),
+        };
+        let first_hop = value_of(&matches, "FIRST_HOP", settings.first_hop);
+        let gap_limit = value_of(&matches, "GAP_LIMIT", settings.gap_limit);
+        let resolve_hostnames =
+            matches.is_present("resolve-hostnames") || settings.resolve_hostnames.unwrap_or(false);
+        let show_return_hops = matches.is_present("back") || settings.back.unwrap_or(false);
+        let stats = matches.is_present("stats") || settings.stats.unwrap_or(false);
+        let loose_source_route = matches
+            .values_of("GATEWAY")
+            .into_iter()
+            .flatten()
+            .map(|gateway| match hostname_to_ip(gateway) {
+                IpAddr::V4(gateway) => gateway,
+                IpAddr::V6(_) => panic!("Gateways must be IPv4 addresses."),
+            })
+            .collect::<Vec<_>>();
+        if loose_source_route.len() > MAX_ROUTE_ADDRESSES {
+            panic!("At most {} gateways can be given.", MAX_ROUTE_ADDRESSES);
+        }
+        let ip_options = IpOptions {
+            record_route: matches.is_present("record-route")
+                || settings.record_route.unwrap_or(false),
+            loose_source_route,
+        };
+        if ip_options.record_route && ip_options.record_route_slots() == 0 {
+            panic!("The gateways leave no room to record the route.");
+        }
+        let pcap_out = matches.value_of("PCAP_OUT").map(|path| path.to_string());
+        let replay = matches.value_of("REPLAY").map(|path| path.to_string());
+        let output = match value_of(&matches, "OUTPUT", settings.output).as_str() {
+            "text" => Output::Text,
+            "json" => Output::Json,
+            "dot" => Output::Dot,
+            "graphml" => Output::Graphml,
+            _ => panic!("Not an available output format."),
+        };
+        let merge = matches.is_present("merge") || settings.merge.unwrap_or(false);
+        let targets = matches.value_of("TARGETS").map(|path| path.to_string());
+        let workers = value_of(&matches, "WORKERS", settings.workers);
+        let doubletree = matches
+            .value_of("DOUBLETREE")
+            .map(|ttl| ttl.parse::<u8>().unwrap());
+        let stop_set = matches.value_of("STOP_SET").map(|path| path.to_string());
+        let stateless = matches.is_present("stateless");
+        if stateless && matches!(method, Method::TcpConnection) {
+            panic!("Connections cannot be traced statelessly.");
+        }
+        let serve_metrics = matches
+            .value_of("SERVE_METRICS")
+            .map(|addr| addr.to_string());
+        let interval = value_of(&matches, "INTERVAL", settings.interval);
+        let serve_api = matches.value_of("SERVE_API").map(|addr| addr.to_string());
+        let max_traces = value_of(&matches, "MAX_TRACES", settings.max_traces);
+        let rate_limit = value_of(&matches, "RATE_LIMIT", settings.rate_limit);
+        let monitor = matches.is_present("monitor");
+        let hysteresis = value_of(&matches, "HYSTERESIS", settings.hysteresis);
+        let webhook = matches
+            .value_of("WEBHOOK")
+            .map(|url| url.to_string())
+            .or(settings.webhook);
+        let asn_table = matches
+            .value_of("ASN_TABLE")
+            .map(|path| path.to_string())
+            .or(settings.asn_table);
+        let geo =
+            option_of(&matches, "GEO", settings.geo).map(|path| Arc::new(GeoDatabase::load(&path)));
+        let pps = option_of(&matches, "PPS", settings.pps).map(|pps| match pps.parse::<f64>() {
+            Ok(pps) if pps.is_finite() && pps > 0.0 => pps,
+            _ => panic!("The probe rate must be a positive number."),
+        });
+        let send_wait =
+            option_of(&matches, "SEND_WAIT", settings.send_wait).map(|secs| {
+                match secs.parse::<f64>().ok().and_then(parse_wait) {
+                    Some(send_wait) => 1.0 / send_wait.as_secs_f64(),
+                    None => panic!("The send wait must be a positive number of seconds."),
+                }
+            });
+        // the stricter limit wins if both are set
+        let max_pps = pps.into_iter().chain(send_wait).reduce(f64::min);
+        let diff_matches = matches.subcommand_matches("diff");
+        let diff = diff_matches.map(|diff| {
+            let before = diff.value_of("BEFORE").unwrap().to_string();
+            let after = diff.value_of("AFTER").unwrap().to_string();
+            (before, after)
+        });
+        let rtt_threshold =
+            diff_matches.map_or("10", |diff| diff.value_of("RTT_THRESHOLD").unwrap());
 
         let config = Config {
             host: host.to_string(),
             hops: hops.parse::<u8>().unwrap(),
-            method: method,
+            method,
             tries: tries.parse::<u8>().unwrap(),
-            wait_secs: wait_secs.parse::<u8>().unwrap(),
+            wait,
             port: port.parse::<u16>().unwrap(),
             first_hop_ttl: first_hop.parse::<u8>().unwrap(),
-            resolve_hostnames: resolve_hostnames,
+            gap_limit: gap_limit.parse::<u8>().unwrap(),
+            resolve_hostnames,
+            show_return_hops,
+            stats,
+            ip_options,
+            pcap_out,
+            replay,
+            output,
+            targets,
+            workers: workers.parse::<usize>().unwrap().max(1),
+            doubletree,
+            stop_set,
+            stateless,
+            serve_metrics,
+            interval_secs: interval.parse::<u64>().unwrap(),
+            serve_api,
+            max_traces: max_traces.parse::<usize>().unwrap().max(1),
+            rate_limit: rate_limit.parse::<usize>().unwrap(),
+            monitor,
+            hysteresis: hysteresis.parse::<usize>().unwrap().max(1),
+            webhook,
+            asn_table,
+            geo,
+            diff,
+            rtt_threshold_ms: rtt_threshold.parse::<f64>().unwrap(),
+            merge,
+            max_pps,
+            payload,
+            reply_protocol,
+            udp_template,
+            local_port,
         };
+        if let Err(e) = config.validate() {
+            panic!("{}", e);
+        }
 
         config
     }
+
+    /// Checks that the probed TTLs and the tries per hop, wherever they came from, can be used.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tries == 0 {
+            return Err("tries must be at least 1".to_string());
+        }
+        if self.hops == 0 {
+            return Err("max-hop must be at least 1".to_string());
+        }
+        if self.first_hop_ttl == 0 {
+            return Err("first-hop must be at least 1".to_string());
+        }
+        if self.first_hop_ttl > self.hops {
+            return Err(format!(
+                "first-hop {} must not be beyond max-hop {}",
+                self.first_hop_ttl, self.hops
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Value of option `name` if given on the command line, else `setting` from the config files,
+/// else the option's default.
+fn value_of<T: ToString>(matches: &ArgMatches, name: &str, setting: Option<T>) -> String {
+    match setting {
+        Some(setting) if matches.occurrences_of(name) == 0 => setting.to_string(),
+        _ => matches.value_of(name).unwrap().to_string(),
+    }
+}
+
+/// Like `value_of`, for options without a default.
+fn option_of<T: ToString>(matches: &ArgMatches, name: &str, setting: Option<T>) -> Option<String> {
+    matches
+        .value_of(name)
+        .map(|value| value.to_string())
+        .or_else(|| setting.map(|setting| setting.to_string()))
+}
+
+/// Time to wait for replies, which must be positive.
+pub fn parse_wait(secs: f64) -> Option<Duration> {
+    if secs.is_finite() && secs > 0.0 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn flags_take_precedence_over_profile() {
+        let path =
+            std::env::temp_dir().join(format!("traceroute-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "tries = 1\n\n[profile.dc-tcp]\nmethod = \"tcp\"\nport = 443\nwait = 0.5\n",
+        )
+        .unwrap();
+        let config = Config::parse_from(
+            [
+                "traceroute_rust",
+                "--config",
+                path.to_str().unwrap(),
+                "--profile",
+                "dc-tcp",
+                "-p",
+                "8443",
+                "192.0.2.1",
+            ],
+            Vec::new(),
+        );
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(config.method, Method::Tcp));
+        assert_eq!(config.port, 8443);
+        assert_eq!(config.wait, Duration::from_millis(500));
+        assert_eq!(config.tries, 1);
+        assert_eq!(config.first_hop_ttl, 1);
+    }
+
+    #[test]
+    fn protocol_selects_raw_probes() {
+        let config = Config::parse_from(
+            [
+                "traceroute_rust",
+                "-P",
+                "47",
+                "--payload",
+                "0000 0800",
+                "--reply-protocol",
+                "47",
+                "192.0.2.1",
+            ],
+            Vec::new(),
+        );
+
+        assert!(matches!(config.method, Method::Raw(47)));
+        assert_eq!(config.payload, vec![0, 0, 8, 0]);
+        assert_eq!(config.reply_protocol, Some(47));
+    }
+
+    #[test]
+    fn template_sets_default_port() {
+        let config = Config::parse_from(
+            [
+                "traceroute_rust",
+                "-M",
+                "udp",
+                "--template",
+                "ntp",
+                "192.0.2.1",
+            ],
+            Vec::new(),
+        );
+        assert_eq!(config.udp_template, Some(Application::Ntp));
+        assert_eq!(config.port, 123);
+
+        let config = Config::parse_from(
+            [
+                "traceroute_rust",
+                "-M",
+                "udp",
+                "--template",
+                "dns",
+                "-p",
+                "5353",
+                "192.0.2.1",
+            ],
+            Vec::new(),
+        );
+        assert_eq!(config.port, 5353);
+    }
+
+    #[test]
+    fn gateways_are_kept_in_order() {
+        let config = Config::parse_from(
+            [
+                "traceroute_rust",
+                "-g",
+                "192.0.2.1",
+                "--gateway",
+                "198.51.100.1",
+                "203.0.113.1",
+            ],
+            Vec::new(),
+        );
+
+        assert_eq!(
+            config.ip_options.loose_source_route,
+            vec![Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(198, 51, 100, 1)]
+        );
+        assert_eq!(config.host, "203.0.113.1");
+    }
+
+    #[test]
+    fn validates_ttls_and_tries() {
+        let config = Config::parse_from(["traceroute_rust", "-f", "3", "192.0.2.1"], Vec::new());
+        assert_eq!(config.validate(), Ok(()));
+
+        for (tries, hops, first_hop_ttl, error) in [
+            (0, 64, 1, "tries must be at least 1"),
+            (3, 0, 1, "max-hop must be at least 1"),
+            (3, 64, 0, "first-hop must be at least 1"),
+            (3, 64, 70, "first-hop 70 must not be beyond max-hop 64"),
+        ] {
+            let config = Config {
+                tries,
+                hops,
+                first_hop_ttl,
+                ..config.clone()
+            };
+            assert_eq!(config.validate(), Err(error.to_string()));
+        }
+    }
 }
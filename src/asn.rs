@@ -0,0 +1,99 @@
+use log::warn;
+use std::collections::HashMap;
+use std::fs;
+use std::net::Ipv4Addr;
+
+/// Maps IPv4 prefixes to the AS announcing them, matching the longest prefix.
+pub struct AsnTable {
+    /// Origin AS by network address, one map per prefix length.
+    prefixes: Vec<HashMap<u32, u32>>,
+}
+
+impl AsnTable {
+    pub fn new() -> Self {
+        AsnTable {
+            prefixes: vec![HashMap::new(); 33],
+        }
+    }
+
+    /// Loads a table with one "prefix length asn" entry per line, as in CAIDA's pfx2as files,
+    /// or "prefix/length asn".
+    pub fn load(path: &str) -> Self {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => panic!("Could not read AS table '{}': {}", path, e),
+        };
+        let mut table = AsnTable::new();
+
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let entry = match fields.as_slice() {
+                [prefix, length, asn] => parse_entry(prefix, length, asn),
+                [prefix, asn] => prefix
+                    .split_once('/')
+                    .and_then(|(prefix, length)| parse_entry(prefix, length, asn)),
+                _ => None,
+            };
+
+            match entry {
+                Some((network, length, asn)) => table.insert(network, length, asn),
+                None => warn!("Ignoring invalid AS table entry '{}'", line),
+            }
+        }
+
+        table
+    }
+
+    pub fn insert(&mut self, network: Ipv4Addr, length: u8, asn: u32) {
+        self.prefixes[length as usize].insert(u32::from(network) & mask(length), asn);
+    }
+
+    pub fn lookup(&self, addr: Ipv4Addr) -> Option<u32> {
+        let addr = u32::from(addr);
+
+        (0..=32u8)
+            .rev()
+            .find_map(|length| self.prefixes[length as usize].get(&(addr & mask(length))))
+            .copied()
+    }
+}
+
+fn parse_entry(prefix: &str, length: &str, asn: &str) -> Option<(Ipv4Addr, u8, u32)> {
+    let length = length.parse::<u8>().ok().filter(|length| *length <= 32)?;
+    // multi-origin prefixes list their ASes separated by '_', use the first one
+    let asn = asn.split(['_', ',']).next()?.parse().ok()?;
+
+    Some((prefix.parse().ok()?, length, asn))
+}
+
+fn mask(length: u8) -> u32 {
+    u32::MAX.checked_shl(32 - length as u32).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_longest_prefix() {
+        let path = std::env::temp_dir().join(format!("asn-table-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(
+            path,
+            "# prefix length asn\n10.0.0.0\t8\t64500\n10.1.0.0\t16\t64501_64502\n10.1.2.0/24 64503\nbogus\n",
+        )
+        .unwrap();
+        let table = AsnTable::load(path);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(table.lookup(Ipv4Addr::new(10, 9, 9, 9)), Some(64500));
+        assert_eq!(table.lookup(Ipv4Addr::new(10, 1, 9, 9)), Some(64501));
+        assert_eq!(table.lookup(Ipv4Addr::new(10, 1, 2, 3)), Some(64503));
+        assert_eq!(table.lookup(Ipv4Addr::new(192, 0, 2, 1)), None);
+    }
+}
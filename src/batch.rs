@@ -0,0 +1,486 @@
+use crate::args::Config;
+use crate::dns::try_hostname_to_ip;
+use crate::doubletree::{trace_doubletree, StopSets};
+use crate::ip_options::final_destination;
+use crate::packet_io::{PacketIo, ReceivedPacket};
+use crate::traceroute::{trace, Trace};
+use log::{debug, error, warn};
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Destination and probe id (ICMP identifier or source port) of a traceroute.
+type FlowKey = (Ipv4Addr, u16);
+
+enum Request {
+    Register(FlowKey, Sender<ReceivedPacket>, Sender<ReceivedPacket>),
+    Unregister(FlowKey),
+    Send(u8, Vec<u8>, IpAddr, Sender<io::Result<Instant>>),
+}
+
+/// Uses the shared `PacketIo` to send the probes of all traceroutes and hands every reply
+/// to the traceroute it belongs to.
+struct Dispatcher<'a> {
+    io: &'a mut dyn PacketIo,
+    probe_protocol: IpNextHeaderProtocol,
+    requests: Receiver<Request>,
+    routes: HashMap<FlowKey, (Sender<ReceivedPacket>, Sender<ReceivedPacket>)>,
+}
+
+impl Dispatcher<'_> {
+    /// Runs until all `DemuxIo`s are gone.
+    fn run(mut self) {
+        loop {
+            loop {
+                match self.requests.try_recv() {
+                    Ok(request) => self.handle_request(request),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+
+            if let Some(packet) = self.io.receive_icmp(Duration::from_millis(1)) {
+                self.route(packet, true);
+            }
+            // on ICMP traces the protocol socket only sees copies of the ICMP packets
+            if self.probe_protocol != IpNextHeaderProtocols::Icmp {
+                if let Some(packet) = self.io.receive_protocol(Duration::from_millis(1)) {
+                    self.route(packet, false);
+                }
+            }
+        }
+    }
+
+    fn handle_request(&mut self, request: Request) {
+        match request {
+            Request::Register(key, icmp, protocol) => {
+                self.routes.insert(key, (icmp, protocol));
+            }
+            Request::Unregister(key) => {
+                self.routes.remove(&key);
+            }
+            Request::Send(ttl, packet, dst, result) => {
+                let time_send = self
+                    .io
+                    .set_ttl(ttl)
+                    .and_then(|_| self.io.send_to(&packet, dst));
+                let _ = result.send(time_send);
+            }
+        }
+    }
+
+    fn route(&mut self, packet: ReceivedPacket, icmp: bool) {
+        let route = reply_key(&packet).and_then(|key| self.routes.get(&key));
+
+        match route {
+            Some((icmp_route, protocol_route)) => {
+                let route = if icmp { icmp_route } else { protocol_route };
+                let _ = route.send(packet);
+            }
+            None => debug!(
+                "Dropping packet from {:?}, no trace waits for it",
+                packet.get_sender()
+            ),
+        }
+    }
+}
+
+/// `PacketIo` of a single traceroute within a batch, backed by the shared `Dispatcher`.
+pub struct DemuxIo {
+    probe_protocol: IpNextHeaderProtocol,
+    requests: Sender<Request>,
+    icmp: (Sender<ReceivedPacket>, Receiver<ReceivedPacket>),
+    protocol: (Sender<ReceivedPacket>, Receiver<ReceivedPacket>),
+    sent: (Sender<io::Result<Instant>>, Receiver<io::Result<Instant>>),
+    ttl: u8,
+    key: Option<FlowKey>,
+}
+
+impl DemuxIo {
+    fn new(probe_protocol: IpNextHeaderProtocol, requests: Sender<Request>) -> Self {
+        DemuxIo {
+            probe_protocol,
+            requests,
+            icmp: channel(),
+            protocol: channel(),
+            sent: channel(),
+            ttl: 64,
+            key: None,
+        }
+    }
+
+    fn request(&self, request: Request) -> io::Result<()> {
+        self.requests
+            .send(request)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "dispatcher stopped"))
+    }
+
+    /// Makes sure replies to `key` are handed to us before the probe leaves.
+    fn register(&mut self, key: FlowKey) -> io::Result<()> {
+        if self.key == Some(key) {
+            return Ok(());
+        }
+
+        if let Some(old_key) = self.key.take() {
+            self.request(Request::Unregister(old_key))?;
+        }
+        self.request(Request::Register(
+            key,
+            self.icmp.0.clone(),
+            self.protocol.0.clone(),
+        ))?;
+        self.key = Some(key);
+
+        Ok(())
+    }
+}
+
+impl PacketIo for DemuxIo {
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+        self.ttl = ttl;
+        Ok(())
+    }
+
+    fn send_to(&mut self, packet: &[u8], dst: IpAddr) -> io::Result<Instant> {
+        if let IpAddr::V4(dst) = dst {
+            if let Some(key) = probe_key(self.probe_protocol, dst, packet) {
+                self.register(key)?;
+            }
+        }
+
+        let request = Request::Send(self.ttl, packet.to_vec(), dst, self.sent.0.clone());
+        self.request(request)?;
+
+        match self.sent.1.recv() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "dispatcher stopped",
+            )),
+        }
+    }
+
+    fn receive_icmp(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        receive(&self.icmp.1, timeout)
+    }
+
+    fn receive_protocol(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        receive(&self.protocol.1, timeout)
+    }
+}
+
+impl Drop for DemuxIo {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let _ = self.request(Request::Unregister(key));
+        }
+    }
+}
+
+fn receive(rx: &Receiver<ReceivedPacket>, timeout: Duration) -> Option<ReceivedPacket> {
+    match rx.recv_timeout(timeout) {
+        Ok(packet) => Some(packet),
+        Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+    }
+}
+
+/// Flow of a probe, identified by the ICMP identifier or the source port.
+///
+/// Probes of protocols without ports are told apart by their destination only.
+fn probe_key(protocol: IpNextHeaderProtocol, dst: Ipv4Addr, probe: &[u8]) -> Option<FlowKey> {
+    let offset = match protocol {
+        IpNextHeaderProtocols::Icmp => 4,
+        IpNextHeaderProtocols::Udp
+        | IpNextHeaderProtocols::Tcp
+        | IpNextHeaderProtocols::Sctp
+        | IpNextHeaderProtocols::Dccp => 0,
+        _ => return Some((dst, 0)),
+    };
+    let id = probe.get(offset..offset + 2)?;
+
+    Some((dst, u16::from_be_bytes([id[0], id[1]])))
+}
+
+/// Flow of the probe a packet answers, taken from the quoted probe of ICMP errors.
+fn reply_key(received: &ReceivedPacket) -> Option<FlowKey> {
+    let ip_packet = received.get_ip_packet()?;
+    let payload = received.get_payload();
+
+    if ip_packet.get_next_level_protocol() != IpNextHeaderProtocols::Icmp {
+        // the reply's destination port is our source port
+        return probe_key(
+            ip_packet.get_next_level_protocol(),
+            ip_packet.get_source(),
+            payload.get(2..)?,
+        );
+    }
+
+    match received.get_icmp_packet()?.get_icmp_type() {
+        IcmpTypes::EchoReply => {
+            probe_key(IpNextHeaderProtocols::Icmp, ip_packet.get_source(), payload)
+        }
+        IcmpTypes::TimeExceeded | IcmpTypes::DestinationUnreachable => {
+            let quoted = payload.get(8..)?;
+            let quoted_packet = Ipv4Packet::new(quoted)?;
+            let header_length = quoted_packet.get_header_length() as usize * 4;
+
+            probe_key(
+                quoted_packet.get_next_level_protocol(),
+                final_destination(&quoted_packet),
+                quoted.get(header_length..)?,
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Reads one target per line from `path` or stdin if `path` is '-', skipping empty lines and
+/// comments starting with '#'.
+pub fn read_targets(path: &str) -> Vec<String> {
+    let reader: Box<dyn BufRead> = if path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => panic!("Could not open targets file '{}': {}", path, e),
+        }
+    };
+
+    parse_targets(reader)
+}
+
+fn parse_targets<R: BufRead>(reader: R) -> Vec<String> {
+    reader
+        .lines()
+        .map(|line| match line {
+            Ok(line) => line,
+            Err(e) => panic!("Could not read targets: {}", e),
+        })
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+/// Traces all `targets` through the shared `io` with at most `config.workers` traces at once.
+///
+/// Each trace is handed to `report` as soon as it is complete, the results are returned in
+/// the order of `targets`. Targets which cannot be resolved are skipped. In Doubletree mode
+/// all traces share the stop sets, which are loaded from and saved to the stop set file.
+pub fn trace_targets(
+    config: &Config,
+    targets: &[String],
+    io: &mut dyn PacketIo,
+    probe_protocol: IpNextHeaderProtocol,
+    report: &(dyn Fn(&Trace) + Sync),
+) -> Vec<Trace> {
+    let (requests, requests_rx) = channel();
+    let dispatcher = Dispatcher {
+        io,
+        probe_protocol,
+        requests: requests_rx,
+        routes: HashMap::new(),
+    };
+
+    let queue = Mutex::new(targets.iter().cloned().enumerate().collect::<VecDeque<_>>());
+    let results = Mutex::new((0..targets.len()).map(|_| None).collect::<Vec<_>>());
+    let stop_sets = Mutex::new(match &config.stop_set {
+        Some(path) => StopSets::load(path),
+        None => StopSets::new(),
+    });
+
+    thread::scope(|scope| {
+        scope.spawn(|| dispatcher.run());
+
+        let workers = (0..config.workers.min(targets.len()))
+            .map(|_| {
+                let requests = requests.clone();
+                scope.spawn(|| {
+                    run_worker(
+                        config,
+                        &queue,
+                        &results,
+                        &stop_sets,
+                        probe_protocol,
+                        requests,
+                        report,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            if worker.join().is_err() {
+                error!("A worker failed, its remaining targets are lost");
+            }
+        }
+
+        // stops the dispatcher
+        drop(requests);
+    });
+
+    if let Some(path) = &config.stop_set {
+        stop_sets.into_inner().unwrap().save(path);
+    }
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+fn run_worker(
+    config: &Config,
+    queue: &Mutex<VecDeque<(usize, String)>>,
+    results: &Mutex<Vec<Option<Trace>>>,
+    stop_sets: &Mutex<StopSets>,
+    probe_protocol: IpNextHeaderProtocol,
+    requests: Sender<Request>,
+    report: &(dyn Fn(&Trace) + Sync),
+) {
+    loop {
+        let (index, target) = match queue.lock().unwrap().pop_front() {
+            Some(next) => next,
+            None => return,
+        };
+
+        if try_hostname_to_ip(&target).is_none() {
+            warn!(
+                "Skipping '{}', it is neither an IP nor a resolvable name",
+                target
+            );
+            continue;
+        }
+
+        let mut target_config = config.clone();
+        target_config.host = target;
+
+        let mut protocol = crate::create_protocol(&target_config);
+        protocol.set_io(Box::new(DemuxIo::new(probe_protocol, requests.clone())));
+
+        let trace = match config.doubletree {
+            Some(start_ttl) => trace_doubletree(&target_config, protocol, start_ttl, stop_sets),
+            None => trace(&target_config, protocol, &mut io::sink()),
+        };
+        report(&trace);
+
+        results.lock().unwrap()[index] = Some(trace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Method;
+    use crate::simulation::{test_config, SimulatedDestination, SimulatedNetwork, SimulatedRouter};
+    use pnet::transport::TransportChannelType::Layer4;
+    use pnet::transport::TransportProtocol::Ipv4;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    /// Three destinations behind a shared first router, each with its own second router.
+    fn network(protocol: IpNextHeaderProtocol) -> SimulatedNetwork {
+        let mut network = SimulatedNetwork::new(Layer4(Ipv4(protocol)), SOURCE);
+        let first = network.add_router(SimulatedRouter::new(
+            Ipv4Addr::new(10, 0, 1, 1),
+            Duration::from_millis(1),
+        ));
+
+        for i in 1..=3 {
+            let second = network.add_router(SimulatedRouter::new(
+                Ipv4Addr::new(10, 0, 2, i),
+                Duration::from_millis(2),
+            ));
+            let destination =
+                SimulatedDestination::new(Ipv4Addr::new(10, 0, 9, i), Duration::from_millis(5));
+            network.add_destination(destination, &[first, second]);
+        }
+
+        network
+    }
+
+    fn trace_all(method: Method, protocol: IpNextHeaderProtocol) -> Vec<Trace> {
+        let mut config = test_config(Ipv4Addr::UNSPECIFIED, method);
+        config.workers = 2;
+        let targets = (1..=3).map(|i| format!("10.0.9.{}", i)).collect::<Vec<_>>();
+
+        trace_targets(&config, &targets, &mut network(protocol), protocol, &|_| {})
+    }
+
+    fn assert_traces(traces: &[Trace]) {
+        assert_eq!(traces.len(), 3);
+
+        for (i, trace) in traces.iter().enumerate() {
+            let i = i as u8 + 1;
+            assert_eq!(trace.host, format!("10.0.9.{}", i));
+            assert!(trace.destination_reached);
+
+            let addrs = trace
+                .hops
+                .iter()
+                .map(|hop| hop.probes[0].as_ref().unwrap().addr)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                addrs,
+                vec![
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1)),
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 2, i)),
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 9, i)),
+                ]
+            );
+            assert!(trace
+                .hops
+                .iter()
+                .all(|hop| hop.probes.iter().all(|probe| probe.is_some())));
+        }
+    }
+
+    #[test]
+    fn traces_icmp_targets_concurrently() {
+        assert_traces(&trace_all(Method::Icmp, IpNextHeaderProtocols::Icmp));
+    }
+
+    #[test]
+    fn traces_udp_targets_concurrently() {
+        assert_traces(&trace_all(Method::Udp, IpNextHeaderProtocols::Udp));
+    }
+
+    #[test]
+    fn traces_tcp_targets_concurrently() {
+        assert_traces(&trace_all(Method::Tcp, IpNextHeaderProtocols::Tcp));
+    }
+
+    #[test]
+    fn traces_sctp_targets_concurrently() {
+        assert_traces(&trace_all(Method::Sctp, IpNextHeaderProtocols::Sctp));
+    }
+
+    #[test]
+    fn traces_dccp_targets_concurrently() {
+        assert_traces(&trace_all(Method::Dccp, IpNextHeaderProtocols::Dccp));
+    }
+
+    #[test]
+    fn traces_raw_ip_targets_concurrently() {
+        assert_traces(&trace_all(Method::Raw(47), IpNextHeaderProtocol(47)));
+    }
+
+    #[test]
+    fn parses_targets() {
+        let input = "1.1.1.1\n\n# resolvers\n  example.com  \n";
+
+        assert_eq!(
+            parse_targets(input.as_bytes()),
+            vec!["1.1.1.1", "example.com"]
+        );
+    }
+}
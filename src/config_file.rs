@@ -0,0 +1,323 @@
+use crate::args::Method;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+const SYSTEM_CONFIG: &str = "/etc/traceroute-rust/config.toml";
+
+/// Options which can be set in a config file, either at the top level or in a
+/// `[profile.NAME]` table. Keys are named like the long command line flags.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub method: Option<String>,
+    pub max_hop: Option<u8>,
+    pub tries: Option<u8>,
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub wait: Option<f64>,
+    pub port: Option<u16>,
+    pub first_hop: Option<u8>,
+    pub gap_limit: Option<u8>,
+    pub resolve_hostnames: Option<bool>,
+    pub back: Option<bool>,
+    pub stats: Option<bool>,
+    pub record_route: Option<bool>,
+    pub output: Option<String>,
+    pub merge: Option<bool>,
+    pub workers: Option<usize>,
+    pub interval: Option<u64>,
+    pub max_traces: Option<usize>,
+    pub rate_limit: Option<usize>,
+    pub hysteresis: Option<usize>,
+    pub webhook: Option<String>,
+    pub asn_table: Option<String>,
+    pub geo: Option<String>,
+    pub pps: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub send_wait: Option<f64>,
+}
+
+impl Settings {
+    /// Overrides the options with those set in `other`.
+    fn merge(&mut self, other: Settings) {
+        macro_rules! merge_fields {
+            ($($field:ident),*) => {
+                $(if other.$field.is_some() {
+                    self.$field = other.$field;
+                })*
+            };
+        }
+        merge_fields!(
+            method,
+            max_hop,
+            tries,
+            wait,
+            port,
+            first_hop,
+            gap_limit,
+            resolve_hostnames,
+            back,
+            stats,
+            record_route,
+            output,
+            merge,
+            workers,
+            interval,
+            max_traces,
+            rate_limit,
+            hysteresis,
+            webhook,
+            asn_table,
+            geo,
+            pps,
+            send_wait
+        );
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(method) = &self.method {
+            Method::parse(method)?;
+        }
+        if let Some(output) = &self.output {
+            if !["text", "json", "dot", "graphml"].contains(&output.as_str()) {
+                return Err(format!(
+                    "output must be 'text', 'json', 'dot' or 'graphml', not '{}'",
+                    output
+                ));
+            }
+        }
+        if let Some(wait) = self.wait {
+            if !(wait.is_finite() && wait > 0.0) {
+                return Err(format!(
+                    "wait must be a positive number of seconds, not {}",
+                    wait
+                ));
+            }
+        }
+        if let Some(send_wait) = self.send_wait {
+            if !(send_wait.is_finite() && send_wait > 0.0) {
+                return Err(format!(
+                    "send-wait must be a positive number of seconds, not {}",
+                    send_wait
+                ));
+            }
+        }
+        if let Some(pps) = self.pps {
+            if !(pps.is_finite() && pps > 0.0) {
+                return Err(format!("pps must be a positive number, not {}", pps));
+            }
+        }
+        if self.tries == Some(0) || self.max_hop == Some(0) || self.first_hop == Some(0) {
+            return Err("tries, max-hop and first-hop must be at least 1".to_string());
+        }
+        if let (Some(first_hop), Some(max_hop)) = (self.first_hop, self.max_hop) {
+            if first_hop > max_hop {
+                return Err(format!(
+                    "first-hop {} must not be beyond max-hop {}",
+                    first_hop, max_hop
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Contents of one config file.
+#[derive(Debug, Default)]
+struct ConfigFile {
+    settings: Settings,
+    profiles: HashMap<String, Settings>,
+}
+
+impl ConfigFile {
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut table = match content.parse::<toml::Value>().map_err(|e| e.to_string())? {
+            toml::Value::Table(table) => table,
+            _ => return Err("expected a table".to_string()),
+        };
+
+        let mut profiles = HashMap::new();
+        if let Some(value) = table.remove("profile") {
+            let tables = match value {
+                toml::Value::Table(tables) => tables,
+                _ => return Err("'profile' must be a table of profiles".to_string()),
+            };
+            for (name, value) in tables {
+                let settings =
+                    parse_settings(value).map_err(|e| format!("profile '{}': {}", name, e))?;
+                profiles.insert(name, settings);
+            }
+        }
+
+        Ok(ConfigFile {
+            settings: parse_settings(toml::Value::Table(table))?,
+            profiles,
+        })
+    }
+}
+
+/// Accepts whole seconds like `wait = 2` as well as `wait = 0.5`.
+fn deserialize_seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f64>, D::Error> {
+    match toml::Value::deserialize(deserializer)? {
+        toml::Value::Integer(secs) => Ok(Some(secs as f64)),
+        toml::Value::Float(secs) => Ok(Some(secs)),
+        value => Err(D::Error::custom(format!(
+            "invalid type: {}, expected a number of seconds",
+            value.type_str()
+        ))),
+    }
+}
+
+fn parse_settings(value: toml::Value) -> Result<Settings, String> {
+    let settings = value.try_into::<Settings>().map_err(|e| e.to_string())?;
+    settings.validate()?;
+    Ok(settings)
+}
+
+/// The system and the user config, in increasing precedence.
+pub fn default_config_files() -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from(SYSTEM_CONFIG)];
+    let user_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    if let Some(dir) = user_dir {
+        files.push(dir.join("traceroute-rust").join("config.toml"));
+    }
+    files
+}
+
+/// Reads `default_files` if they exist, then `path` given with `--config`, which must exist,
+/// and merges their options with those of `profile`, later files and the profile taking
+/// precedence.
+pub fn load_settings(
+    default_files: Vec<PathBuf>,
+    path: Option<&str>,
+    profile: Option<&str>,
+) -> Settings {
+    let mut paths = default_files
+        .into_iter()
+        .map(|path| (path, false))
+        .collect::<Vec<_>>();
+    if let Some(path) = path {
+        paths.push((PathBuf::from(path), true));
+    }
+
+    let mut files = Vec::new();
+    for (path, required) in paths {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => continue,
+            Err(e) => panic!("Could not read config file '{}': {}", path.display(), e),
+        };
+        match ConfigFile::parse(&content) {
+            Ok(file) => files.push(file),
+            Err(e) => panic!("Invalid config file '{}': {}", path.display(), e),
+        }
+    }
+
+    match merge_files(files, profile) {
+        Ok(settings) => settings,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+fn merge_files(files: Vec<ConfigFile>, profile: Option<&str>) -> Result<Settings, String> {
+    let mut settings = Settings::default();
+    let mut profiles: HashMap<String, Settings> = HashMap::new();
+    for file in files {
+        settings.merge(file.settings);
+        for (name, profile) in file.profiles {
+            profiles.entry(name).or_default().merge(profile);
+        }
+    }
+
+    if let Some(name) = profile {
+        match profiles.remove(name) {
+            Some(profile) => settings.merge(profile),
+            None => {
+                let mut names = profiles.into_keys().collect::<Vec<_>>();
+                names.sort();
+                return Err(format!(
+                    "Unknown profile '{}', the config files define: {}",
+                    name,
+                    if names.is_empty() {
+                        "none".to_string()
+                    } else {
+                        names.join(", ")
+                    }
+                ));
+            }
+        }
+    }
+
+    // options of different files and the profile have to fit together as well
+    settings.validate()?;
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_overrides_earlier_files() {
+        let system =
+            ConfigFile::parse("tries = 5\nwait = 2.0\n[profile.dc-tcp]\nmethod = \"udp\"\n")
+                .unwrap();
+        let user = ConfigFile::parse(
+            "[profile.dc-tcp]\nmethod = \"tcp\"\nport = 443\nwait = 0.5\n\n[profile.slow]\nwait = 10.0\n",
+        )
+        .unwrap();
+
+        let settings = merge_files(vec![system, user], Some("dc-tcp")).unwrap();
+
+        assert_eq!(settings.method.as_deref(), Some("tcp"));
+        assert_eq!(settings.port, Some(443));
+        assert_eq!(settings.wait, Some(0.5));
+        assert_eq!(settings.tries, Some(5));
+        assert_eq!(settings.max_hop, None);
+    }
+
+    #[test]
+    fn reports_invalid_files() {
+        let error = ConfigFile::parse("[profile.x]\nprot = 80\n").unwrap_err();
+        assert!(
+            error.starts_with("profile 'x': unknown field `prot`"),
+            "{}",
+            error
+        );
+
+        let error = ConfigFile::parse("port = 70000\n").unwrap_err();
+        assert!(error.contains("port"), "{}", error);
+
+        let error = ConfigFile::parse("method = \"quic\"\n").unwrap_err();
+        assert_eq!(
+            error,
+            "method must be 'icmp', 'udp', 'tcp', 'tcpconn', 'sctp' or 'dccp', not 'quic'"
+        );
+
+        let error = ConfigFile::parse("wait = 0\n").unwrap_err();
+        assert_eq!(error, "wait must be a positive number of seconds, not 0");
+        assert_eq!(
+            ConfigFile::parse("wait = 2\n").unwrap().settings.wait,
+            Some(2.0)
+        );
+
+        let error = ConfigFile::parse("first-hop = 10\nmax-hop = 5\n").unwrap_err();
+        assert_eq!(error, "first-hop 10 must not be beyond max-hop 5");
+        let system = ConfigFile::parse("max-hop = 5\n").unwrap();
+        let user = ConfigFile::parse("[profile.far]\nfirst-hop = 10\n").unwrap();
+        let error = merge_files(vec![system, user], Some("far")).unwrap_err();
+        assert_eq!(error, "first-hop 10 must not be beyond max-hop 5");
+
+        let file = ConfigFile::parse("[profile.b]\n[profile.a]\n").unwrap();
+        let error = merge_files(vec![file], Some("c")).unwrap_err();
+        assert_eq!(error, "Unknown profile 'c', the config files define: a, b");
+    }
+}
@@ -0,0 +1,410 @@
+use crate::traceroute::{Hop, Trace};
+use log::{error, warn};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::time::Duration;
+
+#[derive(Debug, PartialEq)]
+enum Kind {
+    Same,
+    Added,
+    Removed,
+    Changed,
+}
+
+/// One line of a diff, hops of the before and after trace which were aligned to each other.
+struct Row<'a> {
+    kind: Kind,
+    before: Option<&'a Hop>,
+    after: Option<&'a Hop>,
+}
+
+impl<'a> Row<'a> {
+    fn new(kind: Kind, before: Option<&'a Hop>, after: Option<&'a Hop>) -> Self {
+        Row {
+            kind,
+            before,
+            after,
+        }
+    }
+}
+
+/// Reads traces as written by `--output json`, one per line or a single pretty printed one.
+pub fn read_traces(path: &str) -> Vec<Trace> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => panic!("Could not read '{}': {}", path, e),
+    };
+
+    let lines = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str::<Trace>)
+        .collect::<Result<Vec<_>, _>>();
+
+    match lines.or_else(|_| serde_json::from_str::<Trace>(&content).map(|trace| vec![trace])) {
+        Ok(traces) => traces,
+        Err(e) => panic!(
+            "'{}' does not contain traces in the JSON format: {}",
+            path, e
+        ),
+    }
+}
+
+/// Compares the traces of two files, pairing them by host unless each file has only one.
+pub fn diff_files(before_path: &str, after_path: &str, rtt_threshold: Duration) {
+    let before = read_traces(before_path);
+    let after = read_traces(after_path);
+
+    let pairs = if before.len() == 1 && after.len() == 1 {
+        vec![(&before[0], &after[0])]
+    } else {
+        before
+            .iter()
+            .filter_map(|trace| {
+                let other = after.iter().find(|other| other.host == trace.host);
+                if other.is_none() {
+                    warn!("No trace to {} in '{}'", trace.host, after_path);
+                }
+                other.map(|other| (trace, other))
+            })
+            .collect()
+    };
+
+    let mut out = io::stdout().lock();
+    for (before, after) in pairs {
+        let result = write_header(&mut out, "---", before_path, before)
+            .and_then(|_| write_header(&mut out, "+++", after_path, after))
+            .and_then(|_| write_diff(&mut out, before, after, rtt_threshold))
+            .and_then(|_| writeln!(out));
+
+        if result.is_err() {
+            error!("Could not write output");
+            return;
+        }
+    }
+}
+
+fn write_header(out: &mut dyn Write, marker: &str, path: &str, trace: &Trace) -> io::Result<()> {
+    writeln!(
+        out,
+        "{} {}: {} ({}), {} hops, destination {}",
+        marker,
+        path,
+        trace.host,
+        trace.destination,
+        trace.hops.len(),
+        if trace.destination_reached {
+            "reached"
+        } else {
+            "not reached"
+        }
+    )
+}
+
+/// Writes the aligned hops, marking added (+), removed (-) and changed (~) hops and RTT
+/// changes of at least `rtt_threshold` (!), followed by where the paths diverge.
+fn write_diff(
+    out: &mut dyn Write,
+    before: &Trace,
+    after: &Trace,
+    rtt_threshold: Duration,
+) -> io::Result<()> {
+    let rows = align(&before.hops, &after.hops);
+
+    for row in &rows {
+        match (row.before, row.after) {
+            (Some(before), Some(after)) if row.kind == Kind::Same => {
+                let ttl = format_ttl(before.ttl, after.ttl);
+                let rtt = format_rtt_change(before, after, rtt_threshold);
+                let marker = if rtt.1 { "!" } else { " " };
+                let line = format!("{} {:>5}  {}  {}", marker, ttl, format_addrs(after), rtt.0);
+                writeln!(out, "{}", line.trim_end())?;
+            }
+            (Some(before), Some(after)) => writeln!(
+                out,
+                "~ {:>5}  {} -> {}",
+                format_ttl(before.ttl, after.ttl),
+                format_addrs(before),
+                format_addrs(after)
+            )?,
+            (Some(hop), None) => writeln!(out, "- {:>5}  {}", hop.ttl, format_addrs(hop))?,
+            (None, Some(hop)) => writeln!(out, "+ {:>5}  {}", hop.ttl, format_addrs(hop))?,
+            (None, None) => {}
+        }
+    }
+
+    for (diverge, converge) in divergences(&rows) {
+        let diverge = match diverge {
+            Some(hop) => format!("after {}", format_addrs(hop)),
+            None => "at the first hop".to_string(),
+        };
+        match converge {
+            Some(hop) => writeln!(
+                out,
+                "Paths diverge {} and re-converge at {}",
+                diverge,
+                format_addrs(hop)
+            )?,
+            None => writeln!(out, "Paths diverge {} and do not re-converge", diverge)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Aligns both hop sequences along their longest common subsequence, hops match when they
+/// share an address or when one of them did not answer at the same TTL.
+fn align<'a>(before: &'a [Hop], after: &'a [Hop]) -> Vec<Row<'a>> {
+    let mut lengths = vec![vec![0usize; after.len() + 1]; before.len() + 1];
+    for i in (0..before.len()).rev() {
+        for j in (0..after.len()).rev() {
+            lengths[i][j] = if hops_match(&before[i], &after[j]) {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut rows = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < before.len() || j < after.len() {
+        if i < before.len()
+            && j < after.len()
+            && hops_match(&before[i], &after[j])
+            && lengths[i][j] == lengths[i + 1][j + 1] + 1
+        {
+            rows.push(Row::new(Kind::Same, Some(&before[i]), Some(&after[j])));
+            i += 1;
+            j += 1;
+        } else if j == after.len() || (i < before.len() && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            rows.push(Row::new(Kind::Removed, Some(&before[i]), None));
+            i += 1;
+        } else {
+            rows.push(Row::new(Kind::Added, None, Some(&after[j])));
+            j += 1;
+        }
+    }
+
+    pair_changes(rows)
+}
+
+/// Turns removed and added hops between the same matching hops into changed hops.
+fn pair_changes(rows: Vec<Row>) -> Vec<Row> {
+    let mut paired = Vec::new();
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+
+    for row in rows
+        .into_iter()
+        .chain(std::iter::once(Row::new(Kind::Same, None, None)))
+    {
+        match row.kind {
+            Kind::Removed => removed.extend(row.before),
+            Kind::Added => added.extend(row.after),
+            _ => {
+                let changed = removed.len().min(added.len());
+                for (before, after) in removed.drain(..changed).zip(added.drain(..changed)) {
+                    paired.push(Row::new(Kind::Changed, Some(before), Some(after)));
+                }
+                paired.extend(
+                    removed
+                        .drain(..)
+                        .map(|hop| Row::new(Kind::Removed, Some(hop), None)),
+                );
+                paired.extend(
+                    added
+                        .drain(..)
+                        .map(|hop| Row::new(Kind::Added, None, Some(hop))),
+                );
+
+                if row.before.is_some() || row.after.is_some() {
+                    paired.push(row);
+                }
+            }
+        }
+    }
+
+    paired
+}
+
+/// The last common hop before and the first common hop after each differing stretch.
+fn divergences<'a>(rows: &[Row<'a>]) -> Vec<(Option<&'a Hop>, Option<&'a Hop>)> {
+    let mut divergences = Vec::new();
+    let mut last_common = None;
+    let mut diverged = false;
+
+    for row in rows {
+        if row.kind == Kind::Same {
+            if diverged {
+                divergences.push((last_common, row.after));
+                diverged = false;
+            }
+            last_common = row.after;
+        } else {
+            diverged = true;
+        }
+    }
+    if diverged {
+        divergences.push((last_common, None));
+    }
+
+    divergences
+}
+
+fn addresses(hop: &Hop) -> BTreeSet<IpAddr> {
+    hop.probes
+        .iter()
+        .flatten()
+        .map(|reply| reply.addr)
+        .collect()
+}
+
+fn hops_match(before: &Hop, after: &Hop) -> bool {
+    let before_addrs = addresses(before);
+    let after_addrs = addresses(after);
+
+    if before_addrs.is_empty() || after_addrs.is_empty() {
+        before.ttl == after.ttl
+    } else {
+        !before_addrs.is_disjoint(&after_addrs)
+    }
+}
+
+fn median_rtt(hop: &Hop) -> Option<Duration> {
+    let mut rtts = hop
+        .probes
+        .iter()
+        .flatten()
+        .map(|reply| reply.rtt)
+        .collect::<Vec<_>>();
+    rtts.sort();
+    rtts.get(rtts.len() / 2).copied()
+}
+
+/// Formats both median RTTs, true if they differ by at least `threshold`.
+fn format_rtt_change(before: &Hop, after: &Hop, threshold: Duration) -> (String, bool) {
+    match (median_rtt(before), median_rtt(after)) {
+        (Some(before), Some(after)) => {
+            let delta = after.as_secs_f64() - before.as_secs_f64();
+            let significant = delta.abs() >= threshold.as_secs_f64();
+            let mut text = format!("{:.3}ms -> {:.3}ms", millis(before), millis(after));
+            if significant {
+                text += &format!(" ({:+.3}ms)", delta * 1000.0);
+            }
+            (text, significant)
+        }
+        _ => (String::new(), false),
+    }
+}
+
+fn format_ttl(before: u8, after: u8) -> String {
+    if before == after {
+        before.to_string()
+    } else {
+        format!("{}/{}", before, after)
+    }
+}
+
+fn format_addrs(hop: &Hop) -> String {
+    let addrs = addresses(hop);
+    if addrs.is_empty() {
+        return "*".to_string();
+    }
+
+    addrs
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{probe_reply, trace_of};
+    use std::net::Ipv4Addr;
+
+    /// Trace through hops 10.0.0.<octet> with the given RTTs in ms, octet 0 is a timeout.
+    fn trace(hops: &[(u8, u64)]) -> Trace {
+        let hops = hops.iter().map(|(octet, rtt)| {
+            vec![(*octet != 0).then(|| probe_reply(Ipv4Addr::new(10, 0, 0, *octet), *rtt))]
+        });
+        trace_of(Ipv4Addr::new(10, 0, 0, 9), hops.collect())
+    }
+
+    fn diff(before: &Trace, after: &Trace) -> String {
+        let mut out = Vec::new();
+        write_diff(&mut out, before, after, Duration::from_millis(10)).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn aligns_shifted_hops() {
+        let before = trace(&[(1, 1), (2, 2), (3, 3), (9, 4)]);
+        let after = trace(&[(1, 1), (5, 2), (6, 3), (3, 4), (9, 5)]);
+
+        assert_eq!(
+            diff(&before, &after),
+            "      1  10.0.0.1  1.000ms -> 1.000ms\n\
+             ~     2  10.0.0.2 -> 10.0.0.5\n\
+             +     3  10.0.0.6\n\
+             \u{20}   3/4  10.0.0.3  3.000ms -> 4.000ms\n\
+             \u{20}   4/5  10.0.0.9  4.000ms -> 5.000ms\n\
+             Paths diverge after 10.0.0.1 and re-converge at 10.0.0.3\n"
+        );
+    }
+
+    #[test]
+    fn flags_rtt_changes_and_ignores_timeouts() {
+        let before = trace(&[(1, 1), (2, 2), (9, 5)]);
+        let after = trace(&[(1, 1), (0, 0), (9, 30)]);
+
+        assert_eq!(
+            diff(&before, &after),
+            "      1  10.0.0.1  1.000ms -> 1.000ms\n\
+             \u{20}     2  *\n\
+             !     3  10.0.0.9  5.000ms -> 30.000ms (+25.000ms)\n"
+        );
+    }
+
+    #[test]
+    fn reports_paths_which_do_not_reconverge() {
+        let before = trace(&[(1, 1), (2, 2), (3, 3)]);
+        let after = trace(&[(1, 1), (4, 2)]);
+
+        let diff = diff(&before, &after);
+        assert!(diff.contains("~     2  10.0.0.2 -> 10.0.0.4\n-     3  10.0.0.3\n"));
+        assert!(diff.ends_with("Paths diverge after 10.0.0.1 and do not re-converge\n"));
+    }
+
+    #[test]
+    fn reads_json_output() {
+        let path = std::env::temp_dir().join(format!("trace-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let traces = [trace(&[(1, 1), (0, 0)]), trace(&[(9, 3)])];
+        let lines = traces
+            .iter()
+            .map(|trace| serde_json::to_string(trace).unwrap() + "\n")
+            .collect::<String>();
+        fs::write(path, lines).unwrap();
+
+        let read = read_traces(path);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(read.len(), 2);
+        assert_eq!(
+            read[0].hops[0].probes[0].as_ref().unwrap().rtt,
+            Duration::from_millis(1)
+        );
+        assert!(read[0].hops[1].probes[0].is_none());
+        assert_eq!(read[1].hops.len(), 1);
+    }
+}
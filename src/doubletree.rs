@@ -0,0 +1,282 @@
+use crate::args::Config;
+use crate::protocols::protocol::TracerouteProtocol;
+use crate::traceroute::{check_limits, next_silent_hops, probe_hop, EndReason, Hop, Trace};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Path segments already known, as used by Doubletree to avoid probing them again.
+///
+/// The local stop set holds every interface seen from this vantage point, the global stop
+/// set holds (interface, destination) pairs and can be shared through a file.
+pub struct StopSets {
+    local: HashSet<IpAddr>,
+    global: HashSet<(IpAddr, IpAddr)>,
+}
+
+impl StopSets {
+    pub fn new() -> Self {
+        StopSets {
+            local: HashSet::new(),
+            global: HashSet::new(),
+        }
+    }
+
+    /// Loads the global stop set from `path`, one "interface destination" pair per line.
+    ///
+    /// A missing file is treated as an empty stop set.
+    pub fn load(path: &str) -> Self {
+        let mut stop_sets = StopSets::new();
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return stop_sets,
+            Err(e) => panic!("Could not read stop set '{}': {}", path, e),
+        };
+
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let mut addrs = line.split_whitespace().map(|addr| addr.parse::<IpAddr>());
+
+            match (addrs.next(), addrs.next()) {
+                (Some(Ok(interface)), Some(Ok(destination))) => {
+                    stop_sets.global.insert((interface, destination));
+                }
+                _ => warn!("Ignoring invalid stop set entry '{}'", line),
+            }
+        }
+
+        stop_sets
+    }
+
+    /// Writes the global stop set to `path`, replacing the file.
+    pub fn save(&self, path: &str) {
+        let mut pairs = self.global.iter().collect::<Vec<_>>();
+        pairs.sort();
+
+        let content = pairs
+            .iter()
+            .map(|(interface, destination)| format!("{} {}\n", interface, destination))
+            .collect::<String>();
+
+        if let Err(e) = fs::write(path, content) {
+            panic!("Could not write stop set '{}': {}", path, e);
+        }
+    }
+
+    fn contains_interface(&self, hop: &Hop) -> bool {
+        replied_addrs(hop).any(|addr| self.local.contains(&addr))
+    }
+
+    fn contains_pair(&self, hop: &Hop, destination: IpAddr) -> bool {
+        replied_addrs(hop).any(|addr| self.global.contains(&(addr, destination)))
+    }
+
+    fn add(&mut self, hop: &Hop, destination: IpAddr) {
+        for addr in replied_addrs(hop) {
+            self.local.insert(addr);
+            self.global.insert((addr, destination));
+        }
+    }
+}
+
+fn replied_addrs(hop: &Hop) -> impl Iterator<Item = IpAddr> + '_ {
+    hop.probes.iter().flatten().map(|reply| reply.addr)
+}
+
+/// Traces the configured host Doubletree style, starting at TTL `start_ttl`.
+///
+/// Probing goes forward until the destination answers or the global stop set knows the rest
+/// of the path, then backward until an interface of the local stop set is hit. Hops which
+/// were not probed are missing from the returned trace.
+pub fn trace_doubletree(
+    config: &Config,
+    mut protocol: Box<dyn TracerouteProtocol>,
+    start_ttl: u8,
+    stop_sets: &Mutex<StopSets>,
+) -> Trace {
+    let mut trace = Trace::new(config);
+    let destination = trace.destination;
+    let start_ttl = start_ttl.max(config.first_hop_ttl);
+    let mut current_seq: u16 = 0;
+    let out = &mut io::sink();
+
+    let mut current_ttl = start_ttl;
+    let mut silent_hops: u8 = 0;
+    loop {
+        let hop = probe_hop(
+            &mut trace,
+            protocol.as_mut(),
+            current_ttl,
+            &mut current_seq,
+            config,
+            out,
+        );
+        let known = stop_sets.lock().unwrap().contains_pair(&hop, destination);
+        silent_hops = next_silent_hops(silent_hops, &hop);
+        trace.hops.push(hop);
+
+        if trace.end_reason.is_some() {
+            break;
+        }
+        if known {
+            info!(
+                "Remaining path to {} is in the global stop set, stopping.",
+                destination
+            );
+            break;
+        }
+        trace.end_reason = check_limits(config, current_ttl, silent_hops);
+        if trace.end_reason.is_some() {
+            break;
+        }
+        current_ttl += 1;
+    }
+
+    let mut current_ttl = start_ttl;
+    while current_ttl > config.first_hop_ttl {
+        current_ttl -= 1;
+
+        let hop = probe_hop(
+            &mut trace,
+            protocol.as_mut(),
+            current_ttl,
+            &mut current_seq,
+            config,
+            out,
+        );
+        let mut stop_sets = stop_sets.lock().unwrap();
+        let known = stop_sets.contains_interface(&hop);
+        stop_sets.add(&hop, destination);
+        trace.hops.push(hop);
+
+        if trace.end_reason == Some(EndReason::SendFailed) {
+            break;
+        }
+        if known {
+            info!("Reached an interface of the local stop set, stopping.");
+            break;
+        }
+    }
+
+    let mut stop_sets = stop_sets.lock().unwrap();
+    for hop in &trace.hops {
+        stop_sets.add(hop, destination);
+    }
+    trace.hops.sort_by_key(|hop| hop.ttl);
+
+    trace
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Method;
+    use crate::batch::trace_targets;
+    use crate::protocols::icmp::IcmpTraceroute;
+    use crate::simulation::{test_config, SimulatedDestination, SimulatedNetwork, SimulatedRouter};
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::transport::TransportChannelType::Layer4;
+    use pnet::transport::TransportProtocol::Ipv4;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn router(addr: Ipv4Addr) -> SimulatedRouter {
+        SimulatedRouter::new(addr, Duration::from_millis(1))
+    }
+
+    fn destination(last_octet: u8) -> SimulatedDestination {
+        SimulatedDestination::new(
+            Ipv4Addr::new(10, 0, 9, last_octet),
+            Duration::from_millis(5),
+        )
+    }
+
+    fn ttls(trace: &Trace) -> Vec<u8> {
+        trace.hops.iter().map(|hop| hop.ttl).collect()
+    }
+
+    #[test]
+    fn backward_probing_stops_at_known_interface() {
+        // three shared routers, then one router per destination
+        let mut network = SimulatedNetwork::new(Layer4(Ipv4(IpNextHeaderProtocols::Icmp)), SOURCE);
+        let shared = (1..=3)
+            .map(|i| network.add_router(router(Ipv4Addr::new(10, 0, 1, i))))
+            .collect::<Vec<_>>();
+        for i in 1..=2 {
+            let own = network.add_router(router(Ipv4Addr::new(10, 0, 2, i)));
+            let path = [&shared[..], &[own]].concat();
+            network.add_destination(destination(i), &path);
+        }
+
+        let mut config = test_config(Ipv4Addr::UNSPECIFIED, Method::Icmp);
+        config.doubletree = Some(4);
+        let targets = vec!["10.0.9.1".to_string(), "10.0.9.2".to_string()];
+        let traces = trace_targets(
+            &config,
+            &targets,
+            &mut network,
+            IpNextHeaderProtocols::Icmp,
+            &|_| {},
+        );
+
+        assert_eq!(ttls(&traces[0]), vec![1, 2, 3, 4, 5]);
+        assert_eq!(ttls(&traces[1]), vec![3, 4, 5]);
+        assert!(traces.iter().all(|trace| trace.destination_reached));
+    }
+
+    #[test]
+    fn forward_probing_stops_at_known_pair() {
+        let dst = Ipv4Addr::new(10, 0, 9, 1);
+        let routers = (1..=4)
+            .map(|i| router(Ipv4Addr::new(10, 0, 1, i)))
+            .collect();
+        let mut protocol = IcmpTraceroute::new();
+        protocol.set_io(Box::new(SimulatedNetwork::linear(
+            protocol.get_protocol(),
+            SOURCE,
+            routers,
+            destination(1),
+        )));
+
+        let mut stop_sets = StopSets::new();
+        stop_sets
+            .global
+            .insert((IpAddr::V4(Ipv4Addr::new(10, 0, 1, 3)), IpAddr::V4(dst)));
+        let stop_sets = Mutex::new(stop_sets);
+
+        let config = test_config(dst, Method::Icmp);
+        let trace = trace_doubletree(&config, Box::new(protocol), 2, &stop_sets);
+
+        assert_eq!(ttls(&trace), vec![1, 2, 3]);
+        assert!(!trace.destination_reached);
+        assert!(stop_sets
+            .lock()
+            .unwrap()
+            .local
+            .contains(&IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1))));
+    }
+
+    #[test]
+    fn saves_and_loads_global_stop_set() {
+        let path = std::env::temp_dir().join(format!("stop-set-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let pair = (
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 9, 1)),
+        );
+
+        let mut stop_sets = StopSets::new();
+        stop_sets.global.insert(pair);
+        stop_sets.save(path);
+        let loaded = StopSets::load(path);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.global, [pair].iter().cloned().collect());
+        assert!(StopSets::load(path).global.is_empty());
+    }
+}
@@ -0,0 +1,408 @@
+use crate::traceroute::Hop;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::convert::TryInto;
+use std::fs;
+use std::net::IpAddr;
+
+/// Marks the start of the metadata, which follows its last occurrence.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// Zeros between the search tree and the data section.
+const DATA_SECTION_SEPARATOR: usize = 16;
+/// Speed of light in optical fiber, in km per ms.
+const FIBER_KM_PER_MS: f64 = 299.792458 / 1.47;
+const EARTH_RADIUS_KM: f64 = 6371.0;
+/// Nesting of maps, arrays and pointers decoded at most, so pointers forming a cycle in a
+/// broken database fail the lookup instead of overflowing the stack.
+const MAX_DECODE_DEPTH: usize = 32;
+
+/// Where an address is located according to the database.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Location {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+}
+
+impl Location {
+    fn coordinates(&self) -> Option<(f64, f64)> {
+        Some((self.latitude?, self.longitude?))
+    }
+}
+
+/// Country, city and coordinates, as far as known, e.g. "DE, Frankfurt, 50.110, 8.680".
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        parts.extend(self.country.clone());
+        parts.extend(self.city.clone());
+        if let Some((latitude, longitude)) = self.coordinates() {
+            parts.push(format!("{:.3}, {:.3}", latitude, longitude));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Geolocation database in the MaxMind DB format, e.g. GeoLite2 City, read from disk.
+pub struct GeoDatabase {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    data_section: usize,
+    ipv6: bool,
+    /// Node for IPv4 addresses, which IPv6 trees keep under ::/96.
+    ipv4_start: usize,
+}
+
+impl GeoDatabase {
+    pub fn load(path: &str) -> Self {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) => panic!("Could not read geolocation database '{}': {}", path, e),
+        };
+
+        match GeoDatabase::parse(data) {
+            Some(database) => database,
+            None => panic!("'{}' is not a MaxMind DB geolocation database.", path),
+        }
+    }
+
+    pub fn parse(data: Vec<u8>) -> Option<Self> {
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = Decoder::new(&data, metadata_start).decode(metadata_start)?;
+
+        let node_count = metadata["node_count"].as_u64()? as usize;
+        let record_size = metadata["record_size"].as_u64()? as usize;
+        if ![24, 28, 32].contains(&record_size) {
+            return None;
+        }
+        let tree_size = node_count * record_size / 4;
+        if tree_size + DATA_SECTION_SEPARATOR > marker {
+            return None;
+        }
+
+        let mut database = GeoDatabase {
+            data,
+            node_count,
+            record_size,
+            data_section: tree_size + DATA_SECTION_SEPARATOR,
+            ipv6: metadata["ip_version"].as_u64()? == 6,
+            ipv4_start: 0,
+        };
+        if database.ipv6 {
+            for _ in 0..96 {
+                if database.ipv4_start >= node_count {
+                    break;
+                }
+                database.ipv4_start = database.read_record(database.ipv4_start, 0)?;
+            }
+        }
+
+        Some(database)
+    }
+
+    /// Location of `addr`, if the database knows any detail of it.
+    pub fn lookup(&self, addr: IpAddr) -> Option<Location> {
+        let (bits, mut node) = match addr {
+            IpAddr::V4(addr) => (u32::from(addr) as u128, self.ipv4_start),
+            IpAddr::V6(addr) if self.ipv6 => (u128::from(addr), 0),
+            IpAddr::V6(_) => return None,
+        };
+        let length = if addr.is_ipv4() { 32 } else { 128 };
+
+        for i in (0..length).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.read_record(node, (bits >> i) as usize & 1)?;
+        }
+        // the node count itself means not found
+        if node <= self.node_count {
+            return None;
+        }
+
+        let offset = self.data_section + node - self.node_count - DATA_SECTION_SEPARATOR;
+        let (record, _) = Decoder::new(&self.data, self.data_section).decode(offset)?;
+        let location = Location {
+            country: record["country"]["iso_code"].as_str().map(String::from),
+            city: record["city"]["names"]["en"].as_str().map(String::from),
+            latitude: record["location"]["latitude"].as_f64(),
+            longitude: record["location"]["longitude"].as_f64(),
+        };
+
+        (location.country.is_some() || location.city.is_some() || location.coordinates().is_some())
+            .then_some(location)
+    }
+
+    /// Left (`bit` 0) or right record of `node`.
+    fn read_record(&self, node: usize, bit: usize) -> Option<usize> {
+        let start = node * self.record_size / 4;
+        let bytes = self.data.get(start..start + self.record_size / 4)?;
+        let be = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .fold(0, |value, byte| value << 8 | *byte as usize)
+        };
+
+        Some(match (self.record_size, bit) {
+            (24, 0) => be(&bytes[..3]),
+            (24, _) => be(&bytes[3..]),
+            // the middle byte holds the most significant bits of both records
+            (28, 0) => (bytes[3] as usize >> 4) << 24 | be(&bytes[..3]),
+            (28, _) => (bytes[3] as usize & 0x0f) << 24 | be(&bytes[4..]),
+            (_, 0) => be(&bytes[..4]),
+            (_, _) => be(&bytes[4..]),
+        })
+    }
+}
+
+/// Decodes the data section format into JSON values.
+struct Decoder<'a> {
+    data: &'a [u8],
+    /// Start of the section pointers are relative to.
+    base: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8], base: usize) -> Self {
+        Decoder { data, base }
+    }
+
+    /// Value at `offset` and the offset following it.
+    fn decode(&self, offset: usize) -> Option<(Value, usize)> {
+        self.decode_nested(offset, 0)
+    }
+
+    /// Like `decode`, for a value inside `depth` maps, arrays or pointers.
+    fn decode_nested(&self, offset: usize, depth: usize) -> Option<(Value, usize)> {
+        if depth > MAX_DECODE_DEPTH {
+            return None;
+        }
+        let control = *self.data.get(offset)?;
+        let mut offset = offset + 1;
+
+        let mut data_type = control >> 5;
+        if data_type == 1 {
+            let (target, next) = self.read_pointer(control, offset)?;
+            let (value, _) = self.decode_nested(target, depth + 1)?;
+            return Some((value, next));
+        }
+        if data_type == 0 {
+            data_type = 7 + self.data.get(offset)?;
+            offset += 1;
+        }
+
+        let (size, offset) = self.read_size(control & 0x1f, offset)?;
+        let bytes = |length: usize| self.data.get(offset..offset + length);
+        let unsigned = |length: usize| {
+            bytes(length).map(|bytes| {
+                bytes
+                    .iter()
+                    .fold(0u128, |value, byte| value << 8 | *byte as u128)
+            })
+        };
+
+        Some(match data_type {
+            2 => (
+                Value::from(std::str::from_utf8(bytes(size)?).ok()?),
+                offset + size,
+            ),
+            3 => (
+                Value::from(f64::from_be_bytes(bytes(8)?.try_into().ok()?)),
+                offset + 8,
+            ),
+            4 => (Value::from(bytes(size)?.to_vec()), offset + size),
+            5 | 6 | 9 => (Value::from(unsigned(size)? as u64), offset + size),
+            8 => (Value::from(unsigned(size)? as u32 as i32), offset + size),
+            // only used for IDs, which do not matter here
+            10 => (Value::Null, offset + size),
+            7 => {
+                let mut map = Map::new();
+                let mut offset = offset;
+                for _ in 0..size {
+                    let (key, next) = self.decode_nested(offset, depth + 1)?;
+                    let (value, next) = self.decode_nested(next, depth + 1)?;
+                    map.insert(key.as_str()?.to_string(), value);
+                    offset = next;
+                }
+                (Value::Object(map), offset)
+            }
+            11 => {
+                let mut array = Vec::with_capacity(size);
+                let mut offset = offset;
+                for _ in 0..size {
+                    let (value, next) = self.decode_nested(offset, depth + 1)?;
+                    array.push(value);
+                    offset = next;
+                }
+                (Value::Array(array), offset)
+            }
+            // the value is the size
+            14 => (Value::from(size != 0), offset),
+            15 => (
+                Value::from(f32::from_be_bytes(bytes(4)?.try_into().ok()?) as f64),
+                offset + 4,
+            ),
+            _ => return None,
+        })
+    }
+
+    /// Sizes from 29 on take up to three more bytes.
+    fn read_size(&self, size: u8, offset: usize) -> Option<(usize, usize)> {
+        let extra = |length: usize| {
+            self.data.get(offset..offset + length).map(|bytes| {
+                bytes
+                    .iter()
+                    .fold(0, |value, byte| value << 8 | *byte as usize)
+            })
+        };
+
+        Some(match size {
+            0..=28 => (size as usize, offset),
+            29 => (29 + extra(1)?, offset + 1),
+            30 => (285 + extra(2)?, offset + 2),
+            _ => (65821 + extra(3)?, offset + 3),
+        })
+    }
+
+    fn read_pointer(&self, control: u8, offset: usize) -> Option<(usize, usize)> {
+        let length = ((control >> 3) & 0x03) as usize + 1;
+        let bytes = self.data.get(offset..offset + length)?;
+        let value = bytes
+            .iter()
+            .fold(0, |value, byte| value << 8 | *byte as usize);
+        let high = (control & 0x07) as usize;
+
+        let target = match length {
+            1 => high << 8 | value,
+            2 => (high << 16 | value) + 2048,
+            3 => (high << 24 | value) + 526336,
+            _ => value,
+        };
+        Some((self.base + target, offset + length))
+    }
+}
+
+/// Distance in km and RTT increase in ms from the previous hop if they are too far apart for
+/// light in fiber to travel there and back in that time, comparing the fastest replies.
+pub fn faster_than_light(previous: &Hop, hop: &Hop) -> Option<(f64, f64)> {
+    let fastest = |hop: &Hop| {
+        hop.probes
+            .iter()
+            .flatten()
+            .filter_map(|reply| Some((reply.geo.as_ref()?.coordinates()?, reply.rtt)))
+            .min_by_key(|(_, rtt)| *rtt)
+    };
+    let (from, previous_rtt) = fastest(previous)?;
+    let (to, rtt) = fastest(hop)?;
+
+    let distance = distance_km(from, to);
+    let rtt_increase = (rtt.as_secs_f64() - previous_rtt.as_secs_f64()) * 1000.0;
+    (2.0 * distance / FIBER_KM_PER_MS > rtt_increase).then_some((distance, rtt_increase))
+}
+
+/// Great-circle distance between two coordinates in degrees.
+fn distance_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (latitude_from, latitude_to) = (from.0.to_radians(), to.0.to_radians());
+    let latitude_delta = latitude_to - latitude_from;
+    let longitude_delta = (to.1 - from.1).to_radians();
+
+    let a = (latitude_delta / 2.0).sin().powi(2)
+        + latitude_from.cos() * latitude_to.cos() * (longitude_delta / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traceroute::ProbeReply;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    /// IPv6 tree with 24 bit records: 192.0.2.0/24 in Frankfurt, 198.51.100.0/24 in New York,
+    /// whose record points to a key of the first one, and 203.0.113.0/24 only known to be in AU.
+    const FIXTURE: &[u8] = include_bytes!("testdata/geo.mmdb");
+
+    fn database() -> GeoDatabase {
+        GeoDatabase::parse(FIXTURE.to_vec()).unwrap()
+    }
+
+    fn hop(ttl: u8, addr: Ipv4Addr, rtt_ms: u64) -> Hop {
+        let addr = IpAddr::V4(addr);
+        Hop {
+            ttl,
+            probes: vec![Some(ProbeReply {
+                addr,
+                rtt: Duration::from_millis(rtt_ms),
+                reply_ttl: None,
+                quoted_ttl: None,
+                geo: database().lookup(addr),
+                recorded_route: Vec::new(),
+                unreachable_code: None,
+            })],
+            stats: None,
+        }
+    }
+
+    #[test]
+    fn looks_up_locations() {
+        let database = database();
+
+        let frankfurt = database.lookup("192.0.2.77".parse().unwrap()).unwrap();
+        assert_eq!(frankfurt.country.as_deref(), Some("DE"));
+        assert_eq!(frankfurt.city.as_deref(), Some("Frankfurt"));
+        assert_eq!(frankfurt.coordinates(), Some((50.11, 8.68)));
+        assert_eq!(frankfurt.to_string(), "DE, Frankfurt, 50.110, 8.680");
+
+        let new_york = database.lookup("198.51.100.1".parse().unwrap()).unwrap();
+        assert_eq!(new_york.city.as_deref(), Some("New York"));
+
+        let australia = database.lookup("203.0.113.9".parse().unwrap()).unwrap();
+        assert_eq!(australia.to_string(), "AU");
+
+        assert_eq!(database.lookup("192.0.3.1".parse().unwrap()), None);
+        assert_eq!(database.lookup("2001:db8::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(GeoDatabase::parse(b"10.0.0.0 8 64500\n".to_vec()).is_none());
+    }
+
+    #[test]
+    fn stops_at_pointer_cycles() {
+        // a pointer to itself, and a map whose value points back to the map
+        let pointer = [0x20, 0x00];
+        let map = [0xe1, 0x41, b'a', 0x20, 0x00];
+
+        assert_eq!(Decoder::new(&pointer, 0).decode(0), None);
+        assert_eq!(Decoder::new(&map, 0).decode(0), None);
+    }
+
+    #[test]
+    fn flags_hops_faster_than_light() {
+        let frankfurt = hop(1, Ipv4Addr::new(192, 0, 2, 1), 10);
+
+        // about 6200 km, at least 61 ms there and back in fiber
+        let (distance, _) =
+            faster_than_light(&frankfurt, &hop(2, Ipv4Addr::new(198, 51, 100, 1), 30)).unwrap();
+        assert!((distance - 6200.0).abs() < 50.0);
+        assert_eq!(
+            faster_than_light(&frankfurt, &hop(2, Ipv4Addr::new(198, 51, 100, 1), 80)),
+            None
+        );
+        // without coordinates nothing can be said
+        assert_eq!(
+            faster_than_light(&frankfurt, &hop(2, Ipv4Addr::new(203, 0, 113, 1), 10)),
+            None
+        );
+    }
+}
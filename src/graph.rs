@@ -0,0 +1,367 @@
+use crate::args::{Config, Output};
+use crate::asn::AsnTable;
+use crate::dns::ip_to_hostname;
+use crate::traceroute::{Hop, Trace};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+
+struct Node {
+    /// The address, or None for hops which did not answer.
+    addr: Option<IpAddr>,
+    hostname: Option<String>,
+    asn: Option<u32>,
+    destination: bool,
+}
+
+/// Topology of one or more traces, one node per answering address.
+pub struct Graph {
+    name: String,
+    nodes: BTreeMap<String, Node>,
+    /// RTT differences in ms between the nodes of an edge, one per trace crossing it.
+    edges: BTreeMap<(String, String), Vec<f64>>,
+}
+
+impl Graph {
+    pub fn new(name: &str) -> Self {
+        Graph {
+            name: name.to_string(),
+            nodes: BTreeMap::new(),
+            edges: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the hops of `trace`, linking every address of a hop to every one of the next.
+    ///
+    /// Hops without any reply become an anonymous node per trace and TTL.
+    pub fn add_trace(&mut self, trace: &Trace, asns: Option<&AsnTable>, resolve_hostnames: bool) {
+        let mut previous: Vec<(String, Option<f64>)> = Vec::new();
+
+        for hop in &trace.hops {
+            let mut current = Vec::new();
+
+            for (addr, rtt) in median_rtts(hop) {
+                let id = addr.to_string();
+                let node = self.nodes.entry(id.clone()).or_insert_with(|| Node {
+                    addr: Some(addr),
+                    hostname: resolve_hostnames.then(|| ip_to_hostname(&addr)).flatten(),
+                    asn: match (asns, addr) {
+                        (Some(asns), IpAddr::V4(addr)) => asns.lookup(addr),
+                        _ => None,
+                    },
+                    destination: false,
+                });
+                node.destination |= addr == trace.destination;
+                current.push((id, Some(rtt)));
+            }
+
+            if current.is_empty() {
+                let id = format!("* {} {}", trace.destination, hop.ttl);
+                self.nodes.entry(id.clone()).or_insert(Node {
+                    addr: None,
+                    hostname: None,
+                    asn: None,
+                    destination: false,
+                });
+                current.push((id, None));
+            }
+
+            for (from, from_rtt) in &previous {
+                for (to, to_rtt) in &current {
+                    let deltas = self.edges.entry((from.clone(), to.clone())).or_default();
+                    if let (Some(from_rtt), Some(to_rtt)) = (from_rtt, to_rtt) {
+                        deltas.push(to_rtt - from_rtt);
+                    }
+                }
+            }
+            previous = current;
+        }
+    }
+}
+
+/// Median RTT in ms per address which answered the hop.
+fn median_rtts(hop: &Hop) -> BTreeMap<IpAddr, f64> {
+    let mut rtts = BTreeMap::<IpAddr, Vec<f64>>::new();
+    for reply in hop.probes.iter().flatten() {
+        rtts.entry(reply.addr)
+            .or_default()
+            .push(reply.rtt.as_secs_f64() * 1000.0);
+    }
+
+    rtts.into_iter()
+        .map(|(addr, mut rtts)| {
+            rtts.sort_by(f64::total_cmp);
+            (addr, rtts[rtts.len() / 2])
+        })
+        .collect()
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+fn node_labels(node: &Node) -> Vec<String> {
+    let mut labels = vec![node.addr.map_or("*".to_string(), |addr| addr.to_string())];
+    labels.extend(node.hostname.clone());
+    labels.extend(node.asn.map(|asn| format!("AS{}", asn)));
+    labels
+}
+
+/// Renders the graphs in the DOT language, one digraph each.
+pub fn render_dot(graphs: &[Graph]) -> String {
+    let mut out = String::new();
+
+    for graph in graphs {
+        let _ = writeln!(out, "digraph \"{}\" {{", escape_dot(&graph.name));
+        let _ = writeln!(out, "  rankdir=LR;");
+        for (id, node) in &graph.nodes {
+            let label = node_labels(node)
+                .iter()
+                .map(|label| escape_dot(label))
+                .collect::<Vec<_>>()
+                .join("\\n");
+            let style = match (node.addr, node.destination) {
+                (None, _) => ", style=dashed",
+                (Some(_), true) => ", peripheries=2",
+                (Some(_), false) => "",
+            };
+            let _ = writeln!(
+                out,
+                "  \"{}\" [label=\"{}\"{}];",
+                escape_dot(id),
+                label,
+                style
+            );
+        }
+        for ((from, to), deltas) in &graph.edges {
+            let label = mean(deltas).map_or(String::new(), |delta| {
+                format!(" [label=\"{:+.3}ms\"]", delta)
+            });
+            let _ = writeln!(
+                out,
+                "  \"{}\" -> \"{}\"{};",
+                escape_dot(from),
+                escape_dot(to),
+                label
+            );
+        }
+        let _ = writeln!(out, "}}");
+    }
+
+    out
+}
+
+/// Renders the graphs as one GraphML document.
+///
+/// Ids have to be unique within the document, so they are prefixed with the index of their
+/// graph, the name of a graph is kept as its `name`.
+pub fn render_graphml(graphs: &[Graph]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+    let _ = writeln!(
+        out,
+        "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">"
+    );
+    for (id, target, attr_type) in &[
+        ("name", "graph", "string"),
+        ("label", "node", "string"),
+        ("address", "node", "string"),
+        ("hostname", "node", "string"),
+        ("asn", "node", "long"),
+        ("destination", "node", "boolean"),
+        ("rtt_delta_ms", "edge", "double"),
+        ("traces", "edge", "int"),
+    ] {
+        let _ = writeln!(
+            out,
+            "  <key id=\"{0}\" for=\"{1}\" attr.name=\"{0}\" attr.type=\"{2}\"/>",
+            id, target, attr_type
+        );
+    }
+
+    for (index, graph) in graphs.iter().enumerate() {
+        let node_id = |id: &str| escape_xml(&format!("g{}:{}", index, id));
+        let _ = writeln!(out, "  <graph id=\"g{}\" edgedefault=\"directed\">", index);
+        let _ = writeln!(
+            out,
+            "    <data key=\"name\">{}</data>",
+            escape_xml(&graph.name)
+        );
+        for (id, node) in &graph.nodes {
+            let _ = writeln!(out, "    <node id=\"{}\">", node_id(id));
+            write_data(&mut out, "label", &node_labels(node).join(" "));
+            if let Some(addr) = node.addr {
+                write_data(&mut out, "address", &addr.to_string());
+            }
+            if let Some(hostname) = &node.hostname {
+                write_data(&mut out, "hostname", hostname);
+            }
+            if let Some(asn) = node.asn {
+                write_data(&mut out, "asn", &asn.to_string());
+            }
+            write_data(&mut out, "destination", &node.destination.to_string());
+            let _ = writeln!(out, "    </node>");
+        }
+        for ((from, to), deltas) in &graph.edges {
+            let _ = writeln!(
+                out,
+                "    <edge source=\"{}\" target=\"{}\">",
+                node_id(from),
+                node_id(to)
+            );
+            if let Some(delta) = mean(deltas) {
+                write_data(&mut out, "rtt_delta_ms", &format!("{:.3}", delta));
+                write_data(&mut out, "traces", &deltas.len().to_string());
+            }
+            let _ = writeln!(out, "    </edge>");
+        }
+        let _ = writeln!(out, "  </graph>");
+    }
+
+    let _ = writeln!(out, "</graphml>");
+    out
+}
+
+fn write_data(out: &mut String, key: &str, value: &str) {
+    let _ = writeln!(
+        out,
+        "      <data key=\"{}\">{}</data>",
+        key,
+        escape_xml(value)
+    );
+}
+
+fn escape_dot(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Prints the traces as graphs in the configured format, merged into one with `--merge`.
+pub fn print_graphs(traces: &[Trace], config: &Config) {
+    let asns = config.asn_table.as_deref().map(AsnTable::load);
+    let mut graphs = Vec::new();
+
+    if config.merge {
+        let mut graph = Graph::new("traceroute");
+        for trace in traces {
+            graph.add_trace(trace, asns.as_ref(), config.resolve_hostnames);
+        }
+        graphs.push(graph);
+    } else {
+        for trace in traces {
+            let mut graph = Graph::new(&trace.host);
+            graph.add_trace(trace, asns.as_ref(), config.resolve_hostnames);
+            graphs.push(graph);
+        }
+    }
+
+    match config.output {
+        Output::Graphml => print!("{}", render_graphml(&graphs)),
+        _ => print!("{}", render_dot(&graphs)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{probe_reply, trace_of};
+    use std::collections::BTreeSet;
+    use std::net::Ipv4Addr;
+
+    fn addresses(graph: &Graph) -> BTreeSet<String> {
+        graph.nodes.keys().cloned().collect()
+    }
+
+    /// Trace to 10.0.9.<last> through hops answered by 10.0.<ttl>.<octet> with RTT ttl ms.
+    fn trace(hops: &[&[u8]]) -> Trace {
+        let hops = hops.iter().enumerate().map(|(i, octets)| {
+            let ttl = i as u8 + 1;
+            let probes = octets.iter().map(|octet| {
+                Some(probe_reply(
+                    Ipv4Addr::new(10, 0, ttl, *octet),
+                    ttl as u64 * 2,
+                ))
+            });
+            if octets.is_empty() {
+                vec![None]
+            } else {
+                probes.collect()
+            }
+        });
+        trace_of(Ipv4Addr::new(10, 0, 3, 1), hops.collect())
+    }
+
+    #[test]
+    fn renders_dot() {
+        let mut asns = AsnTable::new();
+        asns.insert(Ipv4Addr::new(10, 0, 2, 0), 24, 64500);
+        let mut graph = Graph::new("example.com");
+        graph.add_trace(&trace(&[&[1], &[1, 2], &[1]]), Some(&asns), false);
+
+        assert_eq!(
+            render_dot(&[graph]),
+            "digraph \"example.com\" {\n  rankdir=LR;\n  \
+             \"10.0.1.1\" [label=\"10.0.1.1\"];\n  \
+             \"10.0.2.1\" [label=\"10.0.2.1\\nAS64500\"];\n  \
+             \"10.0.2.2\" [label=\"10.0.2.2\\nAS64500\"];\n  \
+             \"10.0.3.1\" [label=\"10.0.3.1\", peripheries=2];\n  \
+             \"10.0.1.1\" -> \"10.0.2.1\" [label=\"+2.000ms\"];\n  \
+             \"10.0.1.1\" -> \"10.0.2.2\" [label=\"+2.000ms\"];\n  \
+             \"10.0.2.1\" -> \"10.0.3.1\" [label=\"+2.000ms\"];\n  \
+             \"10.0.2.2\" -> \"10.0.3.1\" [label=\"+2.000ms\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn merges_traces() {
+        let mut graph = Graph::new("traceroute");
+        graph.add_trace(&trace(&[&[1], &[1], &[1]]), None, false);
+        graph.add_trace(&trace(&[&[1], &[], &[1]]), None, false);
+
+        assert_eq!(
+            addresses(&graph),
+            ["* 10.0.3.1 2", "10.0.1.1", "10.0.2.1", "10.0.3.1"]
+                .iter()
+                .map(|id| id.to_string())
+                .collect()
+        );
+        assert_eq!(graph.edges.len(), 4);
+        assert_eq!(
+            graph.edges[&("10.0.1.1".to_string(), "10.0.2.1".to_string())],
+            vec![2.0]
+        );
+        // no RTT delta next to a hop without reply
+        assert!(graph.edges[&("10.0.1.1".to_string(), "* 10.0.3.1 2".to_string())].is_empty());
+    }
+
+    #[test]
+    fn renders_graphml() {
+        let mut graph = Graph::new("a<b");
+        graph.add_trace(&trace(&[&[1], &[], &[1]]), None, false);
+        let mut other = Graph::new("a<b");
+        other.add_trace(&trace(&[&[1]]), None, false);
+        let graphml = render_graphml(&[graph, other]);
+
+        assert!(graphml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<graphml"));
+        assert!(graphml.contains(
+            "<graph id=\"g0\" edgedefault=\"directed\">\n    <data key=\"name\">a&lt;b</data>"
+        ));
+        assert!(graphml.contains("<graph id=\"g1\" edgedefault=\"directed\">"));
+        // the first hop is in both graphs
+        assert!(graphml
+            .contains("<node id=\"g0:10.0.1.1\">\n      <data key=\"label\">10.0.1.1</data>"));
+        assert!(graphml.contains("<node id=\"g1:10.0.1.1\">"));
+        assert!(graphml
+            .contains("<edge source=\"g0:10.0.1.1\" target=\"g0:* 10.0.3.1 2\">\n    </edge>"));
+        assert!(graphml.contains("<data key=\"destination\">true</data>"));
+        assert!(graphml.trim_end().ends_with("</graphml>"));
+    }
+}
@@ -1,5 +1,5 @@
 use pnet::datalink::{interfaces, NetworkInterface};
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
 
 pub fn get_source_ip() -> Ipv4Addr {
     let all_interfaces = interfaces();
@@ -23,6 +23,22 @@ pub fn get_source_ip() -> Ipv4Addr {
     source_ip
 }
 
+/// Source IP the kernel uses for packets towards `dst`, falls back to `get_source_ip`.
+pub fn get_source_ip_for(dst: IpAddr) -> Ipv4Addr {
+    // connecting a UDP socket only performs the route lookup, no packet is sent
+    let local_addr = UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| socket.connect((dst, 33434)).map(|_| socket))
+        .and_then(|socket| socket.local_addr());
+
+    match local_addr {
+        Ok(local_addr) => match local_addr.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => get_source_ip(),
+        },
+        Err(_) => get_source_ip(),
+    }
+}
+
 pub fn addr_to_ipv4_addr(addr: IpAddr) -> Ipv4Addr {
     match addr {
         IpAddr::V4(ipv4) => ipv4,
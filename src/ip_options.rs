@@ -0,0 +1,288 @@
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::Packet;
+use std::net::Ipv4Addr;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+pub const OPTION_RECORD_ROUTE: u8 = 7;
+pub const OPTION_LOOSE_SOURCE_ROUTE: u8 = 131;
+/// Bytes available for options after the fixed 20 bytes of the IP header.
+const MAX_OPTIONS_LENGTH: usize = 40;
+/// Addresses fitting into the 40 bytes of options next to a route option's own 3 bytes.
+pub const MAX_ROUTE_ADDRESSES: usize = 9;
+/// Offset of the first address in the option, as counted by its pointer which starts at 1.
+pub const FIRST_ADDRESS_POINTER: u8 = 4;
+
+/// IPv4 options carried by every probe, which takes IP headers of our own to send.
+#[derive(Clone, Default)]
+pub struct IpOptions {
+    /// Let routers record their addresses on the way, see RFC 791.
+    pub record_route: bool,
+    /// Gateways the probes are routed through before going on to the destination.
+    pub loose_source_route: Vec<Ipv4Addr>,
+}
+
+impl IpOptions {
+    pub fn is_empty(&self) -> bool {
+        !self.record_route && self.loose_source_route.is_empty()
+    }
+
+    /// Where probes towards `dst` go first, i.e. the destination of their IP header.
+    pub fn first_hop(&self, dst: Ipv4Addr) -> Ipv4Addr {
+        self.loose_source_route.first().copied().unwrap_or(dst)
+    }
+
+    /// Addresses Record Route has room for next to the source route, which takes precedence.
+    pub fn record_route_slots(&self) -> usize {
+        let source_route_length = match self.loose_source_route.len() {
+            0 => 0,
+            gateways => 3 + 4 * gateways,
+        };
+        MAX_OPTIONS_LENGTH.saturating_sub(source_route_length + 3) / 4
+    }
+
+    /// The options of probes towards `dst` as they follow the fixed IP header, padded to whole
+    /// 32-bit words.
+    pub fn encode(&self, dst: Ipv4Addr) -> Vec<u8> {
+        let mut options = Vec::new();
+
+        if !self.loose_source_route.is_empty() {
+            // the first gateway is the destination of the header, the final destination comes last
+            let route: Vec<Ipv4Addr> = self.loose_source_route[1..]
+                .iter()
+                .copied()
+                .chain(Some(dst))
+                .collect();
+            options.push(OPTION_LOOSE_SOURCE_ROUTE);
+            options.push(3 + 4 * route.len() as u8);
+            options.push(FIRST_ADDRESS_POINTER);
+            for address in route {
+                options.extend_from_slice(&address.octets());
+            }
+        }
+
+        if self.record_route {
+            let slots = self.record_route_slots();
+            options.push(OPTION_RECORD_ROUTE);
+            options.push(3 + 4 * slots as u8);
+            options.push(FIRST_ADDRESS_POINTER);
+            options.resize(options.len() + 4 * slots, 0);
+        }
+
+        while options.len() % 4 != 0 {
+            options.push(OPTION_END);
+        }
+        options
+    }
+}
+
+/// Start within the header and length of the first option of `option_type` in the IPv4 header
+/// `header`.
+pub fn find_option(header: &Ipv4Packet, option_type: u8) -> Option<(usize, usize)> {
+    let header_length = header.get_header_length() as usize * 4;
+    let bytes = header.packet().get(20..header_length)?;
+
+    let mut offset = 0;
+    while let Some(&current_type) = bytes.get(offset) {
+        match current_type {
+            OPTION_END => break,
+            OPTION_NOP => offset += 1,
+            _ => {
+                let length = *bytes.get(offset + 1)? as usize;
+                if length < 2 || offset + length > bytes.len() {
+                    return None;
+                }
+                if current_type == option_type {
+                    return Some((20 + offset, length));
+                }
+                offset += length;
+            }
+        }
+    }
+
+    None
+}
+
+/// Addresses recorded so far in the Record Route option of `header`, if it has one.
+pub fn recorded_route(header: &Ipv4Packet) -> Option<Vec<Ipv4Addr>> {
+    let (start, length) = find_option(header, OPTION_RECORD_ROUTE)?;
+    let option = &header.packet()[start..start + length];
+
+    // the pointer names the next free slot, or points past the end if all are taken
+    let end = (option.get(2).copied()? as usize).clamp(FIRST_ADDRESS_POINTER as usize, length + 1);
+    let addresses = option[FIRST_ADDRESS_POINTER as usize - 1..end - 1]
+        .chunks_exact(4)
+        .map(|address| Ipv4Addr::new(address[0], address[1], address[2], address[3]))
+        .collect();
+
+    Some(addresses)
+}
+
+/// Final destination of the packet with IP header `header`, which differs from the destination
+/// of the header while a source route has addresses left.
+pub fn final_destination(header: &Ipv4Packet) -> Ipv4Addr {
+    let (start, length) = match find_option(header, OPTION_LOOSE_SOURCE_ROUTE) {
+        Some(option) => option,
+        None => return header.get_destination(),
+    };
+    // a truncated route has no address to take the destination from
+    if length < 7 || (length - 3) % 4 != 0 {
+        return header.get_destination();
+    }
+    let option = &header.packet()[start..start + length];
+
+    // each gateway overwrites the address it routes on to with its own, so the last address is
+    // the final destination until the route is complete
+    let pointer = option[2] as usize;
+    if pointer < FIRST_ADDRESS_POINTER as usize || pointer > length - 3 {
+        return header.get_destination();
+    }
+    let last = &option[length - 4..];
+    Ipv4Addr::new(last[0], last[1], last[2], last[3])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_io::{build_ipv4_packet_with_options, ReceivedPacket};
+    use crate::protocols::icmp::process_icmp_message;
+    use crate::protocols::protocol::ReceiveStatus;
+    use crate::simulation::{follow_source_route, record_address};
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use std::net::IpAddr;
+    use std::time::Instant;
+
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 1);
+
+    fn record_route() -> IpOptions {
+        IpOptions {
+            record_route: true,
+            ..IpOptions::default()
+        }
+    }
+
+    fn packet(options: &[u8]) -> Vec<u8> {
+        build_ipv4_packet_with_options(
+            Ipv4Addr::new(192, 0, 2, 1),
+            DESTINATION,
+            64,
+            IpNextHeaderProtocols::Icmp,
+            options,
+            &[8, 0, 0, 0, 0, 0, 0, 0],
+        )
+    }
+
+    #[test]
+    fn encodes_record_route_with_all_slots() {
+        let options = record_route().encode(DESTINATION);
+
+        assert_eq!(options.len(), 40);
+        assert_eq!(options[..3], [OPTION_RECORD_ROUTE, 39, 4]);
+        assert!(IpOptions::default().encode(DESTINATION).is_empty());
+    }
+
+    #[test]
+    fn records_until_the_option_is_full() {
+        let mut packet = packet(&record_route().encode(DESTINATION));
+        assert_eq!(
+            recorded_route(&Ipv4Packet::new(&packet).unwrap()),
+            Some(vec![])
+        );
+
+        for i in 1..=MAX_ROUTE_ADDRESSES as u8 {
+            assert!(record_address(&mut packet, Ipv4Addr::new(10, 0, 0, i)));
+        }
+        assert!(!record_address(&mut packet, Ipv4Addr::new(10, 0, 0, 10)));
+
+        let route = recorded_route(&Ipv4Packet::new(&packet).unwrap()).unwrap();
+        assert_eq!(route.len(), MAX_ROUTE_ADDRESSES);
+        assert_eq!(route[8], Ipv4Addr::new(10, 0, 0, 9));
+    }
+
+    #[test]
+    fn source_route_leads_through_gateways_to_the_destination() {
+        let gateways = vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)];
+        let options = IpOptions {
+            record_route: true,
+            loose_source_route: gateways.clone(),
+        };
+        assert_eq!(options.first_hop(DESTINATION), gateways[0]);
+        assert_eq!(options.record_route_slots(), 6);
+
+        let encoded = options.encode(DESTINATION);
+        assert_eq!(encoded.len(), 40);
+        assert_eq!(encoded[..3], [OPTION_LOOSE_SOURCE_ROUTE, 11, 4]);
+        assert_eq!(encoded[3..7], gateways[1].octets());
+        assert_eq!(encoded[7..11], DESTINATION.octets());
+        assert_eq!(encoded[11..14], [OPTION_RECORD_ROUTE, 27, 4]);
+
+        // sent to the first gateway, which routes it on to the second and then the destination
+        let mut packet = build_ipv4_packet_with_options(
+            Ipv4Addr::new(192, 0, 2, 1),
+            gateways[0],
+            64,
+            IpNextHeaderProtocols::Icmp,
+            &encoded,
+            &[8, 0, 0, 0, 0, 0, 0, 0],
+        );
+        for gateway in &gateways {
+            assert_eq!(
+                final_destination(&Ipv4Packet::new(&packet).unwrap()),
+                DESTINATION
+            );
+            assert!(follow_source_route(&mut packet, *gateway));
+        }
+        let header = Ipv4Packet::new(&packet).unwrap();
+        assert_eq!(header.get_destination(), DESTINATION);
+        assert_eq!(final_destination(&header), DESTINATION);
+        assert!(!follow_source_route(&mut packet, DESTINATION));
+    }
+
+    #[test]
+    fn truncated_source_route_in_quoted_header_is_ignored() {
+        let router = Ipv4Addr::new(10, 0, 0, 1);
+        for options in [
+            vec![OPTION_LOOSE_SOURCE_ROUTE, 2, OPTION_NOP, OPTION_NOP],
+            vec![OPTION_LOOSE_SOURCE_ROUTE, 6, 4, 10, 0, 0, 2, OPTION_END],
+        ] {
+            let quoted = packet(&options);
+            assert_eq!(
+                final_destination(&Ipv4Packet::new(&quoted).unwrap()),
+                DESTINATION
+            );
+
+            let mut time_exceeded = vec![11, 0, 0, 0, 0, 0, 0, 0];
+            time_exceeded.extend_from_slice(&quoted);
+            let received = ReceivedPacket::new(
+                build_ipv4_packet_with_options(
+                    router,
+                    Ipv4Addr::new(192, 0, 2, 1),
+                    64,
+                    IpNextHeaderProtocols::Icmp,
+                    &[],
+                    &time_exceeded,
+                ),
+                Instant::now(),
+            );
+            let result = process_icmp_message(&received, IpAddr::V4(DESTINATION), None).unwrap();
+            assert!(result.status == ReceiveStatus::SuccessContinue);
+        }
+    }
+
+    #[test]
+    fn skips_other_options() {
+        // NOP, then a timestamp option which is not understood
+        let mut options = vec![OPTION_NOP, 68, 4, 5, 0];
+        options.extend_from_slice(&[OPTION_RECORD_ROUTE, 7, 8, 10, 0, 0, 1, 0, 0, 0]);
+        let packet = packet(&options);
+
+        assert_eq!(
+            recorded_route(&Ipv4Packet::new(&packet).unwrap()),
+            Some(vec![Ipv4Addr::new(10, 0, 0, 1)])
+        );
+        assert_eq!(
+            recorded_route(&Ipv4Packet::new(&self::packet(&[])).unwrap()),
+            None
+        );
+    }
+}
@@ -1,32 +1,198 @@
+mod api;
 mod args;
+mod asn;
+mod batch;
+mod config_file;
+mod diff;
 mod protocols {
+    pub mod application;
+    pub mod dccp;
     pub mod icmp;
     pub mod protocol;
+    pub mod raw;
+    pub mod sctp;
     pub mod tcp;
+    pub mod tcp_connection;
     pub mod udp;
 }
 mod traceroute;
 
 mod dns;
+mod doubletree;
+mod geo;
+mod graph;
 mod interfaces;
+mod ip_options;
+mod metrics;
+mod monitor;
+mod pacing;
+mod packet_io;
+mod pcap;
+mod replay;
+#[cfg(test)]
+mod simulation;
+mod stats;
+#[cfg(target_os = "linux")]
+mod timestamps;
+mod ttl;
+mod yarrp;
 
-use args::Config;
+use args::{Config, Output};
+use dns::hostname_to_ip;
+use interfaces::{get_source_ip, get_source_ip_for};
+use pacing::{PacedIo, TokenBucket};
+use packet_io::{get_next_level_protocol, PacketIo, RawSocketIo};
+use pcap::CapturingIo;
+use protocols::dccp::DccpTraceroute;
 use protocols::icmp::IcmpTraceroute;
 use protocols::protocol::TracerouteProtocol;
+use protocols::raw::RawIpTraceroute;
+use protocols::sctp::SctpTraceroute;
 use protocols::tcp::TcpTraceroute;
+use protocols::tcp_connection::TcpConnectionTraceroute;
 use protocols::udp::UdpTraceroute;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use traceroute::Trace;
 
 fn main() {
     init_logging();
     let config = parse_config();
 
-    let protocol: Box<dyn TracerouteProtocol> = match config.method {
-        args::Method::ICMP => Box::new(IcmpTraceroute::new()),
-        args::Method::UDP => Box::new(UdpTraceroute::new(config.port)),
-        args::Method::TCP => Box::new(TcpTraceroute::new(config.port)),
+    if let Some((before, after)) = &config.diff {
+        let threshold = Duration::from_secs_f64(config.rtt_threshold_ms / 1000.0);
+        diff::diff_files(before, after, threshold);
+        return;
+    }
+
+    if let Some(path) = config.replay.clone() {
+        replay::replay_traceroute(config, &path);
+        return;
+    }
+
+    if let Some(listen) = config.serve_api.clone() {
+        // all traces requested through the API share the send rate
+        let bucket = config
+            .max_pps
+            .map(|pps| Arc::new(Mutex::new(TokenBucket::new(pps))));
+        let ip_options = config.ip_options.clone();
+        let open_io = move |protocol, reply_protocol| {
+            let io: Box<dyn PacketIo> =
+                Box::new(RawSocketIo::open(protocol, reply_protocol, &ip_options));
+            match &bucket {
+                Some(bucket) => Box::new(PacedIo::new(
+                    io,
+                    bucket.clone(),
+                    get_next_level_protocol(protocol),
+                )) as Box<dyn PacketIo>,
+                None => io,
+            }
+        };
+        let api = Arc::new(api::Api::new(config, Box::new(open_io)));
+        api.serve(metrics::start_server(&listen));
+        return;
+    }
+
+    let mut protocol = create_protocol(&config);
+    let next_level_protocol = get_next_level_protocol(protocol.get_protocol());
+
+    let mut io: Box<dyn PacketIo> = Box::new(RawSocketIo::open(
+        protocol.get_protocol(),
+        protocol.get_reply_protocol(),
+        &config.ip_options,
+    ));
+    if let Some(path) = &config.pcap_out {
+        // batches have many destinations, so use the source of the default route there
+        let source = if config.targets.is_some() || config.stateless {
+            get_source_ip()
+        } else {
+            // with a source route, probes leave towards the first gateway
+            match hostname_to_ip(&config.host) {
+                IpAddr::V4(dst) => get_source_ip_for(IpAddr::V4(config.ip_options.first_hop(dst))),
+                dst => get_source_ip_for(dst),
+            }
+        };
+        io = Box::new(
+            CapturingIo::create(io, path, next_level_protocol, source)
+                .with_ip_options(&config.ip_options),
+        );
+    }
+    if let Some(pps) = config.max_pps {
+        let bucket = Arc::new(Mutex::new(TokenBucket::new(pps)));
+        io = Box::new(PacedIo::new(io, bucket, next_level_protocol));
+    }
+
+    if config.stateless {
+        let targets = match &config.targets {
+            Some(path) => batch::read_targets(path),
+            None => vec![config.host.clone()],
+        };
+        protocol.set_io(io);
+        let targets = yarrp::expand_targets(&targets);
+        // checksums of probes cover the source, take that of the default route like captures
+        yarrp::trace_stateless(&config, protocol.as_mut(), get_source_ip(), &targets);
+        return;
+    }
+
+    if config.serve_metrics.is_some() || config.monitor {
+        let targets = match &config.targets {
+            Some(path) => batch::read_targets(path),
+            None => vec![config.host.clone()],
+        };
+        match &config.serve_metrics {
+            Some(listen) => {
+                metrics::run_exporter(&config, listen, &targets, io.as_mut(), next_level_protocol)
+            }
+            None => monitor::run_monitor(&config, &targets, io.as_mut(), next_level_protocol),
+        }
+        return;
+    }
+
+    if config.targets.is_none() && config.doubletree.is_none() {
+        protocol.set_io(io);
+        traceroute::do_traceroute(config, protocol);
+        return;
+    }
+
+    // graphs may merge all traces, so they are only printed once the batch is complete
+    let graph_output = matches!(config.output, Output::Dot | Output::Graphml);
+    let report = |trace: &Trace| {
+        if !graph_output {
+            traceroute::print_result(trace, &config)
+        }
     };
 
-    traceroute::do_traceroute(config, protocol);
+    let targets = match &config.targets {
+        Some(path) => batch::read_targets(path),
+        // the stop sets are only kept by batches, so run a batch of one
+        None => vec![config.host.clone()],
+    };
+    let traces = batch::trace_targets(&config, &targets, io.as_mut(), next_level_protocol, &report);
+    if graph_output {
+        graph::print_graphs(&traces, &config);
+    }
+}
+
+fn create_protocol(config: &Config) -> Box<dyn TracerouteProtocol> {
+    match config.method {
+        args::Method::Icmp => Box::new(IcmpTraceroute::new()),
+        args::Method::Udp => Box::new(UdpTraceroute::with_application(
+            config.port,
+            config.udp_template,
+        )),
+        args::Method::Tcp => Box::new(TcpTraceroute::new(config.port)),
+        args::Method::TcpConnection => {
+            Box::new(TcpConnectionTraceroute::new(config.port, config.local_port))
+        }
+        args::Method::Sctp => Box::new(SctpTraceroute::new(config.port)),
+        args::Method::Dccp => Box::new(DccpTraceroute::new(config.port)),
+        args::Method::Raw(ip_protocol) => Box::new(RawIpTraceroute::new(
+            ip_protocol,
+            config.payload.clone(),
+            config.reply_protocol,
+        )),
+    }
 }
 
 fn init_logging() {
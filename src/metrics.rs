@@ -0,0 +1,456 @@
+use crate::args::Config;
+use crate::batch::trace_targets;
+use crate::packet_io::PacketIo;
+use crate::traceroute::Trace;
+use log::{info, warn};
+use pnet::packet::ip::IpNextHeaderProtocol;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Response, Server};
+
+/// Upper bounds of the RTT histogram buckets in seconds.
+const RTT_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+struct Histogram {
+    buckets: [u64; RTT_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: [0; RTT_BUCKETS.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(RTT_BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+struct HopMetrics {
+    rtt: Histogram,
+    loss_ratio: f64,
+    address: Option<IpAddr>,
+    addresses: BTreeSet<IpAddr>,
+}
+
+struct TargetMetrics {
+    hops: BTreeMap<u8, HopMetrics>,
+    hop_count: usize,
+    destination_reached: bool,
+    traces: u64,
+    path_changes: u64,
+}
+
+/// Name, type, help text and value of a metric with one sample per target.
+type TargetMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&TargetMetrics) -> String,
+);
+
+/// Metrics of all traced targets, updated after every trace.
+pub struct Metrics {
+    targets: BTreeMap<String, TargetMetrics>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            targets: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, trace: &Trace) {
+        let target = self
+            .targets
+            .entry(trace.host.clone())
+            .or_insert_with(|| TargetMetrics {
+                hops: BTreeMap::new(),
+                hop_count: 0,
+                destination_reached: false,
+                traces: 0,
+                path_changes: 0,
+            });
+
+        if target.traces > 0 && has_path_changed(target, trace) {
+            target.path_changes += 1;
+        }
+        target.traces += 1;
+        target.hop_count = hop_count(trace);
+        target.destination_reached = trace.destination_reached;
+
+        // hops beyond the current path are stale
+        target
+            .hops
+            .retain(|ttl, _| trace.hops.iter().any(|hop| hop.ttl == *ttl));
+
+        for hop in &trace.hops {
+            let metrics = target.hops.entry(hop.ttl).or_insert_with(|| HopMetrics {
+                rtt: Histogram::new(),
+                loss_ratio: 0.0,
+                address: None,
+                addresses: BTreeSet::new(),
+            });
+            let replies = hop.probes.iter().flatten().collect::<Vec<_>>();
+
+            for reply in &replies {
+                metrics.rtt.observe(reply.rtt.as_secs_f64());
+            }
+            if !hop.probes.is_empty() {
+                metrics.loss_ratio = 1.0 - replies.len() as f64 / hop.probes.len() as f64;
+            }
+            if let Some(reply) = replies.last() {
+                metrics.address = Some(reply.addr);
+            }
+            metrics.addresses = replies.iter().map(|reply| reply.addr).collect();
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        write_help(
+            &mut out,
+            "traceroute_hop_rtt_seconds",
+            "histogram",
+            "Round trip time of the probes per hop.",
+        );
+        for (target, metrics) in &self.targets {
+            for (ttl, hop) in &metrics.hops {
+                let labels = format!("target=\"{}\",ttl=\"{}\"", escape(target), ttl);
+                for (count, bound) in hop.rtt.buckets.iter().zip(RTT_BUCKETS.iter()) {
+                    let _ = writeln!(
+                        out,
+                        "traceroute_hop_rtt_seconds_bucket{{{},le=\"{}\"}} {}",
+                        labels, bound, count
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "traceroute_hop_rtt_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                    labels, hop.rtt.count
+                );
+                let _ = writeln!(
+                    out,
+                    "traceroute_hop_rtt_seconds_sum{{{}}} {}",
+                    labels, hop.rtt.sum
+                );
+                let _ = writeln!(
+                    out,
+                    "traceroute_hop_rtt_seconds_count{{{}}} {}",
+                    labels, hop.rtt.count
+                );
+            }
+        }
+
+        write_help(
+            &mut out,
+            "traceroute_hop_loss_ratio",
+            "gauge",
+            "Share of the probes of a hop without reply in the last trace.",
+        );
+        for (target, metrics) in &self.targets {
+            for (ttl, hop) in &metrics.hops {
+                let _ = writeln!(
+                    out,
+                    "traceroute_hop_loss_ratio{{target=\"{}\",ttl=\"{}\"}} {}",
+                    escape(target),
+                    ttl,
+                    hop.loss_ratio
+                );
+            }
+        }
+
+        write_help(
+            &mut out,
+            "traceroute_hop_address_info",
+            "gauge",
+            "Address which last answered a probe of the hop.",
+        );
+        for (target, metrics) in &self.targets {
+            for (ttl, hop) in &metrics.hops {
+                if let Some(address) = hop.address {
+                    let _ = writeln!(
+                        out,
+                        "traceroute_hop_address_info{{target=\"{}\",ttl=\"{}\",address=\"{}\"}} 1",
+                        escape(target),
+                        ttl,
+                        address
+                    );
+                }
+            }
+        }
+
+        let per_target: [TargetMetric; 4] = [
+            (
+                "traceroute_hop_count",
+                "gauge",
+                "Number of hops of the last trace.",
+                |metrics| metrics.hop_count.to_string(),
+            ),
+            (
+                "traceroute_destination_reached",
+                "gauge",
+                "Whether the destination answered in the last trace.",
+                |metrics| (metrics.destination_reached as u8).to_string(),
+            ),
+            (
+                "traceroute_path_changes_total",
+                "counter",
+                "Number of traces which found a different path than the one before.",
+                |metrics| metrics.path_changes.to_string(),
+            ),
+            (
+                "traceroute_traces_total",
+                "counter",
+                "Number of completed traces.",
+                |metrics| metrics.traces.to_string(),
+            ),
+        ];
+        for (name, metric_type, help, value) in per_target.iter() {
+            write_help(&mut out, name, metric_type, help);
+            for (target, metrics) in &self.targets {
+                let _ = writeln!(
+                    out,
+                    "{}{{target=\"{}\"}} {}",
+                    name,
+                    escape(target),
+                    value(metrics)
+                );
+            }
+        }
+
+        out
+    }
+}
+
+/// TTL of the last hop, which also counts the hops before the first probed one.
+fn hop_count(trace: &Trace) -> usize {
+    trace.hops.last().map_or(0, |hop| hop.ttl as usize)
+}
+
+/// The path changed if the hop count differs or a hop answered from other addresses only.
+///
+/// Hops without any reply in one of the traces are not compared, so a lost probe alone
+/// does not count as a change.
+fn has_path_changed(target: &TargetMetrics, trace: &Trace) -> bool {
+    if target.hop_count != hop_count(trace) {
+        return true;
+    }
+
+    trace.hops.iter().any(|hop| {
+        let addresses = hop
+            .probes
+            .iter()
+            .flatten()
+            .map(|reply| reply.addr)
+            .collect::<BTreeSet<_>>();
+
+        match target.hops.get(&hop.ttl) {
+            Some(previous) if !previous.addresses.is_empty() && !addresses.is_empty() => {
+                previous.addresses.is_disjoint(&addresses)
+            }
+            _ => false,
+        }
+    })
+}
+
+fn write_help(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answers scrapes of `/metrics` until the server fails.
+pub fn serve(server: Server, metrics: &Mutex<Metrics>) {
+    for request in server.incoming_requests() {
+        let response = if request.url() == "/metrics" {
+            let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4");
+            Response::from_string(metrics.lock().unwrap().render())
+                .with_header(content_type.unwrap())
+        } else {
+            Response::from_string("Not Found\n").with_status_code(404)
+        };
+
+        if let Err(e) = request.respond(response) {
+            warn!("Could not answer metrics request: {}", e);
+        }
+    }
+}
+
+/// Opens an HTTP server on `listen`, where ":9100" is short for port 9100 on all addresses.
+pub fn start_server(listen: &str) -> Server {
+    let addr = match listen.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => listen.to_string(),
+    };
+
+    match Server::http(&addr) {
+        Ok(server) => server,
+        Err(e) => panic!("Could not listen on '{}': {}", addr, e),
+    }
+}
+
+/// Traces all targets every `config.interval_secs` and exports the results on `listen`.
+pub fn run_exporter(
+    config: &Config,
+    listen: &str,
+    targets: &[String],
+    io: &mut dyn PacketIo,
+    probe_protocol: IpNextHeaderProtocol,
+) {
+    let metrics = Arc::new(Mutex::new(Metrics::new()));
+    let server = start_server(listen);
+    info!("Serving metrics on {}", server.server_addr());
+
+    let served = Arc::clone(&metrics);
+    thread::spawn(move || serve(server, &served));
+
+    let interval = Duration::from_secs(config.interval_secs);
+    loop {
+        let round_start = Instant::now();
+        trace_targets(config, targets, io, probe_protocol, &|trace| {
+            metrics.lock().unwrap().record(trace)
+        });
+        info!(
+            "Traced {} targets in {:?}",
+            targets.len(),
+            round_start.elapsed()
+        );
+
+        thread::sleep(interval.saturating_sub(round_start.elapsed()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{probe_reply, trace_of};
+    use crate::traceroute::ProbeReply;
+    use std::io::{Read, Write as _};
+    use std::net::{Ipv4Addr, TcpStream};
+
+    fn reply(last_octet: u8, rtt_ms: u64) -> Option<ProbeReply> {
+        Some(probe_reply(Ipv4Addr::new(10, 0, 1, last_octet), rtt_ms))
+    }
+
+    fn trace(hops: Vec<Vec<Option<ProbeReply>>>) -> Trace {
+        trace_of(Ipv4Addr::new(10, 0, 9, 1), hops)
+    }
+
+    fn path_changes(metrics: &Metrics) -> u64 {
+        metrics.targets["example.com"].path_changes
+    }
+
+    #[test]
+    fn renders_hop_metrics() {
+        let mut metrics = Metrics::new();
+        metrics.record(&trace(vec![
+            vec![reply(1, 2), None],
+            vec![reply(2, 20), reply(3, 300)],
+        ]));
+        let rendered = metrics.render();
+
+        for line in &[
+            "# TYPE traceroute_hop_rtt_seconds histogram",
+            "traceroute_hop_rtt_seconds_bucket{target=\"example.com\",ttl=\"1\",le=\"0.001\"} 0",
+            "traceroute_hop_rtt_seconds_bucket{target=\"example.com\",ttl=\"1\",le=\"0.0025\"} 1",
+            "traceroute_hop_rtt_seconds_bucket{target=\"example.com\",ttl=\"2\",le=\"0.025\"} 1",
+            "traceroute_hop_rtt_seconds_bucket{target=\"example.com\",ttl=\"2\",le=\"+Inf\"} 2",
+            "traceroute_hop_rtt_seconds_count{target=\"example.com\",ttl=\"2\"} 2",
+            "traceroute_hop_loss_ratio{target=\"example.com\",ttl=\"1\"} 0.5",
+            "traceroute_hop_loss_ratio{target=\"example.com\",ttl=\"2\"} 0",
+            "traceroute_hop_address_info{target=\"example.com\",ttl=\"2\",address=\"10.0.1.3\"} 1",
+            "traceroute_hop_count{target=\"example.com\"} 2",
+            "traceroute_destination_reached{target=\"example.com\"} 1",
+            "traceroute_traces_total{target=\"example.com\"} 1",
+        ] {
+            assert!(rendered.lines().any(|l| l == *line), "missing '{}'", line);
+        }
+    }
+
+    #[test]
+    fn counts_hops_before_first_hop() {
+        let mut metrics = Metrics::new();
+        let mut first_hop_3 = trace(vec![vec![reply(3, 1)], vec![reply(4, 1)]]);
+        for hop in &mut first_hop_3.hops {
+            hop.ttl += 2;
+        }
+        metrics.record(&first_hop_3);
+
+        assert!(metrics
+            .render()
+            .lines()
+            .any(|l| l == "traceroute_hop_count{target=\"example.com\"} 4"));
+    }
+
+    #[test]
+    fn counts_path_changes() {
+        let mut metrics = Metrics::new();
+        metrics.record(&trace(vec![vec![reply(1, 1)], vec![reply(2, 1)]]));
+        assert_eq!(path_changes(&metrics), 0);
+
+        // a timeout is no change
+        metrics.record(&trace(vec![vec![reply(1, 1)], vec![None]]));
+        assert_eq!(path_changes(&metrics), 0);
+
+        metrics.record(&trace(vec![vec![reply(1, 1)], vec![reply(2, 1)]]));
+        metrics.record(&trace(vec![vec![reply(1, 1)], vec![reply(4, 1)]]));
+        assert_eq!(path_changes(&metrics), 1);
+
+        metrics.record(&trace(vec![vec![reply(1, 1)]]));
+        assert_eq!(path_changes(&metrics), 2);
+    }
+
+    fn get(addr: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let metrics = Arc::new(Mutex::new(Metrics::new()));
+        metrics
+            .lock()
+            .unwrap()
+            .record(&trace(vec![vec![reply(1, 1)]]));
+
+        let server = start_server("127.0.0.1:0");
+        let addr = server.server_addr().to_string();
+        let served = Arc::clone(&metrics);
+        thread::spawn(move || serve(server, &served));
+
+        let response = get(&addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.0 200") || response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("traceroute_hop_count{target=\"example.com\"} 1"));
+
+        let response = get(&addr, "/");
+        assert!(response.contains(" 404 "));
+    }
+}
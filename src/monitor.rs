@@ -0,0 +1,476 @@
+use crate::args::Config;
+use crate::asn::AsnTable;
+use crate::batch::trace_targets;
+use crate::packet_io::PacketIo;
+use crate::traceroute::Trace;
+use log::{info, warn};
+use pnet::packet::ip::IpNextHeaderProtocol;
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Addresses which answered per TTL, an empty set marks a hop without any reply.
+#[derive(Clone)]
+struct Path {
+    hops: BTreeMap<u8, BTreeSet<IpAddr>>,
+}
+
+impl Path {
+    fn from_trace(trace: &Trace) -> Self {
+        let hops = trace
+            .hops
+            .iter()
+            .map(|hop| {
+                let addresses = hop.probes.iter().flatten().map(|reply| reply.addr);
+                (hop.ttl, addresses.collect())
+            })
+            .collect();
+
+        Path { hops }
+    }
+
+    /// Number of hops, unknown if the last one did not answer, e.g. as the reply of the
+    /// destination got lost and probing went on until the gap limit.
+    fn hop_count(&self) -> Option<usize> {
+        match self.hops.values().next_back() {
+            Some(addresses) if addresses.is_empty() => None,
+            _ => Some(self.hops.len()),
+        }
+    }
+
+    /// Takes the addresses of hops which never answered before from `other`.
+    fn fill_from(&mut self, other: &Path) {
+        for (ttl, addresses) in self.hops.iter_mut() {
+            if addresses.is_empty() {
+                if let Some(other) = other.hops.get(ttl) {
+                    addresses.clone_from(other);
+                }
+            }
+        }
+
+        // silent hops at the end only stood in for the lost reply of the destination
+        if self.hop_count().is_none() && other.hop_count().is_some() {
+            if let Some(&last) = other.hops.keys().next_back() {
+                self.hops.retain(|ttl, _| *ttl <= last);
+            }
+        }
+    }
+}
+
+/// Difference between the known path of a target and the current one.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Change {
+    NewHop {
+        ttl: u8,
+        addresses: BTreeSet<IpAddr>,
+    },
+    MissingHop {
+        ttl: u8,
+        addresses: BTreeSet<IpAddr>,
+    },
+    ChangedAs {
+        ttl: u8,
+        previous: BTreeSet<u32>,
+        current: BTreeSet<u32>,
+    },
+    HopCount {
+        previous: usize,
+        current: usize,
+    },
+}
+
+/// Confirmed change of the path towards a target.
+#[derive(Serialize)]
+pub struct PathEvent {
+    pub target: String,
+    pub timestamp: u64,
+    pub changes: Vec<Change>,
+    /// The new path, the answering addresses per hop.
+    pub path: Vec<BTreeSet<IpAddr>>,
+}
+
+struct TargetState {
+    known: Path,
+    /// Path of the last traces in a row which differed from the known path but agreed with
+    /// each other, and their number.
+    candidate: Option<(Path, usize)>,
+}
+
+/// Keeps the last known path per target and detects changes.
+pub struct Monitor {
+    hysteresis: usize,
+    asns: Option<AsnTable>,
+    targets: HashMap<String, TargetState>,
+}
+
+impl Monitor {
+    pub fn new(hysteresis: usize, asns: Option<AsnTable>) -> Self {
+        Monitor {
+            hysteresis: hysteresis.max(1),
+            asns,
+            targets: HashMap::new(),
+        }
+    }
+
+    /// Compares `trace` to the known path of its target.
+    ///
+    /// A change is only confirmed once `hysteresis` traces in a row differ from the known
+    /// path in the same way, the last of them becomes the new known path. Hops without any
+    /// reply are never a change on their own.
+    pub fn record(&mut self, trace: &Trace) -> Option<PathEvent> {
+        let mut path = Path::from_trace(trace);
+
+        let state = match self.targets.entry(trace.host.clone()) {
+            Entry::Vacant(entry) => {
+                info!(
+                    "Monitoring path to {} with {} hops",
+                    trace.host,
+                    path.hops.len()
+                );
+                entry.insert(TargetState {
+                    known: path,
+                    candidate: None,
+                });
+                return None;
+            }
+            Entry::Occupied(entry) => entry.into_mut(),
+        };
+
+        let changes = compare(&state.known, &path, self.asns.as_ref());
+        if changes.is_empty() {
+            state.candidate = None;
+            state.known.fill_from(&path);
+            return None;
+        }
+
+        // paths flapping between several alternatives are not confirmed
+        let differing = match state.candidate.take() {
+            Some((candidate, differing))
+                if compare(&candidate, &path, self.asns.as_ref()).is_empty() =>
+            {
+                path.fill_from(&candidate);
+                differing + 1
+            }
+            _ => 1,
+        };
+        if differing < self.hysteresis {
+            info!(
+                "Path to {} differs, waiting for confirmation ({}/{})",
+                trace.host, differing, self.hysteresis
+            );
+            state.candidate = Some((path, differing));
+            return None;
+        }
+
+        state.known = path;
+
+        Some(PathEvent {
+            target: trace.host.clone(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            changes,
+            path: state.known.hops.values().cloned().collect(),
+        })
+    }
+}
+
+fn compare(known: &Path, current: &Path, asns: Option<&AsnTable>) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let ttls = known
+        .hops
+        .keys()
+        .chain(current.hops.keys())
+        .copied()
+        .collect::<BTreeSet<_>>();
+
+    for ttl in ttls {
+        match (known.hops.get(&ttl), current.hops.get(&ttl)) {
+            (Some(previous), Some(addresses))
+                if !previous.is_empty()
+                    && !addresses.is_empty()
+                    && previous.is_disjoint(addresses) =>
+            {
+                changes.push(Change::MissingHop {
+                    ttl,
+                    addresses: previous.clone(),
+                });
+                changes.push(Change::NewHop {
+                    ttl,
+                    addresses: addresses.clone(),
+                });
+
+                if let Some(asns) = asns {
+                    let previous = origin_asns(asns, previous);
+                    let current = origin_asns(asns, addresses);
+                    if !previous.is_empty() && !current.is_empty() && previous.is_disjoint(&current)
+                    {
+                        changes.push(Change::ChangedAs {
+                            ttl,
+                            previous,
+                            current,
+                        });
+                    }
+                }
+            }
+            (Some(previous), None) if !previous.is_empty() => changes.push(Change::MissingHop {
+                ttl,
+                addresses: previous.clone(),
+            }),
+            (None, Some(addresses)) if !addresses.is_empty() => changes.push(Change::NewHop {
+                ttl,
+                addresses: addresses.clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    if let (Some(previous), Some(current)) = (known.hop_count(), current.hop_count()) {
+        if previous != current {
+            changes.push(Change::HopCount { previous, current });
+        }
+    }
+
+    changes
+}
+
+fn origin_asns(asns: &AsnTable, addresses: &BTreeSet<IpAddr>) -> BTreeSet<u32> {
+    addresses
+        .iter()
+        .filter_map(|addr| match addr {
+            IpAddr::V4(addr) => asns.lookup(*addr),
+            IpAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// Posts `body` as JSON to the plain HTTP `url`, returns the status code of the answer.
+pub fn post_json(url: &str, body: &str) -> io::Result<u16> {
+    let invalid_url = || io::Error::new(io::ErrorKind::InvalidInput, "not an http:// URL");
+    let rest = url.strip_prefix("http://").ok_or_else(invalid_url)?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let addr = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        authority,
+        body.len(),
+        body
+    )?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response"))
+}
+
+fn report(event: &PathEvent, config: &Config) {
+    let json = serde_json::to_string(event).unwrap();
+    println!("{}", json);
+
+    if let Some(url) = &config.webhook {
+        match post_json(url, &json) {
+            Ok(status) if (200..300).contains(&status) => {}
+            Ok(status) => warn!("Webhook answered with status {}", status),
+            Err(e) => warn!("Could not post event to webhook: {}", e),
+        }
+    }
+}
+
+/// Traces all targets every `config.interval_secs`, printing an event for every confirmed
+/// path change.
+pub fn run_monitor(
+    config: &Config,
+    targets: &[String],
+    io: &mut dyn PacketIo,
+    probe_protocol: IpNextHeaderProtocol,
+) {
+    if let Some(url) = &config.webhook {
+        if !url.starts_with("http://") {
+            panic!("Only http:// webhooks are supported, not '{}'", url);
+        }
+    }
+    let asns = config.asn_table.as_deref().map(AsnTable::load);
+    let monitor = Mutex::new(Monitor::new(config.hysteresis, asns));
+
+    let interval = Duration::from_secs(config.interval_secs);
+    loop {
+        let round_start = Instant::now();
+        trace_targets(config, targets, io, probe_protocol, &|trace| {
+            let event = monitor.lock().unwrap().record(trace);
+            if let Some(event) = event {
+                report(&event, config);
+            }
+        });
+        info!(
+            "Traced {} targets in {:?}",
+            targets.len(),
+            round_start.elapsed()
+        );
+
+        thread::sleep(interval.saturating_sub(round_start.elapsed()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::start_server;
+    use crate::simulation::{probe_reply, trace_of};
+    use std::net::Ipv4Addr;
+
+    /// Trace with one probe per hop answered by 10.0.<ttl>.<octet>, octet 0 is a timeout.
+    fn trace(octets: &[u8]) -> Trace {
+        let hops = octets.iter().enumerate().map(|(i, octet)| {
+            let addr = Ipv4Addr::new(10, 0, i as u8 + 1, *octet);
+            vec![(*octet != 0).then(|| probe_reply(addr, 1))]
+        });
+        trace_of(Ipv4Addr::new(10, 0, 9, 1), hops.collect())
+    }
+
+    fn addresses(ttl: u8, octet: u8) -> BTreeSet<IpAddr> {
+        [IpAddr::V4(Ipv4Addr::new(10, 0, ttl, octet))].into()
+    }
+
+    #[test]
+    fn confirms_changes_after_hysteresis() {
+        let mut monitor = Monitor::new(2, None);
+        assert!(monitor.record(&trace(&[1, 1, 1])).is_none());
+
+        // lost replies and a single deviation are not reported
+        assert!(monitor.record(&trace(&[1, 0, 1])).is_none());
+        assert!(monitor.record(&trace(&[1, 2, 1])).is_none());
+        assert!(monitor.record(&trace(&[1, 1, 1])).is_none());
+        assert!(monitor.record(&trace(&[1, 2, 1])).is_none());
+
+        let event = monitor.record(&trace(&[1, 2, 1])).unwrap();
+        assert_eq!(
+            event.changes,
+            vec![
+                Change::MissingHop {
+                    ttl: 2,
+                    addresses: addresses(2, 1)
+                },
+                Change::NewHop {
+                    ttl: 2,
+                    addresses: addresses(2, 2)
+                },
+            ]
+        );
+        assert_eq!(event.path[1], addresses(2, 2));
+
+        assert!(monitor.record(&trace(&[1, 2, 1])).is_none());
+    }
+
+    #[test]
+    fn does_not_confirm_alternating_paths() {
+        let mut monitor = Monitor::new(2, None);
+        monitor.record(&trace(&[1, 1, 1]));
+
+        assert!(monitor.record(&trace(&[1, 2, 1])).is_none());
+        assert!(monitor.record(&trace(&[1, 3, 1])).is_none());
+        assert!(monitor.record(&trace(&[1, 2, 1])).is_none());
+
+        let event = monitor.record(&trace(&[1, 2, 1])).unwrap();
+        assert_eq!(event.path[1], addresses(2, 2));
+    }
+
+    #[test]
+    fn ignores_silent_hops_behind_the_destination() {
+        let mut monitor = Monitor::new(1, None);
+        monitor.record(&trace(&[1, 1, 1]));
+
+        // the reply of the destination got lost, so the trace went on until the gap limit
+        assert!(monitor.record(&trace(&[1, 1, 0, 0, 0, 0, 0])).is_none());
+        assert!(monitor.record(&trace(&[1, 1, 1])).is_none());
+
+        let event = monitor.record(&trace(&[1, 1])).unwrap();
+        assert_eq!(
+            event.changes,
+            vec![
+                Change::MissingHop {
+                    ttl: 3,
+                    addresses: addresses(3, 1)
+                },
+                Change::HopCount {
+                    previous: 3,
+                    current: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_hop_count_and_as_changes() {
+        let mut asns = AsnTable::new();
+        asns.insert(Ipv4Addr::new(10, 0, 2, 0), 25, 64500);
+        asns.insert(Ipv4Addr::new(10, 0, 2, 128), 25, 64501);
+        let mut monitor = Monitor::new(1, Some(asns));
+        monitor.record(&trace(&[1, 1, 1]));
+
+        let event = monitor.record(&trace(&[1, 200, 1, 1])).unwrap();
+        assert_eq!(
+            event.changes[2..],
+            [
+                Change::ChangedAs {
+                    ttl: 2,
+                    previous: [64500].into(),
+                    current: [64501].into(),
+                },
+                Change::NewHop {
+                    ttl: 4,
+                    addresses: addresses(4, 1),
+                },
+                Change::HopCount {
+                    previous: 3,
+                    current: 4
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn posts_events_to_webhook() {
+        let server = start_server("127.0.0.1:0");
+        let url = format!("http://{}/hooks/paths", server.server_addr());
+
+        let receiver = thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            let url = request.url().to_string();
+            request.respond(tiny_http::Response::empty(204)).unwrap();
+            (url, body)
+        });
+
+        assert_eq!(post_json(&url, r#"{"target":"example.com"}"#).unwrap(), 204);
+        let (path, body) = receiver.join().unwrap();
+        assert_eq!(path, "/hooks/paths");
+        assert_eq!(body, r#"{"target":"example.com"}"#);
+
+        assert!(post_json("https://example.com/", "{}").is_err());
+    }
+}
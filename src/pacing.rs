@@ -0,0 +1,174 @@
+use crate::packet_io::{PacketIo, ReceivedPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use std::collections::VecDeque;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket holding at most one token, which refills at `rate` tokens per second.
+///
+/// Senders reserve tokens ahead of time, so several senders can share a bucket without
+/// holding its lock while they wait.
+pub struct TokenBucket {
+    interval: Duration,
+    /// When the next token is available.
+    next: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64) -> Self {
+        TokenBucket {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next: None,
+        }
+    }
+
+    /// Takes the next token and returns when it may be used.
+    fn reserve(&mut self, now: Instant) -> Instant {
+        let at = self.next.map_or(now, |next| next.max(now));
+        self.next = Some(at + self.interval);
+        at
+    }
+}
+
+/// `PacketIo` which delays probes to stay within the send rate of a shared `TokenBucket`.
+///
+/// Replies keep being received while waiting, so their timestamps are not delayed.
+pub struct PacedIo {
+    inner: Box<dyn PacketIo>,
+    bucket: Arc<Mutex<TokenBucket>>,
+    probe_protocol: IpNextHeaderProtocol,
+    icmp: VecDeque<ReceivedPacket>,
+    protocol: VecDeque<ReceivedPacket>,
+    /// Whether a token was already taken for the next probe.
+    ready: bool,
+}
+
+impl PacedIo {
+    pub fn new(
+        inner: Box<dyn PacketIo>,
+        bucket: Arc<Mutex<TokenBucket>>,
+        probe_protocol: IpNextHeaderProtocol,
+    ) -> Self {
+        PacedIo {
+            inner,
+            bucket,
+            probe_protocol,
+            icmp: VecDeque::new(),
+            protocol: VecDeque::new(),
+            ready: false,
+        }
+    }
+}
+
+impl PacketIo for PacedIo {
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn send_to(&mut self, packet: &[u8], dst: IpAddr) -> io::Result<Instant> {
+        self.wait_for_send();
+        self.ready = false;
+        self.inner.send_to(packet, dst)
+    }
+
+    fn receive_icmp(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        self.icmp
+            .pop_front()
+            .or_else(|| self.inner.receive_icmp(timeout))
+    }
+
+    fn receive_protocol(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        self.protocol
+            .pop_front()
+            .or_else(|| self.inner.receive_protocol(timeout))
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+
+    fn wait_for_send(&mut self) {
+        if self.ready {
+            return;
+        }
+        let send_at = self.bucket.lock().unwrap().reserve(self.inner.now());
+
+        while self.inner.now() < send_at {
+            let timeout = (send_at - self.inner.now()).min(Duration::from_millis(1));
+            self.icmp.extend(self.inner.receive_icmp(timeout));
+            // on ICMP probing the protocol socket only sees copies of the ICMP packets
+            if self.probe_protocol != IpNextHeaderProtocols::Icmp {
+                self.protocol.extend(self.inner.receive_protocol(timeout));
+            }
+        }
+        self.ready = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Method;
+    use crate::batch::trace_targets;
+    use crate::protocols::icmp::IcmpTraceroute;
+    use crate::protocols::protocol::TracerouteProtocol;
+    use crate::simulation::{test_config, SimulatedDestination, SimulatedNetwork, SimulatedRouter};
+    use pnet::transport::TransportChannelType::Layer4;
+    use pnet::transport::TransportProtocol::Ipv4;
+    use std::net::Ipv4Addr;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(10, 0, 9, 1);
+
+    fn paced_network(rate: f64) -> PacedIo {
+        let network = SimulatedNetwork::linear(
+            Layer4(Ipv4(IpNextHeaderProtocols::Icmp)),
+            SOURCE,
+            vec![SimulatedRouter::new(
+                Ipv4Addr::new(10, 0, 1, 1),
+                Duration::from_millis(2),
+            )],
+            SimulatedDestination::new(DESTINATION, Duration::from_millis(5)),
+        );
+        let bucket = Arc::new(Mutex::new(TokenBucket::new(rate)));
+
+        PacedIo::new(Box::new(network), bucket, IpNextHeaderProtocols::Icmp)
+    }
+
+    #[test]
+    fn keeps_receiving_while_waiting() {
+        let mut protocol = IcmpTraceroute::new();
+        protocol.set_io(Box::new(paced_network(10.0)));
+        protocol.set_ttl(1);
+
+        let first = protocol.send(IpAddr::V4(DESTINATION), 1).unwrap();
+        let second = protocol.send(IpAddr::V4(DESTINATION), 2).unwrap();
+        let reply = protocol.get_io().receive_icmp(Duration::ZERO).unwrap();
+
+        assert_eq!(second - first, Duration::from_millis(100));
+        assert_eq!(reply.time_receive - first, Duration::from_millis(2));
+    }
+
+    #[test]
+    fn limits_send_rate_of_batches() {
+        let mut config = test_config(Ipv4Addr::UNSPECIFIED, Method::Icmp);
+        config.workers = 2;
+        let targets = vec![DESTINATION.to_string(); 2];
+        let mut io = paced_network(20.0);
+        let start = io.now();
+
+        let traces = trace_targets(
+            &config,
+            &targets,
+            &mut io,
+            IpNextHeaderProtocols::Icmp,
+            &|_| {},
+        );
+
+        assert!(traces.iter().all(|trace| trace.destination_reached));
+        // two traces of two hops with three probes each
+        assert!(io.now() - start >= Duration::from_millis(11 * 50));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::interfaces::get_source_ip_for;
+use crate::ip_options::IpOptions;
+#[cfg(target_os = "linux")]
+use crate::timestamps;
+#[cfg(target_os = "linux")]
+use log::{debug, warn};
+use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{checksum, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::Packet;
+#[cfg(not(target_os = "linux"))]
+use pnet::transport::icmp_packet_iter;
+use pnet::transport::TransportChannelType::{Layer3, Layer4};
+use pnet::transport::TransportProtocol::Ipv4;
+use pnet::transport::{
+    transport_channel, TransportChannelType, TransportProtocol, TransportReceiver, TransportSender,
+};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+/// A packet received from the network, including its IPv4 header.
+pub struct ReceivedPacket {
+    pub data: Vec<u8>,
+    pub time_receive: Instant,
+}
+
+impl ReceivedPacket {
+    pub fn new(data: Vec<u8>, time_receive: Instant) -> Self {
+        ReceivedPacket { data, time_receive }
+    }
+
+    pub fn get_ip_packet(&self) -> Option<Ipv4Packet<'_>> {
+        Ipv4Packet::new(&self.data)
+    }
+
+    pub fn get_sender(&self) -> Option<IpAddr> {
+        self.get_ip_packet()
+            .map(|ip_packet| IpAddr::V4(ip_packet.get_source()))
+    }
+
+    pub fn get_ttl(&self) -> Option<u8> {
+        self.get_ip_packet().map(|ip_packet| ip_packet.get_ttl())
+    }
+
+    /// The transport layer part of the packet, i.e. everything after the IP header.
+    pub fn get_payload(&self) -> &[u8] {
+        match self.get_ip_packet() {
+            Some(ip_packet) => {
+                let header_length = ip_packet.get_header_length() as usize * 4;
+                self.data.get(header_length..).unwrap_or(&[])
+            }
+            None => &[],
+        }
+    }
+
+    pub fn get_icmp_packet(&self) -> Option<IcmpPacket<'_>> {
+        IcmpPacket::new(self.get_payload())
+    }
+}
+
+/// Transmission and reception of packets for a `TracerouteProtocol`.
+pub trait PacketIo: Send {
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()>;
+
+    /// Sends a transport layer packet to `dst` and returns the time it was sent.
+    fn send_to(&mut self, packet: &[u8], dst: IpAddr) -> io::Result<Instant>;
+
+    /// Receives the next ICMP packet, waiting at most `timeout`.
+    fn receive_icmp(&mut self, timeout: Duration) -> Option<ReceivedPacket>;
+
+    /// Receives the next packet of the probed transport protocol, waiting at most `timeout`.
+    fn receive_protocol(&mut self, timeout: Duration) -> Option<ReceivedPacket>;
+
+    /// Current time as seen by this I/O backend.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Waits until the next probe may be sent, `send_to` waits by itself if this was not called.
+    fn wait_for_send(&mut self) {}
+}
+
+/// `PacketIo` on top of raw sockets, requires privileges.
+///
+/// On Linux, send and receive times are taken by the kernel where it supports it, so they do not
+/// include scheduling delays of this process.
+pub struct RawSocketIo {
+    tx: TransportSender,
+    rx_protocol: TransportReceiver,
+    rx_icmp: TransportReceiver,
+    /// Whether the kernel reports when sent packets left.
+    send_timestamps: bool,
+    /// Packets sent so far, which is the key of the next send timestamp.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    sent_packets: u32,
+    protocol: IpNextHeaderProtocol,
+    ttl: u8,
+    /// IP options of every probe, which are then sent with an IP header of our own.
+    ip_options: IpOptions,
+}
+
+impl RawSocketIo {
+    /// Sends packets of `protocol` with `ip_options` and receives ICMP and packets of
+    /// `reply_protocol`.
+    pub fn open(
+        protocol: TransportChannelType,
+        reply_protocol: TransportChannelType,
+        ip_options: &IpOptions,
+    ) -> Self {
+        // the kernel only adds options set per socket, so the header has to be built here
+        let tx_channel = if ip_options.is_empty() {
+            protocol
+        } else {
+            Layer3(get_next_level_protocol(protocol))
+        };
+        let (tx, rx_protocol) = match transport_channel(4096, tx_channel) {
+            Ok((tx, rx)) => (tx, rx),
+            Err(e) => panic!("An error occurred when creating tx/rx channel: {}", e),
+        };
+        let rx_protocol =
+            if get_next_level_protocol(reply_protocol) == get_next_level_protocol(protocol) {
+                rx_protocol
+            } else {
+                match transport_channel(4096, reply_protocol) {
+                    Ok((_, rx)) => rx,
+                    Err(e) => panic!("An error occurred when creating rx channel: {}", e),
+                }
+            };
+
+        let rx_icmp = match transport_channel(4096, Layer4(Ipv4(IpNextHeaderProtocols::Icmp))) {
+            Ok((_, rx)) => rx,
+            Err(e) => panic!("An error occurred when creating rx channel: {}", e),
+        };
+
+        RawSocketIo {
+            send_timestamps: enable_kernel_timestamps(&tx, &[&rx_protocol, &rx_icmp]),
+            sent_packets: 0,
+            tx,
+            rx_protocol,
+            rx_icmp,
+            protocol: get_next_level_protocol(protocol),
+            ttl: 64,
+            ip_options: ip_options.clone(),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn receive(rx: &mut TransportReceiver, timeout: Duration) -> Option<ReceivedPacket> {
+        // raw IPv4 sockets receive whole datagrams, including the IP header
+        let (length, time_receive) =
+            timestamps::receive(rx.socket.fd, &mut rx.buffer, timeout).ok()??;
+        let time_receive = time_receive.unwrap_or_else(Instant::now);
+
+        Some(ReceivedPacket::new(
+            rx.buffer[..length].to_vec(),
+            time_receive,
+        ))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn receive(rx: &mut TransportReceiver, timeout: Duration) -> Option<ReceivedPacket> {
+        // the iterator strips the IP header, but leaves it in the receive buffer
+        let payload_length = match icmp_packet_iter(rx).next_with_timeout(timeout) {
+            Ok(Some((packet, _))) => packet.packet().len(),
+            Ok(None) | Err(_) => return None,
+        };
+        let time_receive = Instant::now();
+
+        let header_length = Ipv4Packet::new(&rx.buffer)?.get_header_length() as usize * 4;
+        let data = rx.buffer[..header_length + payload_length].to_vec();
+
+        Some(ReceivedPacket::new(data, time_receive))
+    }
+}
+
+impl PacketIo for RawSocketIo {
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+        self.ttl = ttl;
+        self.tx.set_ttl(ttl)
+    }
+
+    fn send_to(&mut self, packet: &[u8], dst: IpAddr) -> io::Result<Instant> {
+        if self.ip_options.is_empty() {
+            self.tx.send_to(RawPacket(packet), dst)?;
+        } else {
+            let dst = match dst {
+                IpAddr::V4(dst) => dst,
+                IpAddr::V6(_) => return Err(io::Error::from(io::ErrorKind::AddrNotAvailable)),
+            };
+            // with a source route, the header leads to the first gateway, while the transport
+            // checksum was computed for the final destination as RFC 791 requires
+            let first_hop = self.ip_options.first_hop(dst);
+            let packet = build_ipv4_packet_with_options(
+                get_source_ip_for(IpAddr::V4(first_hop)),
+                first_hop,
+                self.ttl,
+                self.protocol,
+                &self.ip_options.encode(dst),
+                packet,
+            );
+            self.tx.send_to(RawPacket(&packet), IpAddr::V4(first_hop))?;
+        }
+        let time_send = Instant::now();
+
+        #[cfg(target_os = "linux")]
+        if self.send_timestamps {
+            let key = self.sent_packets;
+            self.sent_packets = self.sent_packets.wrapping_add(1);
+            if let Some(time_send) = timestamps::read_send_timestamp(self.tx.socket.fd, key) {
+                return Ok(time_send);
+            }
+        }
+
+        Ok(time_send)
+    }
+
+    fn receive_icmp(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        RawSocketIo::receive(&mut self.rx_icmp, timeout)
+    }
+
+    fn receive_protocol(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        RawSocketIo::receive(&mut self.rx_protocol, timeout)
+    }
+}
+
+/// Enables kernel timestamps on the sockets, returns whether send timestamps are available.
+#[cfg(target_os = "linux")]
+fn enable_kernel_timestamps(tx: &TransportSender, receivers: &[&TransportReceiver]) -> bool {
+    for rx in receivers {
+        if !timestamps::enable_receive_timestamps(rx.socket.fd) {
+            warn!("Kernel receive timestamps are not supported, falling back to user space.");
+        }
+    }
+
+    let send_timestamps = timestamps::enable_send_timestamps(tx.socket.fd);
+    if !send_timestamps {
+        debug!("Kernel send timestamps are not supported, falling back to user space.");
+    }
+
+    send_timestamps
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_kernel_timestamps(_tx: &TransportSender, _receivers: &[&TransportReceiver]) -> bool {
+    false
+}
+
+/// Already serialized packet which can be handed to a `TransportSender`.
+struct RawPacket<'a>(&'a [u8]);
+
+impl Packet for RawPacket<'_> {
+    fn packet(&self) -> &[u8] {
+        self.0
+    }
+
+    fn payload(&self) -> &[u8] {
+        self.0
+    }
+}
+
+/// Serializes an IPv4 packet without options around `payload`.
+#[cfg(test)]
+pub fn build_ipv4_packet(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    ttl: u8,
+    protocol: IpNextHeaderProtocol,
+    payload: &[u8],
+) -> Vec<u8> {
+    build_ipv4_packet_with_options(src, dst, ttl, protocol, &[], payload)
+}
+
+/// Serializes an IPv4 packet with the encoded `options` around `payload`, padding the options
+/// to whole 32-bit words.
+pub fn build_ipv4_packet_with_options(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    ttl: u8,
+    protocol: IpNextHeaderProtocol,
+    options: &[u8],
+    payload: &[u8],
+) -> Vec<u8> {
+    let header_length = 20 + options.len().div_ceil(4) * 4;
+    let mut buffer = vec![0; header_length + payload.len()];
+    buffer[20..20 + options.len()].copy_from_slice(options);
+    buffer[header_length..].copy_from_slice(payload);
+    let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+
+    packet.set_version(4);
+    packet.set_header_length((header_length / 4) as u8);
+    packet.set_total_length((header_length + payload.len()) as u16);
+    packet.set_ttl(ttl);
+    packet.set_next_level_protocol(protocol);
+    packet.set_source(src);
+    packet.set_destination(dst);
+
+    let checksum = checksum(&packet.to_immutable());
+    packet.set_checksum(checksum);
+
+    buffer
+}
+
+pub fn get_next_level_protocol(channel_type: TransportChannelType) -> IpNextHeaderProtocol {
+    match channel_type {
+        TransportChannelType::Layer4(TransportProtocol::Ipv4(protocol))
+        | TransportChannelType::Layer4(TransportProtocol::Ipv6(protocol))
+        | TransportChannelType::Layer3(protocol) => protocol,
+    }
+}
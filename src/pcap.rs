@@ -0,0 +1,534 @@
+use crate::ip_options::IpOptions;
+use crate::packet_io::{build_ipv4_packet_with_options, PacketIo, ReceivedPacket};
+use log::error;
+use pnet::packet::ip::IpNextHeaderProtocol;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Magic number of pcap files with microsecond resolution timestamps.
+const PCAP_MAGIC_MICROSECONDS: u32 = 0xa1b2_c3d4;
+/// Magic number of pcap files with nanosecond resolution timestamps.
+const PCAP_MAGIC_NANOSECONDS: u32 = 0xa1b2_3c4d;
+/// Magic number of pcapng files, which are not supported for reading.
+const PCAPNG_MAGIC: u32 = 0x0a0d_0d0a;
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+/// Link type for packets starting directly with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const SNAPLEN: u32 = 65535;
+
+/// Writes packets in the classic pcap format as understood by Wireshark and tcpdump.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC_NANOSECONDS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header)?;
+
+        Ok(PcapWriter { writer })
+    }
+
+    pub fn write_packet(&mut self, time: SystemTime, data: &[u8]) -> io::Result<()> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let captured_length = data.len().min(SNAPLEN as usize);
+
+        let mut record = Vec::with_capacity(16 + captured_length);
+        record.extend_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+        record.extend_from_slice(&(captured_length as u32).to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&data[..captured_length]);
+
+        // flushed per packet, so the capture is usable even if we are interrupted
+        self.writer.write_all(&record)?;
+        self.writer.flush()
+    }
+}
+
+/// IPv4 packet read from a capture file.
+pub struct CapturedPacket {
+    pub time: SystemTime,
+    pub data: Vec<u8>,
+}
+
+/// Reads all IPv4 packets from a pcap file, as written by us or by tcpdump.
+pub fn read_pcap<R: Read>(mut reader: R) -> io::Result<Vec<CapturedPacket>> {
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content)?;
+
+    let header = content
+        .get(..24)
+        .ok_or_else(|| invalid_data("file is too short for a pcap header"))?;
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let (little_endian, nanoseconds) = match magic {
+        PCAP_MAGIC_MICROSECONDS => (true, false),
+        PCAP_MAGIC_NANOSECONDS => (true, true),
+        _ if magic.swap_bytes() == PCAP_MAGIC_MICROSECONDS => (false, false),
+        _ if magic.swap_bytes() == PCAP_MAGIC_NANOSECONDS => (false, true),
+        PCAPNG_MAGIC => return Err(invalid_data("pcapng is not supported, convert to pcap")),
+        _ => return Err(invalid_data("unknown pcap magic number")),
+    };
+    let read_u32 = |bytes: &[u8]| {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    };
+    let link_type = read_u32(&header[20..24]);
+
+    let mut packets = Vec::new();
+    let mut offset = 24;
+    while offset + 16 <= content.len() {
+        let record_header = &content[offset..offset + 16];
+        let seconds = read_u32(&record_header[0..4]);
+        let fraction = read_u32(&record_header[4..8]);
+        let captured_length = read_u32(&record_header[8..12]) as usize;
+
+        let frame = content
+            .get(offset + 16..offset + 16 + captured_length)
+            .ok_or_else(|| invalid_data("truncated pcap record"))?;
+        offset += 16 + captured_length;
+
+        let nanos = if nanoseconds {
+            fraction
+        } else {
+            fraction
+                .checked_mul(1000)
+                .ok_or_else(|| invalid_data("invalid microseconds in pcap record"))?
+        };
+        let time = UNIX_EPOCH + Duration::new(seconds.into(), nanos);
+
+        if let Some(data) = strip_link_layer(link_type, frame) {
+            packets.push(CapturedPacket {
+                time,
+                data: data.to_vec(),
+            });
+        }
+    }
+
+    Ok(packets)
+}
+
+/// Removes the link layer header of a frame, if it contains an IPv4 packet.
+fn strip_link_layer(link_type: u32, frame: &[u8]) -> Option<&[u8]> {
+    let ethertype_at = |offset: usize| {
+        frame
+            .get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+
+    let ip_packet = match link_type {
+        LINKTYPE_RAW | LINKTYPE_IPV4 => frame,
+        LINKTYPE_NULL => frame.get(4..)?,
+        LINKTYPE_ETHERNET => {
+            let mut ethertype_offset = 12;
+            while ethertype_at(ethertype_offset)? == ETHERTYPE_VLAN {
+                ethertype_offset += 4;
+            }
+            if ethertype_at(ethertype_offset)? != ETHERTYPE_IPV4 {
+                return None;
+            }
+            frame.get(ethertype_offset + 2..)?
+        }
+        LINKTYPE_LINUX_SLL => {
+            if ethertype_at(14)? != ETHERTYPE_IPV4 {
+                return None;
+            }
+            frame.get(16..)?
+        }
+        LINKTYPE_LINUX_SLL2 => {
+            if ethertype_at(0)? != ETHERTYPE_IPV4 {
+                return None;
+            }
+            frame.get(20..)?
+        }
+        _ => return None,
+    };
+
+    match ip_packet.first() {
+        Some(version_and_length) if version_and_length >> 4 == 4 => Some(ip_packet),
+        _ => None,
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// `PacketIo` which records all sent probes and received packets of another `PacketIo`.
+pub struct CapturingIo<W: Write> {
+    inner: Box<dyn PacketIo>,
+    writer: PcapWriter<W>,
+    protocol: IpNextHeaderProtocol,
+    source: Ipv4Addr,
+    ttl: u8,
+    /// IP options the kernel does not know of, added to probes by `inner`.
+    ip_options: IpOptions,
+    /// Reference point to convert the monotonic time of packets to wall clock time.
+    reference: (Instant, SystemTime),
+}
+
+impl CapturingIo<File> {
+    pub fn create(
+        inner: Box<dyn PacketIo>,
+        path: &str,
+        protocol: IpNextHeaderProtocol,
+        source: Ipv4Addr,
+    ) -> Self {
+        let file = match File::create(path) {
+            Ok(file) => file,
+            Err(e) => panic!("Could not create pcap file '{}': {}", path, e),
+        };
+
+        match PcapWriter::new(file) {
+            Ok(writer) => CapturingIo::new(inner, writer, protocol, source),
+            Err(e) => panic!("Could not write pcap file '{}': {}", path, e),
+        }
+    }
+}
+
+impl<W: Write> CapturingIo<W> {
+    pub fn new(
+        inner: Box<dyn PacketIo>,
+        writer: PcapWriter<W>,
+        protocol: IpNextHeaderProtocol,
+        source: Ipv4Addr,
+    ) -> Self {
+        let reference = (inner.now(), SystemTime::now());
+
+        CapturingIo {
+            inner,
+            writer,
+            protocol,
+            source,
+            ttl: 64,
+            ip_options: IpOptions::default(),
+            reference,
+        }
+    }
+
+    /// Records probes with `ip_options`, as sent by a `RawSocketIo` opened with them.
+    pub fn with_ip_options(mut self, ip_options: &IpOptions) -> Self {
+        self.ip_options = ip_options.clone();
+        self
+    }
+
+    fn to_system_time(&self, time: Instant) -> SystemTime {
+        let (reference_instant, reference_system_time) = self.reference;
+
+        if time >= reference_instant {
+            reference_system_time + (time - reference_instant)
+        } else {
+            reference_system_time - (reference_instant - time)
+        }
+    }
+
+    fn record(&mut self, time: Instant, data: &[u8]) {
+        let time = self.to_system_time(time);
+
+        if let Err(e) = self.writer.write_packet(time, data) {
+            error!("Could not write packet to pcap file: {}", e);
+        }
+    }
+
+    fn record_received(&mut self, packet: Option<ReceivedPacket>) -> Option<ReceivedPacket> {
+        if let Some(packet) = &packet {
+            self.record(packet.time_receive, &packet.data);
+        }
+
+        packet
+    }
+}
+
+impl<W: Write + Send> PacketIo for CapturingIo<W> {
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+        self.ttl = ttl;
+        self.inner.set_ttl(ttl)
+    }
+
+    fn send_to(&mut self, packet: &[u8], dst: IpAddr) -> io::Result<Instant> {
+        let time_send = self.inner.send_to(packet, dst)?;
+
+        // the kernel adds the IP header, so we reconstruct it for the capture
+        if let IpAddr::V4(dst) = dst {
+            let data = build_ipv4_packet_with_options(
+                self.source,
+                self.ip_options.first_hop(dst),
+                self.ttl,
+                self.protocol,
+                &self.ip_options.encode(dst),
+                packet,
+            );
+            self.record(time_send, &data);
+        }
+
+        Ok(time_send)
+    }
+
+    fn receive_icmp(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        let packet = self.inner.receive_icmp(timeout);
+        self.record_received(packet)
+    }
+
+    fn receive_protocol(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        let packet = self.inner.receive_protocol(timeout);
+        self.record_received(packet)
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ip_options::{final_destination, recorded_route};
+    use crate::packet_io::build_ipv4_packet;
+    use crate::protocols::icmp::IcmpTraceroute;
+    use crate::protocols::protocol::TracerouteProtocol;
+    use crate::simulation::{
+        SharedBuffer, SimulatedDestination, SimulatedNetwork, SimulatedRouter,
+    };
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::Ipv4Packet;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    }
+
+    fn read_timestamp(data: &[u8], record_offset: usize) -> Duration {
+        Duration::new(
+            read_u32(data, record_offset) as u64,
+            read_u32(data, record_offset + 4),
+        )
+    }
+
+    /// Splits a capture into its packets, skipping the global header.
+    fn read_records(data: &[u8]) -> Vec<&[u8]> {
+        let mut records = Vec::new();
+        let mut offset = 24;
+
+        while offset < data.len() {
+            let length = read_u32(data, offset + 8) as usize;
+            records.push(&data[offset + 16..offset + 16 + length]);
+            offset += 16 + length;
+        }
+
+        records
+    }
+
+    #[test]
+    fn writes_global_header() {
+        let mut buffer = Vec::new();
+        PcapWriter::new(&mut buffer).unwrap();
+
+        assert_eq!(buffer.len(), 24);
+        assert_eq!(read_u32(&buffer, 0), PCAP_MAGIC_NANOSECONDS);
+        assert_eq!(read_u32(&buffer, 20), LINKTYPE_RAW);
+    }
+
+    #[test]
+    fn writes_timestamped_records() {
+        let mut buffer = Vec::new();
+        let mut writer = PcapWriter::new(&mut buffer).unwrap();
+        let time = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+        writer.write_packet(time, &[1, 2, 3]).unwrap();
+
+        assert_eq!(read_u32(&buffer, 24), 1_600_000_000);
+        assert_eq!(read_u32(&buffer, 28), 123_456_789);
+        assert_eq!(read_u32(&buffer, 32), 3);
+        assert_eq!(&buffer[40..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn reads_own_captures() {
+        let mut buffer = Vec::new();
+        let mut writer = PcapWriter::new(&mut buffer).unwrap();
+        let time = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+        let packet = build_ipv4_packet(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            3,
+            IpNextHeaderProtocols::Udp,
+            &[0; 8],
+        );
+        writer.write_packet(time, &packet).unwrap();
+
+        let packets = read_pcap(&buffer[..]).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].time, time);
+        assert_eq!(packets[0].data, packet);
+    }
+
+    #[test]
+    fn reads_big_endian_ethernet_captures() {
+        let packet = build_ipv4_packet(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            3,
+            IpNextHeaderProtocols::Udp,
+            &[0; 8],
+        );
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+        frame.extend_from_slice(&[0, 42]);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&packet);
+        let arp_frame = [&[0; 12][..], &[0x08, 0x06], &[0; 28]].concat();
+
+        let mut capture = Vec::new();
+        capture.extend_from_slice(&PCAP_MAGIC_MICROSECONDS.to_be_bytes());
+        capture.extend_from_slice(&[0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255]);
+        capture.extend_from_slice(&LINKTYPE_ETHERNET.to_be_bytes());
+        for frame in [&frame, &arp_frame].iter() {
+            capture.extend_from_slice(&1u32.to_be_bytes());
+            capture.extend_from_slice(&500u32.to_be_bytes());
+            capture.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            capture.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            capture.extend_from_slice(frame);
+        }
+
+        let packets = read_pcap(&capture[..]).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].time, UNIX_EPOCH + Duration::new(1, 500_000));
+        assert_eq!(packets[0].data, packet);
+    }
+
+    #[test]
+    fn rejects_pcapng() {
+        let capture = [
+            PCAPNG_MAGIC.to_le_bytes(),
+            [0; 4],
+            [0; 4],
+            [0; 4],
+            [0; 4],
+            [0; 4],
+        ]
+        .concat();
+
+        assert!(read_pcap(&capture[..]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_microseconds() {
+        let mut capture = Vec::new();
+        capture.extend_from_slice(&PCAP_MAGIC_MICROSECONDS.to_le_bytes());
+        capture.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 0, 0]);
+        capture.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        capture.extend_from_slice(&1u32.to_le_bytes());
+        capture.extend_from_slice(&u32::MAX.to_le_bytes());
+        capture.extend_from_slice(&[0; 8]);
+
+        assert!(read_pcap(&capture[..]).is_err());
+    }
+
+    #[test]
+    fn captures_probes_and_replies() {
+        let source = Ipv4Addr::new(10, 0, 0, 1);
+        let destination = Ipv4Addr::new(10, 0, 9, 9);
+        let buffer = SharedBuffer::new();
+
+        let mut protocol = IcmpTraceroute::new();
+        let network = SimulatedNetwork::linear(
+            protocol.get_protocol(),
+            source,
+            vec![SimulatedRouter::new(
+                Ipv4Addr::new(10, 0, 1, 1),
+                Duration::from_millis(1),
+            )],
+            SimulatedDestination::new(destination, Duration::from_millis(5)),
+        );
+        let writer = PcapWriter::new(buffer.clone()).unwrap();
+        let mut io = CapturingIo::new(
+            Box::new(network),
+            writer,
+            IpNextHeaderProtocols::Icmp,
+            source,
+        );
+        io.set_ttl(1).unwrap();
+        protocol.set_io(Box::new(io));
+
+        protocol.send(IpAddr::V4(destination), 0).unwrap();
+        protocol.poll(IpAddr::V4(destination), Duration::from_secs(1));
+
+        let data = buffer.get_data();
+        let records = read_records(&data);
+        assert_eq!(records.len(), 2);
+
+        let probe = Ipv4Packet::new(records[0]).unwrap();
+        assert_eq!(probe.get_ttl(), 1);
+        assert_eq!(probe.get_destination(), destination);
+
+        let reply = Ipv4Packet::new(records[1]).unwrap();
+        assert_eq!(reply.get_source(), Ipv4Addr::new(10, 0, 1, 1));
+        let reply_offset = 24 + 16 + records[0].len();
+        assert_eq!(
+            read_timestamp(&data, reply_offset) - read_timestamp(&data, 24),
+            Duration::from_millis(1)
+        );
+    }
+
+    #[test]
+    fn captures_probes_with_ip_options() {
+        let source = Ipv4Addr::new(10, 0, 0, 1);
+        let gateway = Ipv4Addr::new(10, 0, 1, 1);
+        let destination = Ipv4Addr::new(10, 0, 9, 9);
+        let ip_options = IpOptions {
+            record_route: true,
+            loose_source_route: vec![gateway],
+        };
+        let buffer = SharedBuffer::new();
+
+        let mut protocol = IcmpTraceroute::new();
+        let network = SimulatedNetwork::linear(
+            protocol.get_protocol(),
+            source,
+            vec![SimulatedRouter::new(gateway, Duration::from_millis(1))],
+            SimulatedDestination::new(destination, Duration::from_millis(5)),
+        )
+        .with_ip_options(&ip_options);
+        let writer = PcapWriter::new(buffer.clone()).unwrap();
+        let io = CapturingIo::new(
+            Box::new(network),
+            writer,
+            IpNextHeaderProtocols::Icmp,
+            source,
+        )
+        .with_ip_options(&ip_options);
+        protocol.set_io(Box::new(io));
+
+        protocol.send(IpAddr::V4(destination), 0).unwrap();
+
+        let data = buffer.get_data();
+        let records = read_records(&data);
+        let probe = Ipv4Packet::new(records[0]).unwrap();
+        assert_eq!(probe.get_destination(), gateway);
+        assert_eq!(final_destination(&probe), destination);
+        assert_eq!(recorded_route(&probe), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,175 @@
+use rand::Rng;
+
+/// Smallest datagram carrying a QUIC Initial, servers drop anything shorter.
+const QUIC_MIN_DATAGRAM: usize = 1200;
+/// Reserved version of the form 0x?a?a?a?a, which servers answer with Version Negotiation.
+const QUIC_RESERVED_VERSION: u32 = 0x1a2a_3a4a;
+const QUIC_CONNECTION_ID_LENGTH: usize = 8;
+
+/// Application protocol spoken by UDP probes, so firewalls only passing well-formed traffic on
+/// well-known ports let them through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Application {
+    Dns,
+    Ntp,
+    Quic,
+}
+
+impl Application {
+    /// Template by its command line name, e.g. "dns".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dns" => Some(Application::Dns),
+            "ntp" => Some(Application::Ntp),
+            "quic" => Some(Application::Quic),
+            _ => None,
+        }
+    }
+
+    /// Application usually listening on `port`.
+    pub fn for_port(port: u16) -> Option<Self> {
+        match port {
+            53 => Some(Application::Dns),
+            123 => Some(Application::Ntp),
+            443 => Some(Application::Quic),
+            _ => None,
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Application::Dns => 53,
+            Application::Ntp => 123,
+            Application::Quic => 443,
+        }
+    }
+
+    /// Request with random identifiers, which the application's reply echoes.
+    pub fn create_request<R: Rng>(&self, rng: &mut R) -> Vec<u8> {
+        match self {
+            Application::Dns => create_dns_query(rng.gen()),
+            Application::Ntp => create_ntp_request(rng.gen()),
+            Application::Quic => create_quic_initial(rng.gen(), rng.gen()),
+        }
+    }
+
+    /// Whether `reply` is the application's answer to `request`.
+    pub fn is_reply(&self, request: &[u8], reply: &[u8]) -> bool {
+        match self {
+            Application::Dns => is_dns_response(request, reply),
+            Application::Ntp => is_ntp_response(request, reply),
+            Application::Quic => is_version_negotiation(request, reply),
+        }
+    }
+}
+
+/// Recursive query for the NS records of the root zone, which every resolver can answer.
+fn create_dns_query(id: u16) -> Vec<u8> {
+    let mut query = Vec::with_capacity(17);
+    query.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    // root name, type NS, class IN
+    query.extend_from_slice(&[0, 0, 2, 0, 1]);
+
+    query
+}
+
+fn is_dns_response(request: &[u8], reply: &[u8]) -> bool {
+    reply.len() >= 12 && reply.get(..2) == request.get(..2) && reply[2] & 0x80 != 0
+}
+
+/// NTPv4 client request, the random transmit timestamp comes back as origin timestamp.
+fn create_ntp_request(transmit_timestamp: u64) -> Vec<u8> {
+    let mut request = vec![0; 48];
+    // no leap indicator, version 4, client mode
+    request[0] = 0x23;
+    request[40..48].copy_from_slice(&transmit_timestamp.to_be_bytes());
+
+    request
+}
+
+fn is_ntp_response(request: &[u8], reply: &[u8]) -> bool {
+    // server or broadcast mode
+    reply.len() >= 48
+        && matches!(reply[0] & 0x07, 4 | 5)
+        && reply.get(24..32) == request.get(40..48)
+}
+
+/// Initial packet with a reserved version, padded to the minimal datagram size.
+///
+/// Servers answer it with Version Negotiation before any handshake keys are needed.
+fn create_quic_initial(destination_id: u64, source_id: u64) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(QUIC_MIN_DATAGRAM);
+    // long header, fixed bit, Initial, one byte packet number
+    packet.push(0xc0);
+    packet.extend_from_slice(&QUIC_RESERVED_VERSION.to_be_bytes());
+    packet.push(QUIC_CONNECTION_ID_LENGTH as u8);
+    packet.extend_from_slice(&destination_id.to_be_bytes());
+    packet.push(QUIC_CONNECTION_ID_LENGTH as u8);
+    packet.extend_from_slice(&source_id.to_be_bytes());
+    // no token
+    packet.push(0);
+    // two byte variable-length integer covering packet number and padding
+    let length = QUIC_MIN_DATAGRAM - packet.len() - 2;
+    packet.extend_from_slice(&(0x4000 | length as u16).to_be_bytes());
+    packet.resize(QUIC_MIN_DATAGRAM, 0);
+
+    packet
+}
+
+/// Version Negotiation swaps the connection IDs of our Initial.
+fn is_version_negotiation(request: &[u8], reply: &[u8]) -> bool {
+    let ids = |packet: &[u8]| {
+        let destination_length = *packet.get(5)? as usize;
+        let destination = packet.get(6..6 + destination_length)?.to_vec();
+        let source_length = *packet.get(6 + destination_length)? as usize;
+        let source_start = 7 + destination_length;
+        let source = packet
+            .get(source_start..source_start + source_length)?
+            .to_vec();
+        Some((destination, source))
+    };
+
+    let is_negotiation = reply.len() >= 5 && reply[0] & 0x80 != 0 && reply[1..5] == [0; 4];
+    match (ids(request), ids(reply)) {
+        (Some((destination, source)), Some((reply_destination, reply_source))) => {
+            is_negotiation && reply_destination == source && reply_source == destination
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::create_application_answer;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn builds_quic_initial_of_minimal_size() {
+        let packet = create_quic_initial(1, 2);
+
+        assert_eq!(packet.len(), QUIC_MIN_DATAGRAM);
+        assert_eq!(packet[1..5], QUIC_RESERVED_VERSION.to_be_bytes());
+        // the length field covers everything after it
+        let length = u16::from_be_bytes([packet[24], packet[25]]) & 0x3fff;
+        assert_eq!(length as usize, packet.len() - 26);
+    }
+
+    #[test]
+    fn recognizes_replies() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for application in [Application::Dns, Application::Ntp, Application::Quic] {
+            let request = application.create_request(&mut rng);
+            let other = application.create_request(&mut rng);
+            let reply = create_application_answer(application, &request);
+
+            assert!(application.is_reply(&request, &reply));
+            assert!(!application.is_reply(&other, &reply));
+            assert!(!application.is_reply(&request, &request));
+        }
+    }
+}
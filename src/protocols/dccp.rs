@@ -0,0 +1,316 @@
+use super::protocol::{fingerprint, Channels, ReceiveStatus, Result, TracerouteProtocol};
+use crate::interfaces::{addr_to_ipv4_addr, get_source_ip_for};
+use crate::packet_io::ReceivedPacket;
+use log::debug;
+use pnet::packet::icmp::destination_unreachable::IcmpCodes;
+use pnet::packet::icmp::{IcmpCode, IcmpType, IcmpTypes};
+use pnet::util::checksum;
+use pnet::{
+    packet::ip::IpNextHeaderProtocols,
+    transport::{TransportChannelType, TransportChannelType::Layer4, TransportProtocol::Ipv4},
+};
+use rand::Rng;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+/// Length of the generic header with 48-bit sequence numbers.
+const GENERIC_HEADER_LENGTH: usize = 16;
+pub const TYPE_REQUEST: u8 = 0;
+pub const TYPE_RESPONSE: u8 = 1;
+pub const TYPE_RESET: u8 = 7;
+/// Service code of our requests, as the destination port alone does not name a service.
+const SERVICE_CODE: u32 = u32::from_be_bytes(*b"TRCE");
+
+/// Traces with DCCP-Request packets, which the destination answers with DCCP-Response or
+/// DCCP-Reset.
+pub struct DccpTraceroute {
+    src_port: u16,
+    dst_port: u16,
+    /// Sequence number of our requests, which answers acknowledge.
+    sequence: u64,
+    channels: Channels,
+}
+
+impl DccpTraceroute {
+    pub fn new(dst_port: u16) -> Self {
+        let mut rng = rand::thread_rng();
+        DccpTraceroute::with_sequence(
+            rng.gen_range(50000..60000),
+            dst_port,
+            rng.gen_range(0..1 << 48),
+        )
+    }
+
+    pub fn with_sequence(src_port: u16, dst_port: u16, sequence: u64) -> Self {
+        DccpTraceroute {
+            src_port,
+            dst_port,
+            sequence,
+            channels: Channels::new(),
+        }
+    }
+
+    fn create_request(
+        &self,
+        src_port: u16,
+        sequence: u64,
+        source: Ipv4Addr,
+        dst: Ipv4Addr,
+    ) -> Vec<u8> {
+        let header = DccpHeader {
+            src_port,
+            dst_port: self.dst_port,
+            packet_type: TYPE_REQUEST,
+            sequence,
+        };
+
+        build_dccp_packet(&header, &SERVICE_CODE.to_be_bytes(), source, dst)
+    }
+
+    /// Whether `packet` is a DCCP packet of the destination answering our request.
+    fn is_answer(&self, packet: &[u8]) -> bool {
+        let header = match parse_header(packet) {
+            Some(header) => header,
+            None => return false,
+        };
+
+        header.src_port == self.dst_port
+            && header.dst_port == self.src_port
+            && matches!(header.packet_type, TYPE_RESPONSE | TYPE_RESET)
+            && parse_acknowledgement(packet) == Some(self.sequence)
+    }
+}
+
+impl TracerouteProtocol for DccpTraceroute {
+    fn get_protocol(&self) -> TransportChannelType {
+        Layer4(Ipv4(IpNextHeaderProtocols::Dccp))
+    }
+
+    fn send(&mut self, dst: IpAddr, _current_seq: u16) -> io::Result<Instant> {
+        let source = get_source_ip_for(dst);
+        let packet =
+            self.create_request(self.src_port, self.sequence, source, addr_to_ipv4_addr(dst));
+
+        self.get_io().send_to(&packet, dst)
+    }
+
+    /// Request from the destination's fingerprint as source port, with TTL and timestamp in
+    /// the sequence number, which is only quoted by routers which quote at least 16 bytes.
+    fn create_stateless_probe(
+        &self,
+        source: Ipv4Addr,
+        dst: Ipv4Addr,
+        ttl: u8,
+        timestamp: u16,
+    ) -> Vec<u8> {
+        let sequence = (ttl as u64) << 16 | timestamp as u64;
+
+        self.create_request(fingerprint(dst), sequence, source, dst)
+    }
+
+    fn decode_stateless_probe(&self, dst: Ipv4Addr, probe: &[u8]) -> Option<(u8, u16)> {
+        let header = parse_header(probe)?;
+
+        if header.src_port != fingerprint(dst) || header.packet_type != TYPE_REQUEST {
+            return None;
+        }
+
+        Some(((header.sequence >> 16) as u8, header.sequence as u16))
+    }
+
+    fn decode_stateless_answer(&self, received: &ReceivedPacket) -> Option<(u8, u16)> {
+        let packet = received.get_payload();
+        let header = parse_header(packet)?;
+        let sender = match received.get_sender()? {
+            IpAddr::V4(sender) => sender,
+            IpAddr::V6(_) => return None,
+        };
+
+        if header.dst_port != fingerprint(sender) {
+            return None;
+        }
+
+        let acknowledgement = parse_acknowledgement(packet)?;
+        Some(((acknowledgement >> 16) as u8, acknowledgement as u16))
+    }
+
+    /// Hosts without DCCP answer with Protocol Unreachable.
+    fn get_destination_reached_icmp(&self) -> Option<(IcmpType, IcmpCode)> {
+        Some((
+            IcmpTypes::DestinationUnreachable,
+            IcmpCodes::DestinationProtocolUnreachable,
+        ))
+    }
+
+    fn get_channels(&mut self) -> &mut Channels {
+        &mut self.channels
+    }
+
+    fn handle_protocol_level(&mut self, dst: IpAddr) -> Option<Result> {
+        let received = self.get_io().receive_protocol(Duration::from_millis(1))?;
+        let addr = received.get_sender()?;
+
+        if addr != dst || !self.is_answer(received.get_payload()) {
+            debug!("Ignoring DCCP packet from {}", addr);
+            return None;
+        }
+
+        Some(Result::new_filled(
+            ReceiveStatus::SuccessDestinationFound,
+            addr,
+            received.time_receive,
+            received.get_ttl(),
+            None,
+        ))
+    }
+
+    /// Matches the ports, and the type and sequence number if they were quoted.
+    fn matches_quoted_probe(&self, probe: &[u8]) -> bool {
+        let ports = match probe.get(..4) {
+            Some(ports) => ports,
+            None => return false,
+        };
+        let matches_ports = u16::from_be_bytes([ports[0], ports[1]]) == self.src_port
+            && u16::from_be_bytes([ports[2], ports[3]]) == self.dst_port;
+
+        matches_ports
+            && parse_header(probe).is_none_or(|header| {
+                header.packet_type == TYPE_REQUEST && header.sequence == self.sequence
+            })
+    }
+}
+
+/// Generic DCCP header, always with 48-bit sequence numbers.
+pub struct DccpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub packet_type: u8,
+    pub sequence: u64,
+}
+
+pub fn parse_header(packet: &[u8]) -> Option<DccpHeader> {
+    let header = packet.get(..GENERIC_HEADER_LENGTH)?;
+    // short sequence numbers are not used by requests and their answers
+    if header[8] & 1 == 0 {
+        return None;
+    }
+
+    let mut sequence = [0; 8];
+    sequence[2..].copy_from_slice(&header[10..16]);
+
+    Some(DccpHeader {
+        src_port: u16::from_be_bytes([header[0], header[1]]),
+        dst_port: u16::from_be_bytes([header[2], header[3]]),
+        packet_type: (header[8] >> 1) & 0x0f,
+        sequence: u64::from_be_bytes(sequence),
+    })
+}
+
+/// Acknowledgement number of packets with a 48-bit acknowledgement subheader.
+pub fn parse_acknowledgement(packet: &[u8]) -> Option<u64> {
+    let subheader = packet.get(GENERIC_HEADER_LENGTH + 2..GENERIC_HEADER_LENGTH + 8)?;
+
+    let mut acknowledgement = [0; 8];
+    acknowledgement[2..].copy_from_slice(subheader);
+    Some(u64::from_be_bytes(acknowledgement))
+}
+
+/// DCCP packet of `header` followed by the type-specific `rest`, without payload.
+///
+/// The checksum covers the whole packet (CsCov 0) and the IPv4 pseudo-header.
+pub fn build_dccp_packet(
+    header: &DccpHeader,
+    rest: &[u8],
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+) -> Vec<u8> {
+    let length = GENERIC_HEADER_LENGTH + rest.len();
+
+    let mut packet = Vec::with_capacity(length);
+    packet.extend_from_slice(&header.src_port.to_be_bytes());
+    packet.extend_from_slice(&header.dst_port.to_be_bytes());
+    // data offset in 32-bit words, then CCVal and CsCov
+    packet.push((length / 4) as u8);
+    packet.push(0);
+    packet.extend_from_slice(&[0; 2]);
+    packet.push(header.packet_type << 1 | 1);
+    packet.push(0);
+    packet.extend_from_slice(&header.sequence.to_be_bytes()[2..]);
+    packet.extend_from_slice(rest);
+
+    let checksum = dccp_checksum(&packet, src, dst);
+    packet[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    packet
+}
+
+/// Checksum over the IPv4 pseudo-header and the bytes covered according to CsCov.
+fn dccp_checksum(packet: &[u8], src: Ipv4Addr, dst: Ipv4Addr) -> u16 {
+    let checksum_coverage = (packet[5] & 0x0f) as usize;
+    let covered = match checksum_coverage {
+        0 => packet.len(),
+        // the header plus CsCov - 1 words of payload
+        _ => (packet[4] as usize + checksum_coverage - 1) * 4,
+    };
+
+    let mut data = Vec::with_capacity(12 + covered);
+    data.extend_from_slice(&src.octets());
+    data.extend_from_slice(&dst.octets());
+    data.push(0);
+    data.push(IpNextHeaderProtocols::Dccp.0);
+    data.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+    data.extend_from_slice(&packet[..covered.min(packet.len())]);
+
+    // the checksum field is part of the covered header, it must be zero while summing
+    checksum(&data, 9)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+
+    #[test]
+    fn builds_request_with_valid_checksum() {
+        let header = DccpHeader {
+            src_port: 50000,
+            dst_port: 5001,
+            packet_type: TYPE_REQUEST,
+            sequence: 0x0102_0304_0506,
+        };
+        let packet = build_dccp_packet(&header, &SERVICE_CODE.to_be_bytes(), SOURCE, DESTINATION);
+
+        assert_eq!(packet.len(), 20);
+        assert_eq!(packet[4], 5);
+        assert_eq!(packet[8], 0x01);
+        assert_eq!(packet[10..16], [1, 2, 3, 4, 5, 6]);
+
+        // summing the pseudo-header and the packet including its checksum yields zero
+        let mut data = SOURCE.octets().to_vec();
+        data.extend_from_slice(&DESTINATION.octets());
+        data.extend_from_slice(&[0, 33, 0, 20]);
+        data.extend_from_slice(&packet);
+        assert_eq!(checksum(&data, usize::MAX), 0);
+    }
+
+    #[test]
+    fn matches_quoted_probes() {
+        let protocol = DccpTraceroute::with_sequence(50000, 5001, 42);
+        let header = |sequence| DccpHeader {
+            src_port: 50000,
+            dst_port: 5001,
+            packet_type: TYPE_REQUEST,
+            sequence,
+        };
+        let probe = build_dccp_packet(&header(42), &[0; 4], SOURCE, DESTINATION);
+        let other = build_dccp_packet(&header(43), &[0; 4], SOURCE, DESTINATION);
+
+        assert!(protocol.matches_quoted_probe(&probe));
+        // routers quoting only 8 bytes
+        assert!(protocol.matches_quoted_probe(&probe[..8]));
+        assert!(!protocol.matches_quoted_probe(&other));
+    }
+}
@@ -1,41 +1,48 @@
-use super::protocol::MinimumChannels;
+use super::protocol::Channels;
 use super::protocol::ReceiveStatus;
 use super::protocol::Result;
 use super::protocol::TracerouteProtocol;
+use super::protocol::{checksum_fudge, fingerprint, ones_complement_add};
+use crate::ip_options::{final_destination, recorded_route};
+use crate::packet_io::ReceivedPacket;
 
-use log::error;
+use log::{debug, error};
 use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::Packet;
 use pnet::transport::TransportChannelType::Layer4;
 use pnet::util::checksum;
 use pnet::{
-    packet::icmp::{echo_request::MutableEchoRequestPacket, IcmpPacket, IcmpType},
-    transport::TransportReceiver,
-};
-use pnet::{
+    packet::icmp::{
+        echo_reply,
+        echo_request::{EchoRequestPacket, MutableEchoRequestPacket},
+        IcmpCode, IcmpPacket, IcmpType,
+    },
     packet::ip::IpNextHeaderProtocols,
-    transport::{TransportChannelType, TransportProtocol::Ipv4, TransportSender},
+    transport::{TransportChannelType, TransportProtocol::Ipv4},
 };
 use rand::Rng;
-use std::net::IpAddr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
 use std::time::Instant;
 
 pub struct IcmpTraceroute {
     identifier: u16,
-    channels: MinimumChannels,
+    channels: Channels,
 }
 
 impl IcmpTraceroute {
     pub fn new() -> Self {
         IcmpTraceroute {
             identifier: rand::thread_rng().gen::<u16>(),
-            channels: MinimumChannels::new(),
+            channels: Channels::new(),
         }
     }
 
     fn create_request<'packet>(
         &self,
-        buffer: &'packet mut Vec<u8>,
+        buffer: &'packet mut [u8],
+        identifier: u16,
         sequence_number: u16,
     ) -> MutableEchoRequestPacket<'packet> {
         use pnet::packet::icmp::echo_request::IcmpCodes;
@@ -44,10 +51,10 @@ impl IcmpTraceroute {
 
         packet.set_icmp_type(IcmpTypes::EchoRequest);
         packet.set_icmp_code(IcmpCodes::NoCode);
-        packet.set_identifier(self.identifier);
+        packet.set_identifier(identifier);
         packet.set_sequence_number(sequence_number);
 
-        let checksum = checksum(&packet.to_immutable().packet(), 1);
+        let checksum = checksum(packet.to_immutable().packet(), 1);
         packet.set_checksum(checksum);
 
         packet
@@ -63,64 +70,176 @@ impl TracerouteProtocol for IcmpTraceroute {
         Layer4(Ipv4(IpNextHeaderProtocols::Icmp))
     }
 
-    fn open(&mut self) {
-        let (tx_icmp, _, rx_icmp) = self.create_channels();
+    fn send(&mut self, dst: IpAddr, current_seq: u16) -> io::Result<Instant> {
+        let mut buffer = self.create_buffer();
+        let icmp_packet = self.create_request(&mut buffer, self.identifier, current_seq);
 
-        self.channels.tx = Some(tx_icmp);
-        self.channels.rx_icmp = Some(rx_icmp);
+        self.get_io().send_to(icmp_packet.packet(), dst)
     }
 
-    fn send(&mut self, dst: IpAddr, current_seq: u16) -> Instant {
-        let mut buffer = self.create_buffer();
-        let icmp_packet = self.create_request(&mut buffer, current_seq);
+    /// Echo request with the destination's fingerprint as identifier and the TTL as sequence
+    /// number, the timestamp is the checksum, adjusted by a two byte payload.
+    fn create_stateless_probe(
+        &self,
+        _source: Ipv4Addr,
+        dst: Ipv4Addr,
+        ttl: u8,
+        timestamp: u16,
+    ) -> Vec<u8> {
+        let mut buffer = vec![0; 10];
+        let mut packet = self.create_request(&mut buffer, fingerprint(dst), ttl as u16);
+
+        let fudge = checksum_fudge(packet.get_checksum(), timestamp);
+        packet.set_payload(&fudge.to_be_bytes());
+        packet.set_checksum(timestamp);
+
+        buffer
+    }
+
+    fn decode_stateless_probe(&self, dst: Ipv4Addr, probe: &[u8]) -> Option<(u8, u16)> {
+        let packet = EchoRequestPacket::new(probe)?;
 
-        self.get_tx().send_to(icmp_packet, dst).unwrap();
+        if packet.get_identifier() != fingerprint(dst) {
+            return None;
+        }
 
-        return Instant::now();
+        Some((packet.get_sequence_number() as u8, packet.get_checksum()))
     }
 
-    fn get_destination_reached_icmp_type(&self) -> Option<IcmpType> {
-        Some(IcmpTypes::EchoReply)
+    fn decode_stateless_answer(&self, received: &ReceivedPacket) -> Option<(u8, u16)> {
+        let packet = received.get_icmp_packet()?;
+        let sender = match received.get_sender()? {
+            IpAddr::V4(sender) => sender,
+            IpAddr::V6(_) => return None,
+        };
+
+        if packet.get_icmp_type() != IcmpTypes::EchoReply {
+            return None;
+        }
+
+        // the reply echoes identifier, sequence number and payload, only the type differs
+        let (ttl, checksum) = self.decode_stateless_probe(sender, packet.packet())?;
+        let type_difference = (IcmpTypes::EchoRequest.0 as u16) << 8;
+
+        // undo the difference on the sum, subtracting from the checksum maps 0 to 0xffff
+        Some((ttl, !ones_complement_add(!checksum, type_difference)))
     }
 
-    fn get_rx(&mut self) -> &mut TransportReceiver {
-        self.channels.rx_icmp.as_mut().unwrap()
+    fn get_destination_reached_icmp(&self) -> Option<(IcmpType, IcmpCode)> {
+        Some((IcmpTypes::EchoReply, echo_reply::IcmpCodes::NoCode))
     }
 
-    fn get_tx(&mut self) -> &mut TransportSender {
-        self.channels.tx.as_mut().unwrap()
+    fn get_channels(&mut self) -> &mut Channels {
+        &mut self.channels
     }
 }
 
 pub fn process_icmp_message(
-    packet: IcmpPacket,
-    sender: IpAddr,
+    received: &ReceivedPacket,
     dst: IpAddr,
-    icmp_dest_reached_type: Option<IcmpType>,
+    icmp_dest_reached: Option<(IcmpType, IcmpCode)>,
 ) -> Option<Result> {
-    let time_receive = Instant::now();
+    let packet = received.get_icmp_packet()?;
+    let sender = received.get_sender()?;
+
+    // probes routed through gateways quote their next gateway as destination until they pass it
+    if let Some(quoted) = get_quoted_header(&packet) {
+        if IpAddr::V4(final_destination(&quoted)) != dst {
+            debug!(
+                "Ignoring ICMP error from {} for a probe to {}",
+                sender,
+                quoted.get_destination()
+            );
+            return None;
+        }
+    }
+
+    let time_receive = received.time_receive;
+    let reply_ttl = received.get_ttl();
+    let quoted_ttl = get_quoted_ttl(&packet);
+
+    if let Some(quoted_ttl) = quoted_ttl {
+        debug!(
+            "ICMP message from {} quotes probe TTL {}",
+            sender, quoted_ttl
+        );
+    }
 
     let icmp_type = packet.get_icmp_type();
-    match icmp_type {
-        _ if icmp_dest_reached_type.is_some() && icmp_type == icmp_dest_reached_type.unwrap() => {
-            if sender == dst {
-                Some(Result::new_filled(
-                    ReceiveStatus::SuccessDestinationFound,
-                    sender,
-                    time_receive,
-                ))
-            } else {
-                Some(Result::new_empty(ReceiveStatus::Error))
-            }
-        }
+    // other codes from the destination, e.g. administratively prohibited, do not answer probes
+    let answers_probe = icmp_dest_reached == Some((icmp_type, packet.get_icmp_code()));
+    let mut result = match icmp_type {
+        _ if answers_probe && sender == dst => Some(Result::new_filled(
+            ReceiveStatus::SuccessDestinationFound,
+            sender,
+            time_receive,
+            reply_ttl,
+            quoted_ttl,
+        )),
+        IcmpTypes::DestinationUnreachable => Some(Result::new_filled(
+            ReceiveStatus::Unreachable,
+            sender,
+            time_receive,
+            reply_ttl,
+            quoted_ttl,
+        )),
+        _ if answers_probe => Some(Result::new_empty(ReceiveStatus::Error)),
         IcmpTypes::TimeExceeded => Some(Result::new_filled(
             ReceiveStatus::SuccessContinue,
             sender,
             time_receive,
+            reply_ttl,
+            quoted_ttl,
         )),
         _ => {
             error!("Received ICMP packet, but type is '{:?}'", icmp_type);
             Some(Result::new_empty(ReceiveStatus::Error))
         }
+    };
+
+    if let Some(result) = result.as_mut() {
+        let unreachable = result.status == ReceiveStatus::Unreachable;
+        if let Some(metadata) = result.metadata.as_mut() {
+            metadata.recorded_route = get_recorded_route(received, &packet).unwrap_or_default();
+            metadata.unreachable_code = unreachable.then(|| packet.get_icmp_code().0);
+        }
+    }
+    result
+}
+
+/// Addresses recorded in the Record Route option of the probe quoted in an ICMP error message,
+/// or of the reply itself, as echo replies carry the option of the request.
+fn get_recorded_route(received: &ReceivedPacket, packet: &IcmpPacket) -> Option<Vec<Ipv4Addr>> {
+    match get_quoted_header(packet) {
+        Some(quoted) => recorded_route(&quoted),
+        None => recorded_route(&received.get_ip_packet()?),
+    }
+}
+
+/// Transport header and payload of the probe quoted in an ICMP error message.
+pub fn get_quoted_probe(received: &ReceivedPacket) -> Option<&[u8]> {
+    let icmp_type = IcmpType(*received.get_payload().first()?);
+    if icmp_type != IcmpTypes::TimeExceeded && icmp_type != IcmpTypes::DestinationUnreachable {
+        return None;
+    }
+
+    let quoted = received.get_payload().get(8..)?;
+    let header_length = Ipv4Packet::new(quoted)?.get_header_length() as usize * 4;
+    quoted.get(header_length..)
+}
+
+/// Extracts the TTL of the IP header quoted in ICMP error messages.
+fn get_quoted_ttl(packet: &IcmpPacket) -> Option<u8> {
+    get_quoted_header(packet).map(|ip_packet| ip_packet.get_ttl())
+}
+
+/// The IP header quoted in ICMP error messages
+/// (type, code, checksum and 4 unused bytes precede the quoted header).
+fn get_quoted_header<'p>(packet: &'p IcmpPacket) -> Option<Ipv4Packet<'p>> {
+    match packet.get_icmp_type() {
+        IcmpTypes::TimeExceeded | IcmpTypes::DestinationUnreachable => {
+            Ipv4Packet::new(packet.packet().get(8..)?)
+        }
+        _ => None,
     }
 }
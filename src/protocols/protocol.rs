@@ -1,12 +1,12 @@
-use super::icmp::process_icmp_message;
-use pnet::transport::transport_channel;
-use pnet::transport::TransportChannelType::Layer4;
+use super::icmp::{get_quoted_probe, process_icmp_message};
+use crate::packet_io::{PacketIo, ReceivedPacket};
+use log::debug;
 use pnet::{
-    packet::icmp::IcmpType,
-    transport::{icmp_packet_iter, TransportChannelType, TransportReceiver, TransportSender},
+    packet::icmp::{IcmpCode, IcmpType},
+    transport::TransportChannelType,
 };
-use pnet::{packet::ip::IpNextHeaderProtocols, transport::TransportProtocol::Ipv4};
-use std::net::IpAddr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
 use std::time::Duration;
 use std::time::Instant;
 
@@ -16,6 +16,8 @@ pub enum ReceiveStatus {
     Error,
     SuccessContinue,
     SuccessDestinationFound,
+    /// A router or the destination reported that the destination cannot be reached.
+    Unreachable,
 }
 
 pub struct Result {
@@ -26,17 +28,27 @@ pub struct Result {
 impl Result {
     pub fn new_empty(status: ReceiveStatus) -> Self {
         Result {
-            status: status,
+            status,
             metadata: None,
         }
     }
 
-    pub fn new_filled(status: ReceiveStatus, addr: IpAddr, time_receive: Instant) -> Self {
+    pub fn new_filled(
+        status: ReceiveStatus,
+        addr: IpAddr,
+        time_receive: Instant,
+        reply_ttl: Option<u8>,
+        quoted_ttl: Option<u8>,
+    ) -> Self {
         Result {
-            status: status,
+            status,
             metadata: Some(AnswerMetadata {
-                addr: addr,
-                time_receive: time_receive,
+                addr,
+                time_receive,
+                reply_ttl,
+                quoted_ttl,
+                recorded_route: Vec::new(),
+                unreachable_code: None,
             }),
         }
     }
@@ -45,51 +57,100 @@ impl Result {
 pub struct AnswerMetadata {
     pub addr: IpAddr,
     pub time_receive: Instant,
+    /// TTL of the IP header of the reply when it arrived at our host.
+    pub reply_ttl: Option<u8>,
+    /// TTL of our probe as quoted in the payload of an ICMP error message.
+    pub quoted_ttl: Option<u8>,
+    /// Addresses in the Record Route option of the quoted probe or the echo reply.
+    pub recorded_route: Vec<Ipv4Addr>,
+    /// ICMP code of a Destination Unreachable message, which is not an answer of the destination.
+    pub unreachable_code: Option<u8>,
 }
-pub struct MinimumChannels {
-    pub tx: Option<TransportSender>,
-    pub rx_icmp: Option<TransportReceiver>,
+
+/// Identifies the destination of a stateless probe, so rewritten destinations are noticed.
+pub fn fingerprint(dst: Ipv4Addr) -> u16 {
+    let octets = dst.octets();
+
+    !ones_complement_add(
+        u16::from_be_bytes([octets[0], octets[1]]),
+        u16::from_be_bytes([octets[2], octets[3]]),
+    )
+}
+
+/// Value for a 16-bit word of the checksummed data which changes `checksum` to `target`.
+pub fn checksum_fudge(checksum: u16, target: u16) -> u16 {
+    // the sum without the fudge word is !checksum, the fudge adds the difference to !target
+    ones_complement_add(!target, checksum)
+}
+
+pub fn ones_complement_add(a: u16, b: u16) -> u16 {
+    let sum = a as u32 + b as u32;
+    ((sum & 0xffff) + (sum >> 16)) as u16
 }
 
-impl MinimumChannels {
+/// Packet I/O of a protocol, needs to be set before sending the first probe.
+pub struct Channels {
+    io: Option<Box<dyn PacketIo>>,
+}
+
+impl Channels {
     pub fn new() -> Self {
-        MinimumChannels {
-            tx: None,
-            rx_icmp: None,
-        }
+        Channels { io: None }
+    }
+
+    pub fn set_io(&mut self, io: Box<dyn PacketIo>) {
+        self.io = Some(io);
+    }
+
+    pub fn get_io(&mut self) -> &mut dyn PacketIo {
+        self.io.as_deref_mut().unwrap()
     }
 }
 
 pub trait TracerouteProtocol {
     fn get_protocol(&self) -> TransportChannelType;
 
-    fn get_rx(&mut self) -> &mut TransportReceiver;
+    /// Protocol of the destination's direct answers, handed out by `receive_protocol`.
+    fn get_reply_protocol(&self) -> TransportChannelType {
+        self.get_protocol()
+    }
+
+    fn get_channels(&mut self) -> &mut Channels;
 
-    fn get_tx(&mut self) -> &mut TransportSender;
+    fn get_io(&mut self) -> &mut dyn PacketIo {
+        self.get_channels().get_io()
+    }
 
-    fn open(&mut self);
+    fn set_io(&mut self, io: Box<dyn PacketIo>) {
+        self.get_channels().set_io(io);
+    }
 
     fn set_ttl(&mut self, ttl: u8) {
-        self.get_tx().set_ttl(ttl).unwrap();
+        self.get_io().set_ttl(ttl).unwrap();
     }
 
-    fn send(&mut self, dst: IpAddr, current_seq: u16) -> Instant;
+    fn send(&mut self, dst: IpAddr, current_seq: u16) -> io::Result<Instant>;
 
-    fn create_channels(&self) -> (TransportSender, TransportReceiver, TransportReceiver) {
-        let (tx_protocol, rx_protocol) = match transport_channel(4096, self.get_protocol()) {
-            Ok((tx, rx)) => (tx, rx),
-            Err(e) => panic!("An error occurred when creating tx/rx channel: {}", e),
-        };
+    /// Builds a probe for stateless probing which carries `ttl` and the send `timestamp` in
+    /// the part of its header which ICMP errors quote. Checksums cover the `source` address.
+    fn create_stateless_probe(
+        &self,
+        source: Ipv4Addr,
+        dst: Ipv4Addr,
+        ttl: u8,
+        timestamp: u16,
+    ) -> Vec<u8>;
 
-        let rx_icmp = match transport_channel(4096, Layer4(Ipv4(IpNextHeaderProtocols::Icmp))) {
-            Ok((_, rx)) => rx,
-            Err(e) => panic!("An error occurred when creating rx channel: {}", e),
-        };
+    /// Recovers TTL and timestamp from a stateless probe towards `dst`, e.g. as quoted.
+    fn decode_stateless_probe(&self, dst: Ipv4Addr, probe: &[u8]) -> Option<(u8, u16)>;
 
-        (tx_protocol, rx_protocol, rx_icmp)
+    /// Recovers TTL and timestamp from a direct answer of the destination to a stateless probe.
+    fn decode_stateless_answer(&self, _received: &ReceivedPacket) -> Option<(u8, u16)> {
+        None
     }
 
-    fn get_destination_reached_icmp_type(&self) -> Option<IcmpType> {
+    /// Type and code of the ICMP messages with which the destination itself answers probes.
+    fn get_destination_reached_icmp(&self) -> Option<(IcmpType, IcmpCode)> {
         None
     }
 
@@ -97,33 +158,42 @@ pub trait TracerouteProtocol {
         None
     }
 
-    fn handle_icmp_level(&mut self, dst: IpAddr) -> Option<Result> {
-        let icmp_dest_reached_type = self.get_destination_reached_icmp_type();
-        let mut iter = icmp_packet_iter(self.get_rx());
+    /// Whether the probe quoted by an ICMP error is one of ours, after the quoted IP header.
+    fn matches_quoted_probe(&self, _probe: &[u8]) -> bool {
+        true
+    }
 
-        match iter.next_with_timeout(Duration::from_millis(1)) {
-            Ok(None) => None,
-            Ok(Some((packet, sender))) => {
-                process_icmp_message(packet, sender, dst, icmp_dest_reached_type)
+    fn handle_icmp_level(&mut self, dst: IpAddr) -> Option<Result> {
+        let icmp_dest_reached = self.get_destination_reached_icmp();
+        let packet = self.get_io().receive_icmp(Duration::from_millis(1))?;
+
+        if let Some(probe) = get_quoted_probe(&packet) {
+            if !self.matches_quoted_probe(probe) {
+                debug!(
+                    "Ignoring ICMP error for another probe from {:?}",
+                    packet.get_sender()
+                );
+                return None;
             }
-            Err(_) => None,
         }
+
+        process_icmp_message(&packet, dst, icmp_dest_reached)
     }
 
-    fn poll(&mut self, dst: IpAddr, wait_secs: u8) -> Result {
-        let time_begin = Instant::now();
+    fn poll(&mut self, dst: IpAddr, wait: Duration) -> Result {
+        let time_begin = self.get_io().now();
 
-        while Instant::now() - time_begin < Duration::from_secs(wait_secs.into()) {
+        while self.get_io().now() - time_begin < wait {
             let result = match self.handle_protocol_level(dst) {
                 None => self.handle_icmp_level(dst),
                 Some(result) => Some(result),
             };
 
-            if result.is_some() {
-                return result.unwrap();
+            if let Some(result) = result {
+                return result;
             }
         }
 
-        return Result::new_empty(ReceiveStatus::Timeout);
+        Result::new_empty(ReceiveStatus::Timeout)
     }
 }
@@ -0,0 +1,155 @@
+use super::protocol::{fingerprint, Channels, ReceiveStatus, Result, TracerouteProtocol};
+use log::debug;
+use pnet::packet::icmp::destination_unreachable::IcmpCodes;
+use pnet::packet::icmp::{IcmpCode, IcmpType, IcmpTypes};
+use pnet::{
+    packet::ip::IpNextHeaderProtocol,
+    transport::{TransportChannelType, TransportChannelType::Layer4, TransportProtocol::Ipv4},
+};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+/// Traces with IP packets of an arbitrary protocol, e.g. GRE or ESP, carrying a fixed payload.
+///
+/// Hosts not speaking the protocol answer with Protocol Unreachable, others may be recognized
+/// by any packet of `reply_protocol` they send back.
+pub struct RawIpTraceroute {
+    protocol: IpNextHeaderProtocol,
+    payload: Vec<u8>,
+    reply_protocol: Option<IpNextHeaderProtocol>,
+    channels: Channels,
+}
+
+impl RawIpTraceroute {
+    pub fn new(protocol: u8, payload: Vec<u8>, reply_protocol: Option<u8>) -> Self {
+        RawIpTraceroute {
+            protocol: IpNextHeaderProtocol(protocol),
+            payload,
+            reply_protocol: reply_protocol.map(IpNextHeaderProtocol),
+            channels: Channels::new(),
+        }
+    }
+}
+
+impl TracerouteProtocol for RawIpTraceroute {
+    fn get_protocol(&self) -> TransportChannelType {
+        Layer4(Ipv4(self.protocol))
+    }
+
+    fn get_reply_protocol(&self) -> TransportChannelType {
+        Layer4(Ipv4(self.reply_protocol.unwrap_or(self.protocol)))
+    }
+
+    fn send(&mut self, dst: IpAddr, _current_seq: u16) -> io::Result<Instant> {
+        let payload = self.payload.clone();
+
+        self.get_io().send_to(&payload, dst)
+    }
+
+    /// The payload is replaced by the destination's fingerprint, TTL and timestamp, as nothing
+    /// else in the packet is ours to encode them in.
+    fn create_stateless_probe(
+        &self,
+        _source: Ipv4Addr,
+        dst: Ipv4Addr,
+        ttl: u8,
+        timestamp: u16,
+    ) -> Vec<u8> {
+        let mut probe = Vec::with_capacity(6);
+        probe.extend_from_slice(&fingerprint(dst).to_be_bytes());
+        probe.push(ttl);
+        probe.push(0);
+        probe.extend_from_slice(&timestamp.to_be_bytes());
+
+        probe
+    }
+
+    fn decode_stateless_probe(&self, dst: Ipv4Addr, probe: &[u8]) -> Option<(u8, u16)> {
+        let probe = probe.get(..6)?;
+
+        if u16::from_be_bytes([probe[0], probe[1]]) != fingerprint(dst) {
+            return None;
+        }
+
+        Some((probe[2], u16::from_be_bytes([probe[4], probe[5]])))
+    }
+
+    fn get_destination_reached_icmp(&self) -> Option<(IcmpType, IcmpCode)> {
+        Some((
+            IcmpTypes::DestinationUnreachable,
+            IcmpCodes::DestinationProtocolUnreachable,
+        ))
+    }
+
+    fn get_channels(&mut self) -> &mut Channels {
+        &mut self.channels
+    }
+
+    fn handle_protocol_level(&mut self, dst: IpAddr) -> Option<Result> {
+        self.reply_protocol?;
+        let received = self.get_io().receive_protocol(Duration::from_millis(1))?;
+        let addr = received.get_sender()?;
+
+        // a reply protocol equal to the probed one also shows our own probes on loopback
+        if addr != dst || received.get_payload() == self.payload.as_slice() {
+            debug!("Ignoring {} packet from {}", self.protocol, addr);
+            return None;
+        }
+
+        Some(Result::new_filled(
+            ReceiveStatus::SuccessDestinationFound,
+            addr,
+            received.time_receive,
+            received.get_ttl(),
+            None,
+        ))
+    }
+
+    /// Matches as much of the payload as was quoted.
+    fn matches_quoted_probe(&self, probe: &[u8]) -> bool {
+        self.payload.starts_with(probe)
+    }
+}
+
+/// Parses payloads given as hex digits, e.g. "0000 0800" for a GRE header carrying IPv4.
+pub fn parse_payload(hex: &str) -> Option<Vec<u8>> {
+    let digits = hex
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect::<Vec<char>>();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_payloads() {
+        assert_eq!(parse_payload("0000 0800"), Some(vec![0, 0, 8, 0]));
+        assert_eq!(
+            parse_payload("de:ad:BE:EF"),
+            Some(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(parse_payload(""), Some(vec![]));
+        assert_eq!(parse_payload("abc"), None);
+        assert_eq!(parse_payload("zz"), None);
+    }
+
+    #[test]
+    fn matches_quoted_payload() {
+        let protocol = RawIpTraceroute::new(47, vec![0, 0, 8, 0, 1, 2, 3, 4, 5, 6], None);
+
+        assert!(protocol.matches_quoted_probe(&[0, 0, 8, 0, 1, 2, 3, 4]));
+        assert!(!protocol.matches_quoted_probe(&[0, 0, 0x65, 0x58, 1, 2, 3, 4]));
+        assert!(!protocol.matches_quoted_probe(&[0; 12]));
+    }
+}
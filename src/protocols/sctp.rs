@@ -0,0 +1,250 @@
+use super::protocol::{fingerprint, Channels, ReceiveStatus, Result, TracerouteProtocol};
+use crate::packet_io::ReceivedPacket;
+use log::debug;
+use pnet::packet::icmp::destination_unreachable::IcmpCodes;
+use pnet::packet::icmp::{IcmpCode, IcmpType, IcmpTypes};
+use pnet::{
+    packet::ip::IpNextHeaderProtocols,
+    transport::{TransportChannelType, TransportChannelType::Layer4, TransportProtocol::Ipv4},
+};
+use rand::Rng;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+const COMMON_HEADER_LENGTH: usize = 12;
+const INIT_CHUNK_LENGTH: u16 = 20;
+pub const CHUNK_INIT: u8 = 1;
+pub const CHUNK_INIT_ACK: u8 = 2;
+pub const CHUNK_ABORT: u8 = 6;
+
+/// Traces with SCTP INIT chunks, which the destination answers with INIT-ACK or ABORT.
+pub struct SctpTraceroute {
+    src_port: u16,
+    dst_port: u16,
+    /// Initiate tag of our INITs, the destination's answers carry it as verification tag.
+    initiate_tag: u32,
+    channels: Channels,
+}
+
+impl SctpTraceroute {
+    pub fn new(dst_port: u16) -> Self {
+        let mut rng = rand::thread_rng();
+        SctpTraceroute::with_tag(
+            rng.gen_range(40000..50000),
+            dst_port,
+            rng.gen_range(1..=u32::MAX),
+        )
+    }
+
+    pub fn with_tag(src_port: u16, dst_port: u16, initiate_tag: u32) -> Self {
+        SctpTraceroute {
+            src_port,
+            dst_port,
+            initiate_tag,
+            channels: Channels::new(),
+        }
+    }
+
+    fn create_request(&self, src_port: u16, initiate_tag: u32) -> Vec<u8> {
+        let mut init = Vec::with_capacity(INIT_CHUNK_LENGTH as usize);
+        init.push(CHUNK_INIT);
+        init.push(0);
+        init.extend_from_slice(&INIT_CHUNK_LENGTH.to_be_bytes());
+        init.extend_from_slice(&initiate_tag.to_be_bytes());
+        // advertised receiver window, outbound and inbound streams
+        init.extend_from_slice(&65535u32.to_be_bytes());
+        init.extend_from_slice(&1u16.to_be_bytes());
+        init.extend_from_slice(&1u16.to_be_bytes());
+        // initial TSN
+        init.extend_from_slice(&initiate_tag.to_be_bytes());
+
+        // INITs must be sent with a verification tag of 0
+        build_sctp_packet(src_port, self.dst_port, 0, &init)
+    }
+
+    /// Whether `packet` is an SCTP packet of the destination answering our INIT.
+    fn is_answer(&self, packet: &[u8]) -> bool {
+        let header = match parse_common_header(packet) {
+            Some(header) => header,
+            None => return false,
+        };
+        let chunk_type = packet.get(COMMON_HEADER_LENGTH).copied();
+
+        header.src_port == self.dst_port
+            && header.dst_port == self.src_port
+            && header.verification_tag == self.initiate_tag
+            && matches!(chunk_type, Some(CHUNK_INIT_ACK) | Some(CHUNK_ABORT))
+    }
+}
+
+impl TracerouteProtocol for SctpTraceroute {
+    fn get_protocol(&self) -> TransportChannelType {
+        Layer4(Ipv4(IpNextHeaderProtocols::Sctp))
+    }
+
+    fn send(&mut self, dst: IpAddr, _current_seq: u16) -> io::Result<Instant> {
+        let packet = self.create_request(self.src_port, self.initiate_tag);
+
+        self.get_io().send_to(&packet, dst)
+    }
+
+    /// INIT from the destination's fingerprint as source port, with TTL and timestamp as the
+    /// upper and lower half of the initiate tag, which is only quoted by routers which quote
+    /// at least 28 bytes of the probe.
+    fn create_stateless_probe(
+        &self,
+        _source: Ipv4Addr,
+        dst: Ipv4Addr,
+        ttl: u8,
+        timestamp: u16,
+    ) -> Vec<u8> {
+        let initiate_tag = (ttl as u32) << 16 | timestamp as u32;
+
+        self.create_request(fingerprint(dst), initiate_tag)
+    }
+
+    fn decode_stateless_probe(&self, dst: Ipv4Addr, probe: &[u8]) -> Option<(u8, u16)> {
+        let header = parse_common_header(probe)?;
+        let initiate_tag = probe.get(16..20)?;
+
+        if header.src_port != fingerprint(dst) || probe[COMMON_HEADER_LENGTH] != CHUNK_INIT {
+            return None;
+        }
+
+        Some((
+            initiate_tag[1],
+            u16::from_be_bytes([initiate_tag[2], initiate_tag[3]]),
+        ))
+    }
+
+    fn decode_stateless_answer(&self, received: &ReceivedPacket) -> Option<(u8, u16)> {
+        let header = parse_common_header(received.get_payload())?;
+        let sender = match received.get_sender()? {
+            IpAddr::V4(sender) => sender,
+            IpAddr::V6(_) => return None,
+        };
+
+        if header.dst_port != fingerprint(sender) {
+            return None;
+        }
+
+        // INIT-ACK and ABORT carry our initiate tag as verification tag
+        let tag = header.verification_tag;
+        Some(((tag >> 16) as u8, tag as u16))
+    }
+
+    /// Hosts without SCTP answer with Protocol Unreachable.
+    fn get_destination_reached_icmp(&self) -> Option<(IcmpType, IcmpCode)> {
+        Some((
+            IcmpTypes::DestinationUnreachable,
+            IcmpCodes::DestinationProtocolUnreachable,
+        ))
+    }
+
+    fn get_channels(&mut self) -> &mut Channels {
+        &mut self.channels
+    }
+
+    fn handle_protocol_level(&mut self, dst: IpAddr) -> Option<Result> {
+        let received = self.get_io().receive_protocol(Duration::from_millis(1))?;
+        let addr = received.get_sender()?;
+
+        if addr != dst || !self.is_answer(received.get_payload()) {
+            debug!("Ignoring SCTP packet from {}", addr);
+            return None;
+        }
+
+        Some(Result::new_filled(
+            ReceiveStatus::SuccessDestinationFound,
+            addr,
+            received.time_receive,
+            received.get_ttl(),
+            None,
+        ))
+    }
+
+    /// Matches ports and verification tag, and the initiate tag if it was quoted.
+    fn matches_quoted_probe(&self, probe: &[u8]) -> bool {
+        let header = match parse_common_header(probe) {
+            Some(header) => header,
+            None => return false,
+        };
+        let initiate_tag = probe
+            .get(16..20)
+            .map(|tag| u32::from_be_bytes([tag[0], tag[1], tag[2], tag[3]]));
+
+        header.src_port == self.src_port
+            && header.dst_port == self.dst_port
+            && header.verification_tag == 0
+            && initiate_tag.is_none_or(|tag| tag == self.initiate_tag)
+    }
+}
+
+pub struct CommonHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub verification_tag: u32,
+}
+
+/// Reads the ports and verification tag, which are within the 8 bytes all ICMP errors quote.
+pub fn parse_common_header(packet: &[u8]) -> Option<CommonHeader> {
+    let header = packet.get(..8)?;
+
+    Some(CommonHeader {
+        src_port: u16::from_be_bytes([header[0], header[1]]),
+        dst_port: u16::from_be_bytes([header[2], header[3]]),
+        verification_tag: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+    })
+}
+
+/// SCTP packet with a single `chunk` and its CRC32c checksum.
+pub fn build_sctp_packet(
+    src_port: u16,
+    dst_port: u16,
+    verification_tag: u32,
+    chunk: &[u8],
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(COMMON_HEADER_LENGTH + chunk.len());
+    packet.extend_from_slice(&src_port.to_be_bytes());
+    packet.extend_from_slice(&dst_port.to_be_bytes());
+    packet.extend_from_slice(&verification_tag.to_be_bytes());
+    packet.extend_from_slice(&[0; 4]);
+    packet.extend_from_slice(chunk);
+
+    // unlike the other fields, the checksum is stored in little-endian byte order
+    let checksum = crc32c::crc32c(&packet);
+    packet[8..12].copy_from_slice(&checksum.to_le_bytes());
+
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_crc32c_checksum() {
+        let packet =
+            SctpTraceroute::with_tag(5000, 80, 0x0102_0304).create_request(5000, 0x0102_0304);
+
+        assert_eq!(packet.len(), 32);
+        let mut zeroed = packet.clone();
+        zeroed[8..12].copy_from_slice(&[0; 4]);
+        assert_eq!(packet[8..12], crc32c::crc32c(&zeroed).to_le_bytes());
+        // RFC 3720 B.4 test vector, 32 bytes of zeros
+        assert_eq!(crc32c::crc32c(&[0; 32]), 0x8a91_36aa);
+    }
+
+    #[test]
+    fn matches_quoted_probes() {
+        let protocol = SctpTraceroute::with_tag(5000, 80, 42);
+        let probe = protocol.create_request(5000, 42);
+
+        assert!(protocol.matches_quoted_probe(&probe));
+        // routers quoting only 8 bytes
+        assert!(protocol.matches_quoted_probe(&probe[..8]));
+        assert!(!protocol.matches_quoted_probe(&protocol.create_request(5000, 43)));
+        assert!(!protocol.matches_quoted_probe(&protocol.create_request(5001, 42)));
+    }
+}
@@ -1,17 +1,17 @@
-use super::protocol::{MinimumChannels, ReceiveStatus, Result, TracerouteProtocol};
-use crate::interfaces::{addr_to_ipv4_addr, get_source_ip};
+use super::protocol::{fingerprint, Channels, ReceiveStatus, Result, TracerouteProtocol};
+use crate::interfaces::{addr_to_ipv4_addr, get_source_ip_for};
+use crate::packet_io::ReceivedPacket;
 use log::{debug, warn};
 use pnet::{
     packet::{
         ip::IpNextHeaderProtocols,
-        tcp::{ipv4_checksum, MutableTcpPacket, TcpFlags},
-    },
-    transport::{
-        tcp_packet_iter, TransportChannelType, TransportChannelType::Layer4,
-        TransportProtocol::Ipv4, TransportReceiver, TransportSender,
+        tcp::{ipv4_checksum, MutableTcpPacket, TcpFlags, TcpPacket},
+        Packet,
     },
+    transport::{TransportChannelType, TransportChannelType::Layer4, TransportProtocol::Ipv4},
 };
 use rand::Rng;
+use std::io;
 use std::net::IpAddr;
 use std::{
     net::Ipv4Addr,
@@ -21,38 +21,42 @@ use std::{
 pub struct TcpTraceroute {
     src_port: u16,
     dst_port: u16,
-    minimum_channels: MinimumChannels,
-    rx_tcp: Option<TransportReceiver>,
+    channels: Channels,
 }
 
 impl TcpTraceroute {
     pub fn new(dst_port: u16) -> Self {
+        TcpTraceroute::with_ports(rand::thread_rng().gen_range(60000..65535), dst_port)
+    }
+
+    pub fn with_ports(src_port: u16, dst_port: u16) -> Self {
         TcpTraceroute {
-            src_port: rand::thread_rng().gen_range(60000..65535),
-            dst_port: dst_port,
-            minimum_channels: MinimumChannels::new(),
-            rx_tcp: None,
+            src_port,
+            dst_port,
+            channels: Channels::new(),
         }
     }
 
     fn create_request<'packet>(
         &self,
-        buffer: &'packet mut Vec<u8>,
-        _sequence_number: u16,
+        buffer: &'packet mut [u8],
+        src_port: u16,
+        sequence: u32,
+        source: Ipv4Addr,
         dst: Ipv4Addr,
     ) -> MutableTcpPacket<'packet> {
         let mut packet = MutableTcpPacket::new(buffer).unwrap();
 
-        packet.set_source(self.src_port);
+        packet.set_source(src_port);
         packet.set_destination(self.dst_port);
-        packet.set_sequence(1337);
+        packet.set_sequence(sequence);
         packet.set_acknowledgement(0);
         packet.set_data_offset(5);
         packet.set_flags(TcpFlags::SYN);
         packet.set_window(0);
         packet.set_urgent_ptr(0);
 
-        let checksum = ipv4_checksum(&packet.to_immutable(), &get_source_ip(), &dst);
+        let checksum = ipv4_checksum(&packet.to_immutable(), &source, &dst);
         packet.set_checksum(checksum);
 
         packet
@@ -60,10 +64,11 @@ impl TcpTraceroute {
 
     fn create_rst_packet<'packet>(
         &self,
-        buffer: &'packet mut Vec<u8>,
+        buffer: &'packet mut [u8],
         dst: Ipv4Addr,
     ) -> MutableTcpPacket<'packet> {
-        let mut packet = self.create_request(buffer, 0, dst);
+        let source = get_source_ip_for(IpAddr::V4(dst));
+        let mut packet = self.create_request(buffer, self.src_port, 1337, source, dst);
         packet.set_flags(TcpFlags::RST);
 
         packet
@@ -73,16 +78,12 @@ impl TcpTraceroute {
         let mut buffer = self.create_buffer();
 
         let rst_packet = self.create_rst_packet(&mut buffer, addr_to_ipv4_addr(dst));
-        &self.get_tx().send_to(rst_packet, dst).unwrap();
+        self.get_io().send_to(rst_packet.packet(), dst).unwrap();
     }
 
     fn create_buffer(&mut self) -> Vec<u8> {
         vec![0; 20]
     }
-
-    fn get_tcp_rx(&mut self) -> &mut TransportReceiver {
-        self.rx_tcp.as_mut().unwrap()
-    }
 }
 
 impl TracerouteProtocol for TcpTraceroute {
@@ -90,70 +91,102 @@ impl TracerouteProtocol for TcpTraceroute {
         Layer4(Ipv4(IpNextHeaderProtocols::Tcp))
     }
 
-    fn send(&mut self, dst: IpAddr, current_seq: u16) -> Instant {
+    fn send(&mut self, dst: IpAddr, _current_seq: u16) -> io::Result<Instant> {
         let mut buffer = self.create_buffer();
 
-        let tcp_packet = self.create_request(&mut buffer, current_seq, addr_to_ipv4_addr(dst));
+        let source = get_source_ip_for(dst);
+        let tcp_packet = self.create_request(
+            &mut buffer,
+            self.src_port,
+            1337,
+            source,
+            addr_to_ipv4_addr(dst),
+        );
 
-        self.get_tx().send_to(tcp_packet, dst).unwrap();
-
-        return Instant::now();
+        self.get_io().send_to(tcp_packet.packet(), dst)
     }
 
-    fn get_rx(&mut self) -> &mut TransportReceiver {
-        self.minimum_channels.rx_icmp.as_mut().unwrap()
+    /// SYN from the destination's fingerprint as source port, with TTL and timestamp as the
+    /// upper and lower half of the sequence number.
+    fn create_stateless_probe(
+        &self,
+        source: Ipv4Addr,
+        dst: Ipv4Addr,
+        ttl: u8,
+        timestamp: u16,
+    ) -> Vec<u8> {
+        let mut buffer = vec![0; 20];
+        let sequence = (ttl as u32) << 16 | timestamp as u32;
+        self.create_request(&mut buffer, fingerprint(dst), sequence, source, dst);
+
+        buffer
     }
 
-    fn get_tx(&mut self) -> &mut TransportSender {
-        self.minimum_channels.tx.as_mut().unwrap()
+    fn decode_stateless_probe(&self, dst: Ipv4Addr, probe: &[u8]) -> Option<(u8, u16)> {
+        // quotes may only contain the first 8 bytes, which is just enough for the sequence
+        let src_port = u16::from_be_bytes([*probe.first()?, *probe.get(1)?]);
+        let sequence = probe.get(4..8)?;
+
+        if src_port != fingerprint(dst) {
+            return None;
+        }
+
+        Some((sequence[1], u16::from_be_bytes([sequence[2], sequence[3]])))
     }
 
-    fn open(&mut self) {
-        let (tx_tcp, rx_tcp, rx_icmp) = self.create_channels();
+    fn decode_stateless_answer(&self, received: &ReceivedPacket) -> Option<(u8, u16)> {
+        let packet = TcpPacket::new(received.get_payload())?;
+        let sender = match received.get_sender()? {
+            IpAddr::V4(sender) => sender,
+            IpAddr::V6(_) => return None,
+        };
+
+        if packet.get_destination() != fingerprint(sender) {
+            return None;
+        }
+
+        // SYN-ACK and RST acknowledge our sequence number plus one
+        let sequence = packet.get_acknowledgement().wrapping_sub(1);
 
-        self.minimum_channels.tx = Some(tx_tcp);
-        self.minimum_channels.rx_icmp = Some(rx_icmp);
-        self.rx_tcp = Some(rx_tcp);
+        Some(((sequence >> 16) as u8, sequence as u16))
+    }
+
+    fn get_channels(&mut self) -> &mut Channels {
+        &mut self.channels
     }
 
     fn handle_protocol_level(&mut self, dst: IpAddr) -> Option<Result> {
-        let src_port = self.src_port;
-        let rx = self.get_tcp_rx();
-        let mut iter = tcp_packet_iter(rx);
-
-        return match iter.next_with_timeout(Duration::from_millis(1)) {
-            Ok(None) => None,
-            Ok(Some((packet, addr))) => {
-                if packet.get_destination() == src_port {
-                    let time_receive = Instant::now();
-
-                    if addr == dst {
-                        let flags = packet.get_flags();
-
-                        if flags == TcpFlags::SYN | TcpFlags::ACK {
-                            // half-open technique
-                            debug!("Received SYN and ACK, sending RST. (half-open)");
-                            self.send_rst_packet(dst);
-                        }
-
-                        Some(Result::new_filled(
-                            ReceiveStatus::SuccessDestinationFound,
-                            addr,
-                            time_receive,
-                        ))
-                    } else {
-                        warn!("Received unexpected packet {:?}", packet);
-                        None
-                    }
-                } else {
-                    warn!(
-                        "Received packet not addressed to me but port {}",
-                        packet.get_destination()
-                    );
-                    None
+        let received = self.get_io().receive_protocol(Duration::from_millis(1))?;
+        let packet = TcpPacket::new(received.get_payload())?;
+        let addr = received.get_sender()?;
+
+        if packet.get_destination() == self.src_port {
+            if addr == dst {
+                let flags = packet.get_flags();
+
+                if flags == TcpFlags::SYN | TcpFlags::ACK {
+                    // half-open technique
+                    debug!("Received SYN and ACK, sending RST. (half-open)");
+                    self.send_rst_packet(dst);
                 }
+
+                Some(Result::new_filled(
+                    ReceiveStatus::SuccessDestinationFound,
+                    addr,
+                    received.time_receive,
+                    received.get_ttl(),
+                    None,
+                ))
+            } else {
+                warn!("Received unexpected packet {:?}", packet);
+                None
             }
-            Err(_) => None,
-        };
+        } else {
+            warn!(
+                "Received packet not addressed to me but port {}",
+                packet.get_destination()
+            );
+            None
+        }
     }
 }
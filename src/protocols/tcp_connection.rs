@@ -0,0 +1,235 @@
+use super::protocol::{Channels, ReceiveStatus, Result, TracerouteProtocol};
+use crate::interfaces::{addr_to_ipv4_addr, get_source_ip_for};
+use crate::packet_io::ReceivedPacket;
+use log::{debug, info, warn};
+use pnet::{
+    packet::{
+        ip::IpNextHeaderProtocols,
+        tcp::{ipv4_checksum, MutableTcpPacket, TcpFlags, TcpPacket},
+        Packet,
+    },
+    transport::{TransportChannelType, TransportChannelType::Layer4, TransportProtocol::Ipv4},
+};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+/// How long to wait for the first segment of the connection, which reveals its sequence numbers.
+const OBSERVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Traces inside an established TCP connection, whose segments pass stateful firewalls which
+/// drop fresh SYNs (0trace).
+///
+/// Probes are keep-alives, which repeat the last byte already sent, so the destination answers
+/// them with an ACK without the connection's data being affected.
+pub struct TcpConnectionTraceroute {
+    /// Local port of the connection, chosen by the kernel when we connect ourselves.
+    src_port: Option<u16>,
+    dst_port: u16,
+    /// Next sequence numbers of our side and of the destination, taken from its segments.
+    sequence: Option<(u32, u32)>,
+    /// When the last probe was sent and our next sequence number then, which its duplicate ACK
+    /// acknowledges.
+    last_probe: Option<(Instant, u32)>,
+    /// Connection opened by us, kept open while tracing.
+    _stream: Option<TcpStream>,
+    channels: Channels,
+}
+
+impl TcpConnectionTraceroute {
+    /// Traces inside the connection from local port `src_port`, or a new one if not given.
+    pub fn new(dst_port: u16, src_port: Option<u16>) -> Self {
+        TcpConnectionTraceroute {
+            src_port,
+            dst_port,
+            sequence: None,
+            last_probe: None,
+            _stream: None,
+            channels: Channels::new(),
+        }
+    }
+
+    /// Opens the connection, its SYN-ACK is then waiting on the protocol channel.
+    fn connect(&mut self, dst: IpAddr) -> io::Result<u16> {
+        let addr = SocketAddr::new(dst, self.dst_port);
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+        let src_port = stream.local_addr()?.port();
+        info!("Connected to {} from port {}", addr, src_port);
+
+        self._stream = Some(stream);
+        Ok(src_port)
+    }
+
+    /// Waits for a segment of the destination to learn the sequence numbers of the connection.
+    fn observe(&mut self, dst: IpAddr) -> io::Result<()> {
+        let time_begin = self.get_io().now();
+
+        while self.sequence.is_none() {
+            if self.get_io().now() - time_begin > OBSERVE_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "no segment of the connection to {}:{} seen within {} seconds",
+                        dst,
+                        self.dst_port,
+                        OBSERVE_TIMEOUT.as_secs()
+                    ),
+                ));
+            }
+
+            if let Some(received) = self.get_io().receive_protocol(Duration::from_millis(100)) {
+                self.learn(&received, dst);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Updates the sequence numbers if `received` is a segment of the connection, returns its
+    /// flags and whether it carries data.
+    fn learn(&mut self, received: &ReceivedPacket, dst: IpAddr) -> Option<(u8, bool)> {
+        let packet = TcpPacket::new(received.get_payload())?;
+        let flags = packet.get_flags();
+
+        if received.get_sender()? != dst
+            || packet.get_source() != self.dst_port
+            || Some(packet.get_destination()) != self.src_port
+            || flags & TcpFlags::ACK == 0
+        {
+            return None;
+        }
+
+        // SYN and FIN take up a sequence number as well
+        let has_data = !packet.payload().is_empty();
+        let length =
+            packet.payload().len() as u32 + (flags & (TcpFlags::SYN | TcpFlags::FIN) != 0) as u32;
+        let next = packet.get_sequence().wrapping_add(length);
+        match self.sequence {
+            // retransmissions must not move the sequence numbers back
+            Some((_, their_next)) if (next.wrapping_sub(their_next) as i32) < 0 => {}
+            _ => self.sequence = Some((packet.get_acknowledgement(), next)),
+        }
+
+        Some((flags, has_data))
+    }
+}
+
+impl TracerouteProtocol for TcpConnectionTraceroute {
+    fn get_protocol(&self) -> TransportChannelType {
+        Layer4(Ipv4(IpNextHeaderProtocols::Tcp))
+    }
+
+    fn send(&mut self, dst: IpAddr, _current_seq: u16) -> io::Result<Instant> {
+        if self.src_port.is_none() {
+            self.src_port = Some(self.connect(dst)?);
+        }
+        self.observe(dst)?;
+
+        let (our_next, their_next) = self.sequence.unwrap();
+        let dst = addr_to_ipv4_addr(dst);
+        let probe = build_segment(
+            self.src_port.unwrap(),
+            self.dst_port,
+            our_next.wrapping_sub(1),
+            their_next,
+            TcpFlags::ACK,
+            &[0],
+            dst,
+        );
+
+        let time_send = self.get_io().send_to(&probe, IpAddr::V4(dst))?;
+        self.last_probe = Some((time_send, our_next));
+        Ok(time_send)
+    }
+
+    fn create_stateless_probe(
+        &self,
+        _source: Ipv4Addr,
+        _dst: Ipv4Addr,
+        _ttl: u8,
+        _timestamp: u16,
+    ) -> Vec<u8> {
+        unreachable!("connections are not traced statelessly")
+    }
+
+    fn decode_stateless_probe(&self, _dst: Ipv4Addr, _probe: &[u8]) -> Option<(u8, u16)> {
+        None
+    }
+
+    fn get_channels(&mut self) -> &mut Channels {
+        &mut self.channels
+    }
+
+    fn handle_protocol_level(&mut self, dst: IpAddr) -> Option<Result> {
+        let received = self.get_io().receive_protocol(Duration::from_millis(1))?;
+        let acknowledgement = TcpPacket::new(received.get_payload())?.get_acknowledgement();
+        let (flags, has_data) = match self.learn(&received, dst) {
+            Some(segment) => segment,
+            None => {
+                debug!("Ignoring TCP packet from {:?}", received.get_sender());
+                return None;
+            }
+        };
+
+        if flags & TcpFlags::RST != 0 {
+            warn!("The destination reset the connection.");
+        } else {
+            // only the duplicate ACK of the keep-alive answers a probe, not segments with data
+            // of the application or ACKs of the data it sent
+            let answers_probe = self.last_probe.is_some_and(|(time_send, our_next)| {
+                received.time_receive >= time_send && acknowledgement == our_next
+            });
+            if has_data || flags & TcpFlags::SYN != 0 || !answers_probe {
+                return None;
+            }
+        }
+
+        Some(Result::new_filled(
+            ReceiveStatus::SuccessDestinationFound,
+            received.get_sender()?,
+            received.time_receive,
+            received.get_ttl(),
+            None,
+        ))
+    }
+
+    /// Matches the ports of the connection, quoted by every ICMP error.
+    fn matches_quoted_probe(&self, probe: &[u8]) -> bool {
+        match probe.get(..4) {
+            Some(ports) => {
+                Some(u16::from_be_bytes([ports[0], ports[1]])) == self.src_port
+                    && u16::from_be_bytes([ports[2], ports[3]]) == self.dst_port
+            }
+            None => false,
+        }
+    }
+}
+
+/// TCP segment towards `dst` carrying `payload`.
+pub fn build_segment(
+    src_port: u16,
+    dst_port: u16,
+    sequence: u32,
+    acknowledgement: u32,
+    flags: u8,
+    payload: &[u8],
+    dst: Ipv4Addr,
+) -> Vec<u8> {
+    let mut buffer = vec![0; 20 + payload.len()];
+    let mut packet = MutableTcpPacket::new(&mut buffer).unwrap();
+
+    packet.set_source(src_port);
+    packet.set_destination(dst_port);
+    packet.set_sequence(sequence);
+    packet.set_acknowledgement(acknowledgement);
+    packet.set_data_offset(5);
+    packet.set_flags(flags);
+    packet.set_window(1024);
+    packet.set_payload(payload);
+
+    let source = get_source_ip_for(IpAddr::V4(dst));
+    let checksum = ipv4_checksum(&packet.to_immutable(), &source, &dst);
+    packet.set_checksum(checksum);
+
+    buffer
+}
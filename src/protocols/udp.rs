@@ -1,49 +1,91 @@
-use super::protocol::MinimumChannels;
+use super::application::Application;
+use super::protocol::Channels;
 use super::protocol::TracerouteProtocol;
-use pnet::packet::icmp::{IcmpType, IcmpTypes};
-use pnet::packet::udp::MutableUdpPacket;
+use super::protocol::{checksum_fudge, fingerprint, ReceiveStatus, Result};
+use log::debug;
+use pnet::packet::icmp::destination_unreachable::IcmpCodes;
+use pnet::packet::icmp::{IcmpCode, IcmpType, IcmpTypes};
+use pnet::packet::udp::{ipv4_checksum, MutableUdpPacket, UdpPacket};
+use pnet::packet::Packet;
 use pnet::transport::TransportChannelType::Layer4;
 use pnet::{
     packet::ip::IpNextHeaderProtocols,
-    transport::{
-        TransportChannelType, TransportProtocol::Ipv4, TransportReceiver, TransportSender,
-    },
+    transport::{TransportChannelType, TransportProtocol::Ipv4},
 };
 use rand::Rng;
-use std::net::IpAddr;
-use std::time::Instant;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+/// Payload of probes which do not speak an application protocol.
+const DEFAULT_PAYLOAD: &[u8] = b"SUPERMAN\0";
 
 pub struct UdpTraceroute {
     src_port: u16,
     dst_port: u16,
-    channels: MinimumChannels,
+    payload: Vec<u8>,
+    /// Application spoken by the probes, whose answers show that the destination was reached.
+    application: Option<Application>,
+    channels: Channels,
 }
 
 impl UdpTraceroute {
-    pub fn new(dst_port: u16) -> Self {
+    pub fn with_application(dst_port: u16, application: Option<Application>) -> Self {
+        let mut rng = rand::thread_rng();
+        let payload = match application {
+            Some(application) => application.create_request(&mut rng),
+            None => DEFAULT_PAYLOAD.to_vec(),
+        };
+
+        UdpTraceroute::with_request(rng.gen_range(30000..40000), dst_port, payload, application)
+    }
+
+    pub fn with_request(
+        src_port: u16,
+        dst_port: u16,
+        payload: Vec<u8>,
+        application: Option<Application>,
+    ) -> Self {
         UdpTraceroute {
-            src_port: rand::thread_rng().gen_range(30000..40000),
-            dst_port: dst_port,
-            channels: MinimumChannels::new(),
+            src_port,
+            dst_port,
+            payload,
+            application,
+            channels: Channels::new(),
         }
     }
 
-    fn create_request<'packet>(&self, buffer: &'packet mut Vec<u8>) -> MutableUdpPacket<'packet> {
+    /// Whether `packet` is the application's answer from the destination port.
+    fn is_answer(&self, application: Application, packet: &[u8]) -> bool {
+        match UdpPacket::new(packet) {
+            Some(packet) => {
+                packet.get_source() == self.dst_port
+                    && packet.get_destination() == self.src_port
+                    && application.is_reply(&self.payload, packet.payload())
+            }
+            None => false,
+        }
+    }
+
+    fn create_request<'packet>(
+        &self,
+        buffer: &'packet mut [u8],
+        src_port: u16,
+        payload: &[u8],
+    ) -> MutableUdpPacket<'packet> {
         let mut packet = MutableUdpPacket::new(buffer).unwrap();
 
-        packet.set_source(self.src_port);
+        packet.set_source(src_port);
         packet.set_destination(self.dst_port);
-        packet.set_length(17);
+        packet.set_length(8 + payload.len() as u16);
         packet.set_checksum(0);
-
-        let payload: [u8; 9] = [b'S', b'U', b'P', b'E', b'R', b'M', b'A', b'N', 0x00];
-        packet.set_payload(&payload);
+        packet.set_payload(payload);
 
         packet
     }
 
-    fn create_buffer(&self) -> Vec<u8> {
-        vec![0; 17]
+    fn create_buffer(&self, payload_length: usize) -> Vec<u8> {
+        vec![0; 8 + payload_length]
     }
 }
 
@@ -52,31 +94,71 @@ impl TracerouteProtocol for UdpTraceroute {
         Layer4(Ipv4(IpNextHeaderProtocols::Udp))
     }
 
-    fn send(&mut self, dst: IpAddr, _current_seq: u16) -> Instant {
-        let mut buffer = self.create_buffer();
-        let udp_packet = self.create_request(&mut buffer);
+    fn send(&mut self, dst: IpAddr, _current_seq: u16) -> io::Result<Instant> {
+        let mut buffer = self.create_buffer(self.payload.len());
+        let udp_packet = self.create_request(&mut buffer, self.src_port, &self.payload);
 
-        self.get_tx().send_to(udp_packet, dst).unwrap();
+        self.get_io().send_to(udp_packet.packet(), dst)
+    }
 
-        return Instant::now();
+    /// Datagram from the destination's fingerprint as source port, the TTL is encoded in the
+    /// length and the timestamp is the checksum, adjusted by the first two payload bytes.
+    fn create_stateless_probe(
+        &self,
+        source: Ipv4Addr,
+        dst: Ipv4Addr,
+        ttl: u8,
+        timestamp: u16,
+    ) -> Vec<u8> {
+        let payload = vec![0; 2 + ttl as usize];
+        let mut buffer = self.create_buffer(payload.len());
+        let mut packet = self.create_request(&mut buffer, fingerprint(dst), &payload);
+
+        let checksum = ipv4_checksum(&packet.to_immutable(), &source, &dst);
+        let fudge = checksum_fudge(checksum, timestamp);
+        packet.set_payload(&[&fudge.to_be_bytes()[..], &payload[2..]].concat());
+        packet.set_checksum(timestamp);
+
+        buffer
     }
 
-    fn get_destination_reached_icmp_type(&self) -> Option<IcmpType> {
-        Some(IcmpTypes::DestinationUnreachable)
+    fn decode_stateless_probe(&self, dst: Ipv4Addr, probe: &[u8]) -> Option<(u8, u16)> {
+        let packet = UdpPacket::new(probe)?;
+
+        if packet.get_source() != fingerprint(dst) || packet.get_length() < 10 {
+            return None;
+        }
+
+        Some(((packet.get_length() - 10) as u8, packet.get_checksum()))
     }
 
-    fn get_rx(&mut self) -> &mut TransportReceiver {
-        self.channels.rx_icmp.as_mut().unwrap()
+    fn get_destination_reached_icmp(&self) -> Option<(IcmpType, IcmpCode)> {
+        Some((
+            IcmpTypes::DestinationUnreachable,
+            IcmpCodes::DestinationPortUnreachable,
+        ))
     }
 
-    fn get_tx(&mut self) -> &mut TransportSender {
-        self.channels.tx.as_mut().unwrap()
+    fn get_channels(&mut self) -> &mut Channels {
+        &mut self.channels
     }
 
-    fn open(&mut self) {
-        let (tx_udp, _, rx_icmp) = self.create_channels();
+    fn handle_protocol_level(&mut self, dst: IpAddr) -> Option<Result> {
+        let application = self.application?;
+        let received = self.get_io().receive_protocol(Duration::from_millis(1))?;
+        let addr = received.get_sender()?;
+
+        if addr != dst || !self.is_answer(application, received.get_payload()) {
+            debug!("Ignoring UDP packet from {}", addr);
+            return None;
+        }
 
-        self.channels.tx = Some(tx_udp);
-        self.channels.rx_icmp = Some(rx_icmp);
+        Some(Result::new_filled(
+            ReceiveStatus::SuccessDestinationFound,
+            addr,
+            received.time_receive,
+            received.get_ttl(),
+            None,
+        ))
     }
 }
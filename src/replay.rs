@@ -0,0 +1,632 @@
+use crate::args::{Config, Method};
+use crate::packet_io::{PacketIo, ReceivedPacket};
+use crate::pcap::{read_pcap, CapturedPacket};
+use crate::protocols::application::Application;
+use crate::protocols::dccp::{self, DccpTraceroute};
+use crate::protocols::protocol::TracerouteProtocol;
+use crate::protocols::sctp::{parse_common_header, SctpTraceroute};
+use crate::protocols::tcp::TcpTraceroute;
+use crate::protocols::udp::UdpTraceroute;
+use crate::traceroute::{do_traceroute, Trace};
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::{TcpFlags, TcpPacket};
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A probe of the capture with the replies which answer it.
+struct CapturedProbe {
+    ttl: u8,
+    time: Instant,
+    data: Vec<u8>,
+    /// Replies in the order they arrived, with whether they are ICMP.
+    replies: Vec<(Instant, bool, Vec<u8>)>,
+    sent: bool,
+}
+
+/// `PacketIo` which plays back a capture instead of using the network.
+///
+/// Each sent probe consumes the next probe of the capture with the same TTL, its replies are
+/// then handed out just like they arrived back then. Replies are assigned to the probes they
+/// quote or answer, so captures of tracers sending many probes at once work too.
+pub struct ReplayIo {
+    probes: Vec<CapturedProbe>,
+    icmp: VecDeque<(Instant, Vec<u8>)>,
+    protocol: VecDeque<(Instant, Vec<u8>)>,
+    probe_protocol: IpNextHeaderProtocol,
+    ttl: u8,
+    clock: Instant,
+}
+
+impl ReplayIo {
+    /// Sorts captured packets into our probes towards `destination` and replies to `source`.
+    pub fn new(
+        packets: Vec<CapturedPacket>,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        probe_protocol: IpNextHeaderProtocol,
+    ) -> Self {
+        let start = Instant::now();
+        let first_time = packets.first().map(|packet| packet.time);
+        let to_instant =
+            |time: SystemTime| start + time.duration_since(first_time.unwrap()).unwrap_or_default();
+
+        let mut replay = ReplayIo {
+            probes: Vec::new(),
+            icmp: VecDeque::new(),
+            protocol: VecDeque::new(),
+            probe_protocol,
+            ttl: 64,
+            clock: start,
+        };
+
+        for packet in packets {
+            let ip_packet = match Ipv4Packet::new(&packet.data) {
+                Some(ip_packet) => ip_packet,
+                None => continue,
+            };
+            let time = to_instant(packet.time);
+
+            if is_probe(&ip_packet, source, destination, probe_protocol) {
+                replay.probes.push(CapturedProbe {
+                    ttl: ip_packet.get_ttl(),
+                    time,
+                    data: packet.data.clone(),
+                    replies: Vec::new(),
+                    sent: false,
+                });
+            } else if ip_packet.get_destination() == source {
+                // the probe sent last is answered if several look alike
+                let probe = replay.probes.iter_mut().rev().find(|probe| {
+                    probe.time <= time && answers(&ip_packet, &probe.data, destination)
+                });
+                if let Some(probe) = probe {
+                    let is_icmp =
+                        ip_packet.get_next_level_protocol() == IpNextHeaderProtocols::Icmp;
+                    probe.replies.push((time, is_icmp, packet.data.clone()));
+                }
+            }
+        }
+
+        replay
+    }
+
+    fn receive(&mut self, icmp: bool, timeout: Duration) -> Option<ReceivedPacket> {
+        let deadline = self.clock + timeout;
+        let queue = if icmp {
+            &mut self.icmp
+        } else {
+            &mut self.protocol
+        };
+
+        let is_due = match queue.front() {
+            Some((time, _)) => *time <= deadline,
+            None => false,
+        };
+
+        if is_due {
+            let (time, data) = queue.pop_front().unwrap();
+            self.clock = self.clock.max(time);
+            Some(ReceivedPacket::new(data, time))
+        } else {
+            self.clock = deadline;
+            None
+        }
+    }
+}
+
+impl PacketIo for ReplayIo {
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+        self.ttl = ttl;
+        Ok(())
+    }
+
+    fn send_to(&mut self, packet: &[u8], _dst: IpAddr) -> io::Result<Instant> {
+        if !is_probe_payload(self.probe_protocol, packet) {
+            return Ok(self.clock);
+        }
+        // replies to earlier probes which were not received in time are lost
+        self.icmp.clear();
+        self.protocol.clear();
+
+        let ttl = self.ttl;
+        if let Some(probe) = self
+            .probes
+            .iter_mut()
+            .find(|probe| !probe.sent && probe.ttl == ttl)
+        {
+            probe.sent = true;
+            self.clock = probe.time;
+            for (time, is_icmp, data) in &probe.replies {
+                let queue = if *is_icmp {
+                    &mut self.icmp
+                } else {
+                    &mut self.protocol
+                };
+                queue.push_back((*time, data.clone()));
+            }
+        }
+
+        Ok(self.clock)
+    }
+
+    fn receive_icmp(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        self.receive(true, timeout)
+    }
+
+    fn receive_protocol(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        self.receive(false, timeout)
+    }
+
+    fn now(&self) -> Instant {
+        self.clock
+    }
+}
+
+fn is_probe(
+    ip_packet: &Ipv4Packet,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    probe_protocol: IpNextHeaderProtocol,
+) -> bool {
+    if ip_packet.get_source() != source
+        || ip_packet.get_destination() != destination
+        || ip_packet.get_next_level_protocol() != probe_protocol
+    {
+        return false;
+    }
+
+    is_probe_payload(probe_protocol, ip_packet.payload())
+}
+
+/// Whether the transport layer `payload` is a probe rather than another packet we send.
+fn is_probe_payload(probe_protocol: IpNextHeaderProtocol, payload: &[u8]) -> bool {
+    // replies look the same when tracing ourselves, but only echo requests are probes, and
+    // resets sent once the destination answers are not probes either
+    match probe_protocol {
+        IpNextHeaderProtocols::Icmp => payload.first() == Some(&IcmpTypes::EchoRequest.0),
+        IpNextHeaderProtocols::Tcp => TcpPacket::new(payload)
+            .is_some_and(|tcp_packet| tcp_packet.get_flags() & TcpFlags::SYN != 0),
+        _ => true,
+    }
+}
+
+/// Whether `reply` may answer the captured `probe` towards `destination`.
+///
+/// ICMP errors have to quote the probe, echo replies have to carry its identifier and sequence
+/// number and other replies from the destination have to come from the probed port.
+fn answers(reply: &Ipv4Packet, probe: &[u8], destination: Ipv4Addr) -> bool {
+    let probe = match Ipv4Packet::new(probe) {
+        Some(probe) => probe,
+        None => return false,
+    };
+    let probe_transport = probe.payload();
+    let transport = reply.payload();
+    let is_icmp = reply.get_next_level_protocol() == IpNextHeaderProtocols::Icmp;
+
+    match transport.first() {
+        Some(&icmp_type)
+            if is_icmp
+                && (icmp_type == IcmpTypes::TimeExceeded.0
+                    || icmp_type == IcmpTypes::DestinationUnreachable.0) =>
+        {
+            let quoted = match transport.get(8..).and_then(Ipv4Packet::new) {
+                Some(quoted) => quoted,
+                None => return false,
+            };
+            // only the first 8 bytes of the transport header are sure to be quoted
+            let length = quoted.payload().len().min(probe_transport.len()).min(8);
+            quoted.get_next_level_protocol() == probe.get_next_level_protocol()
+                && quoted.get_identification() == probe.get_identification()
+                && length > 0
+                && quoted.payload()[..length] == probe_transport[..length]
+        }
+        _ if reply.get_source() != destination => false,
+        Some(&icmp_type) if is_icmp && icmp_type == IcmpTypes::EchoReply.0 => {
+            probe.get_next_level_protocol() == IpNextHeaderProtocols::Icmp
+                && transport.get(4..8).is_some()
+                && transport.get(4..8) == probe_transport.get(4..8)
+        }
+        _ if is_icmp => false,
+        _ => match (transport.get(..4), probe_transport.get(..4)) {
+            // TCP, UDP, SCTP and DCCP start with the ports, which the answer swaps
+            (Some(ports), Some(probe_ports))
+                if reply.get_next_level_protocol() == probe.get_next_level_protocol()
+                    && has_ports(probe.get_next_level_protocol()) =>
+            {
+                ports[..2] == probe_ports[2..] && ports[2..] == probe_ports[..2]
+            }
+            // any packet of the reply protocol of raw IP probes
+            _ => !has_ports(reply.get_next_level_protocol()),
+        },
+    }
+}
+
+fn has_ports(protocol: IpNextHeaderProtocol) -> bool {
+    [
+        IpNextHeaderProtocols::Tcp,
+        IpNextHeaderProtocols::Udp,
+        IpNextHeaderProtocols::Sctp,
+        IpNextHeaderProtocols::Dccp,
+    ]
+    .contains(&protocol)
+}
+
+/// Source, destination and protocol of the probes in the capture, which are those of the flow
+/// covering the most TTLs, lowest TTLs first. Other traffic like DNS lookups keeps its TTL.
+fn find_probe_flow(
+    packets: &[CapturedPacket],
+    destination: Option<Ipv4Addr>,
+) -> Option<(Ipv4Addr, Ipv4Addr, IpNextHeaderProtocol)> {
+    let mut flows: Vec<((Ipv4Addr, Ipv4Addr, IpNextHeaderProtocol), BTreeSet<u8>)> = Vec::new();
+
+    for ip_packet in packets
+        .iter()
+        .filter_map(|packet| Ipv4Packet::new(&packet.data))
+    {
+        let flow = (
+            ip_packet.get_source(),
+            ip_packet.get_destination(),
+            ip_packet.get_next_level_protocol(),
+        );
+        if destination.is_some_and(|dst| flow.1 != dst)
+            || !is_probe(&ip_packet, flow.0, flow.1, flow.2)
+        {
+            continue;
+        }
+
+        match flows.iter_mut().find(|(other, _)| *other == flow) {
+            Some((_, ttls)) => {
+                ttls.insert(ip_packet.get_ttl());
+            }
+            None => flows.push((flow, BTreeSet::from([ip_packet.get_ttl()]))),
+        }
+    }
+
+    // the first flow wins ties
+    flows
+        .iter()
+        .rev()
+        .max_by_key(|(_, ttls)| (ttls.len(), Reverse(ttls.iter().next().copied())))
+        .map(|(flow, _)| *flow)
+}
+
+pub fn replay_traceroute(config: Config, path: &str) -> Trace {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => panic!("Could not open capture '{}': {}", path, e),
+    };
+
+    match read_pcap(file) {
+        Ok(packets) => {
+            let (config, protocol) = prepare_replay(config, packets);
+            do_traceroute(config, protocol)
+        }
+        Err(e) => panic!("Could not read capture '{}': {}", path, e),
+    }
+}
+
+/// Config and protocol rerunning the traceroute with the parameters of the probes found in the
+/// capture.
+///
+/// The probes are told apart from other traffic by their TTLs, towards the configured host if
+/// one is given.
+pub fn prepare_replay(
+    mut config: Config,
+    packets: Vec<CapturedPacket>,
+) -> (Config, Box<dyn TracerouteProtocol>) {
+    let destination = config.host.parse::<Ipv4Addr>().ok();
+    let (source, destination, probe_protocol) =
+        find_probe_flow(&packets, destination).expect("Capture does not contain any probe.");
+    let probes = packets
+        .iter()
+        .filter_map(|packet| Ipv4Packet::new(&packet.data))
+        .filter(|ip_packet| is_probe(ip_packet, source, destination, probe_protocol))
+        .collect::<Vec<_>>();
+    let first_probe = &probes[0];
+
+    let mut tries_per_ttl: BTreeMap<u8, usize> = BTreeMap::new();
+    for probe in &probes {
+        *tries_per_ttl.entry(probe.get_ttl()).or_default() += 1;
+    }
+
+    config.host = destination.to_string();
+    config.first_hop_ttl = *tries_per_ttl.keys().next().unwrap();
+    config.hops = *tries_per_ttl.keys().next_back().unwrap();
+    config.tries = (*tries_per_ttl.values().max().unwrap()).min(u8::MAX as usize) as u8;
+
+    let mut protocol: Box<dyn TracerouteProtocol> = match probe_protocol {
+        IpNextHeaderProtocols::Icmp => {
+            config.method = Method::Icmp;
+            crate::create_protocol(&config)
+        }
+        IpNextHeaderProtocols::Udp => {
+            let udp_packet = UdpPacket::new(first_probe.payload()).unwrap();
+            config.method = Method::Udp;
+            config.port = udp_packet.get_destination();
+            Box::new(UdpTraceroute::with_request(
+                udp_packet.get_source(),
+                udp_packet.get_destination(),
+                udp_packet.payload().to_vec(),
+                Application::for_port(udp_packet.get_destination()),
+            ))
+        }
+        IpNextHeaderProtocols::Tcp => {
+            let tcp_packet = TcpPacket::new(first_probe.payload()).unwrap();
+            config.method = Method::Tcp;
+            config.port = tcp_packet.get_destination();
+            Box::new(TcpTraceroute::with_ports(
+                tcp_packet.get_source(),
+                tcp_packet.get_destination(),
+            ))
+        }
+        IpNextHeaderProtocols::Dccp => {
+            let header = dccp::parse_header(first_probe.payload())
+                .expect("Capture contains a truncated probe.");
+            config.method = Method::Dccp;
+            config.port = header.dst_port;
+            Box::new(DccpTraceroute::with_sequence(
+                header.src_port,
+                header.dst_port,
+                header.sequence,
+            ))
+        }
+        IpNextHeaderProtocols::Sctp => {
+            let probe = first_probe.payload();
+            let header = parse_common_header(probe).expect("Capture contains a truncated probe.");
+            let initiate_tag = match probe.get(16..20) {
+                Some(tag) => u32::from_be_bytes([tag[0], tag[1], tag[2], tag[3]]),
+                None => panic!("Capture contains a truncated probe."),
+            };
+            config.method = Method::Sctp;
+            config.port = header.dst_port;
+            Box::new(SctpTraceroute::with_tag(
+                header.src_port,
+                header.dst_port,
+                initiate_tag,
+            ))
+        }
+        _ => {
+            config.method = Method::Raw(probe_protocol.0);
+            config.payload = first_probe.payload().to_vec();
+            crate::create_protocol(&config)
+        }
+    };
+
+    let io = ReplayIo::new(packets, source, destination, probe_protocol);
+    protocol.set_io(Box::new(io));
+
+    (config, protocol)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_io::{build_ipv4_packet, get_next_level_protocol};
+    use crate::pcap::{CapturingIo, PcapWriter};
+    use crate::protocols::icmp::IcmpTraceroute;
+    use crate::protocols::raw::RawIpTraceroute;
+    use crate::simulation::{
+        test_config, SharedBuffer, SimulatedDestination, SimulatedNetwork, SimulatedRouter,
+    };
+    use crate::traceroute;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(10, 0, 9, 9);
+
+    fn record_trace(
+        config: Config,
+        mut protocol: Box<dyn TracerouteProtocol>,
+        routers: Vec<SimulatedRouter>,
+        destination: SimulatedDestination,
+    ) -> (Trace, Vec<CapturedPacket>) {
+        let network =
+            SimulatedNetwork::linear(protocol.get_protocol(), SOURCE, routers, destination);
+        let next_level_protocol = get_next_level_protocol(protocol.get_protocol());
+        let buffer = SharedBuffer::new();
+        let writer = PcapWriter::new(buffer.clone()).unwrap();
+        let io = CapturingIo::new(Box::new(network), writer, next_level_protocol, SOURCE);
+        protocol.set_io(Box::new(io));
+
+        let trace = traceroute::trace(&config, protocol, &mut io::sink());
+        let packets = read_pcap(&buffer.get_data()[..]).unwrap();
+
+        (trace, packets)
+    }
+
+    fn replay_packets(config: Config, packets: Vec<CapturedPacket>) -> Trace {
+        let (config, protocol) = prepare_replay(config, packets);
+        traceroute::trace(&config, protocol, &mut io::sink())
+    }
+
+    type HopSummary = (u8, Vec<Option<(IpAddr, Duration)>>);
+
+    fn summarize(trace: &Trace) -> Vec<HopSummary> {
+        trace
+            .hops
+            .iter()
+            .map(|hop| {
+                let replies = hop
+                    .probes
+                    .iter()
+                    .map(|probe| probe.as_ref().map(|reply| (reply.addr, reply.rtt)))
+                    .collect();
+                (hop.ttl, replies)
+            })
+            .collect()
+    }
+
+    fn routers() -> Vec<SimulatedRouter> {
+        vec![
+            SimulatedRouter::new(Ipv4Addr::new(10, 0, 1, 1), Duration::from_millis(1)),
+            SimulatedRouter::new(Ipv4Addr::new(10, 0, 1, 2), Duration::from_millis(2))
+                .with_loss(0.5),
+        ]
+    }
+
+    #[test]
+    fn replays_icmp_trace() {
+        let (original, packets) = record_trace(
+            test_config(DESTINATION, Method::Icmp),
+            Box::new(IcmpTraceroute::new()),
+            routers(),
+            SimulatedDestination::new(DESTINATION, Duration::from_millis(20)),
+        );
+
+        let mut config = test_config(DESTINATION, Method::Udp);
+        config.host = String::new();
+        let replayed = replay_packets(config, packets);
+
+        assert!(replayed.destination_reached);
+        assert_eq!(summarize(&replayed), summarize(&original));
+    }
+
+    #[test]
+    fn replays_dns_trace() {
+        let (original, packets) = record_trace(
+            test_config(DESTINATION, Method::Udp),
+            Box::new(UdpTraceroute::with_application(53, Some(Application::Dns))),
+            routers(),
+            SimulatedDestination::new(DESTINATION, Duration::from_millis(20))
+                .with_application(Application::Dns),
+        );
+
+        let replayed = replay_packets(test_config(DESTINATION, Method::Icmp), packets);
+
+        assert!(replayed.destination_reached);
+        assert_eq!(summarize(&replayed), summarize(&original));
+    }
+
+    #[test]
+    fn replays_tcp_trace() {
+        let (original, packets) = record_trace(
+            test_config(DESTINATION, Method::Tcp),
+            Box::new(TcpTraceroute::new(443)),
+            routers(),
+            SimulatedDestination::new(DESTINATION, Duration::from_millis(20)),
+        );
+
+        let replayed = replay_packets(test_config(DESTINATION, Method::Icmp), packets);
+
+        assert!(replayed.destination_reached);
+        assert_eq!(summarize(&replayed), summarize(&original));
+    }
+
+    #[test]
+    fn replays_dccp_trace() {
+        let (original, packets) = record_trace(
+            test_config(DESTINATION, Method::Dccp),
+            Box::new(DccpTraceroute::new(5001)),
+            routers(),
+            SimulatedDestination::new(DESTINATION, Duration::from_millis(20)).with_closed_port(),
+        );
+
+        let replayed = replay_packets(test_config(DESTINATION, Method::Icmp), packets);
+
+        assert!(replayed.destination_reached);
+        assert_eq!(summarize(&replayed), summarize(&original));
+    }
+
+    #[test]
+    fn replays_raw_ip_trace() {
+        let (original, packets) = record_trace(
+            test_config(DESTINATION, Method::Raw(47)),
+            Box::new(RawIpTraceroute::new(47, vec![0, 0, 8, 0], None)),
+            routers(),
+            SimulatedDestination::new(DESTINATION, Duration::from_millis(20)),
+        );
+
+        let replayed = replay_packets(test_config(DESTINATION, Method::Icmp), packets);
+
+        assert!(replayed.destination_reached);
+        assert_eq!(summarize(&replayed), summarize(&original));
+    }
+
+    #[test]
+    fn replays_sctp_trace() {
+        let (original, packets) = record_trace(
+            test_config(DESTINATION, Method::Sctp),
+            Box::new(SctpTraceroute::new(5060)),
+            routers(),
+            SimulatedDestination::new(DESTINATION, Duration::from_millis(20)),
+        );
+
+        let replayed = replay_packets(test_config(DESTINATION, Method::Icmp), packets);
+
+        assert!(replayed.destination_reached);
+        assert_eq!(summarize(&replayed), summarize(&original));
+    }
+
+    fn udp_packet(src: Ipv4Addr, dst: Ipv4Addr, ttl: u8, ports: (u16, u16)) -> Vec<u8> {
+        let mut datagram = vec![0; 12];
+        datagram[..2].copy_from_slice(&ports.0.to_be_bytes());
+        datagram[2..4].copy_from_slice(&ports.1.to_be_bytes());
+        datagram[4..6].copy_from_slice(&12u16.to_be_bytes());
+        build_ipv4_packet(src, dst, ttl, IpNextHeaderProtocols::Udp, &datagram)
+    }
+
+    fn icmp_error(from: Ipv4Addr, icmp_type: u8, code: u8, probe: &[u8]) -> Vec<u8> {
+        let mut message = vec![icmp_type, code, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(probe);
+        build_ipv4_packet(from, SOURCE, 64, IpNextHeaderProtocols::Icmp, &message)
+    }
+
+    #[test]
+    fn replays_parallel_probes_after_other_traffic() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let at = |micros: u64| start + Duration::from_micros(micros);
+        let resolver = Ipv4Addr::new(192, 0, 2, 53);
+        let mut packets = vec![
+            // the name lookup before tracing
+            (at(0), udp_packet(SOURCE, resolver, 64, (50000, 53))),
+            (at(800), udp_packet(resolver, SOURCE, 60, (53, 50000))),
+        ];
+
+        // all probes are sent at once, with a new destination port each, like traceroute does
+        let routers = [Ipv4Addr::new(10, 0, 1, 1), Ipv4Addr::new(10, 0, 1, 2)];
+        for probe in 0..9u16 {
+            let ttl = probe as u8 / 3 + 1;
+            let time = 10_000 + 100 * probe as u64;
+            let data = udp_packet(SOURCE, DESTINATION, ttl, (40000, 33434 + probe));
+            let reply = match ttl {
+                1 => Some((1000, icmp_error(routers[0], 11, 0, &data))),
+                // the second probe is lost
+                2 if probe != 4 => Some((2000, icmp_error(routers[1], 11, 0, &data))),
+                3 => Some((5000, icmp_error(DESTINATION, 3, 3, &data))),
+                _ => None,
+            };
+            if let Some((rtt, reply)) = reply {
+                packets.push((at(time + rtt), reply));
+            }
+            packets.push((at(time), data));
+        }
+        packets.sort_by_key(|(time, _)| *time);
+        let packets = packets
+            .into_iter()
+            .map(|(time, data)| CapturedPacket { time, data })
+            .collect();
+
+        let mut config = test_config(DESTINATION, Method::Icmp);
+        config.host = String::new();
+        let replayed = replay_packets(config, packets);
+
+        let hop =
+            |addr: Ipv4Addr, rtt_ms: u64| Some((IpAddr::V4(addr), Duration::from_millis(rtt_ms)));
+        assert!(replayed.destination_reached);
+        assert_eq!(
+            summarize(&replayed),
+            vec![
+                (1, vec![hop(routers[0], 1); 3]),
+                (2, vec![hop(routers[1], 2), None, hop(routers[1], 2)]),
+                (3, vec![hop(DESTINATION, 5); 3]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,847 @@
+use crate::args::{Config, Method, Output};
+use crate::ip_options::{
+    find_option, IpOptions, FIRST_ADDRESS_POINTER, OPTION_LOOSE_SOURCE_ROUTE, OPTION_RECORD_ROUTE,
+};
+use crate::packet_io::{
+    build_ipv4_packet, build_ipv4_packet_with_options, get_next_level_protocol, PacketIo,
+    ReceivedPacket,
+};
+use crate::protocols::application::Application;
+use crate::protocols::dccp::{
+    build_dccp_packet, parse_header, DccpHeader, TYPE_REQUEST, TYPE_RESET, TYPE_RESPONSE,
+};
+use crate::protocols::sctp::{
+    build_sctp_packet, parse_common_header, CHUNK_ABORT, CHUNK_INIT, CHUNK_INIT_ACK,
+};
+use crate::traceroute::{Hop, ProbeReply, Trace};
+use pnet::packet::icmp::{IcmpType, IcmpTypes};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::{ipv4_checksum, MutableTcpPacket, TcpFlags, TcpPacket};
+use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet::packet::Packet;
+use pnet::transport::TransportChannelType;
+use pnet::util::checksum;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_INITIAL_TTL: u8 = 255;
+
+pub enum LoadBalancing {
+    /// The interface is chosen by hashing the flow identifier, i.e. the first 4 bytes of the probe.
+    PerFlow,
+    /// The interfaces are used round-robin.
+    PerPacket,
+}
+
+/// Router on a simulated path, answering with ICMP Time Exceeded when a probe expires.
+pub struct SimulatedRouter {
+    /// Interfaces answering for this router, more than one models a load balancer.
+    interfaces: Vec<Ipv4Addr>,
+    load_balancing: LoadBalancing,
+    /// Round-trip time between the source and this router.
+    latency: Duration,
+    /// Probability that an expiring probe is not answered.
+    loss: f64,
+    /// Maximum number of ICMP messages generated per second.
+    rate_limit: Option<usize>,
+    initial_ttl: u8,
+    /// Number of hops of the path back to the source, defaults to the forward distance.
+    return_hops: Option<u8>,
+    answered: Vec<Instant>,
+    packet_counter: usize,
+}
+
+impl SimulatedRouter {
+    pub fn new(addr: Ipv4Addr, latency: Duration) -> Self {
+        SimulatedRouter {
+            interfaces: vec![addr],
+            load_balancing: LoadBalancing::PerFlow,
+            latency,
+            loss: 0.0,
+            rate_limit: None,
+            initial_ttl: DEFAULT_INITIAL_TTL,
+            return_hops: None,
+            answered: Vec::new(),
+            packet_counter: 0,
+        }
+    }
+
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    pub fn with_load_balancing(
+        mut self,
+        interfaces: Vec<Ipv4Addr>,
+        load_balancing: LoadBalancing,
+    ) -> Self {
+        self.interfaces = interfaces;
+        self.load_balancing = load_balancing;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, replies_per_second: usize) -> Self {
+        self.rate_limit = Some(replies_per_second);
+        self
+    }
+
+    pub fn with_initial_ttl(mut self, initial_ttl: u8) -> Self {
+        self.initial_ttl = initial_ttl;
+        self
+    }
+
+    pub fn with_return_hops(mut self, return_hops: u8) -> Self {
+        self.return_hops = Some(return_hops);
+        self
+    }
+
+    fn select_interface(&mut self, probe: &[u8]) -> Ipv4Addr {
+        let index = match self.load_balancing {
+            LoadBalancing::PerFlow => {
+                let mut hasher = DefaultHasher::new();
+                probe[..probe.len().min(4)].hash(&mut hasher);
+                hasher.finish() as usize
+            }
+            LoadBalancing::PerPacket => {
+                self.packet_counter += 1;
+                self.packet_counter - 1
+            }
+        };
+
+        self.interfaces[index % self.interfaces.len()]
+    }
+
+    fn is_rate_limited(&mut self, now: Instant) -> bool {
+        match self.rate_limit {
+            None => false,
+            Some(limit) => {
+                self.answered
+                    .retain(|answered| now - *answered < Duration::from_secs(1));
+                self.answered.len() >= limit
+            }
+        }
+    }
+}
+
+/// Host at the end of a simulated path.
+pub struct SimulatedDestination {
+    addr: Ipv4Addr,
+    latency: Duration,
+    /// Whether probes are dropped silently, e.g. by a firewall.
+    filtered: bool,
+    /// Whether the last router answers probes with Host Unreachable instead of forwarding them.
+    unreachable: bool,
+    /// Whether a firewall of the destination rejects probes as administratively prohibited.
+    prohibited: bool,
+    /// Whether TCP, SCTP and DCCP probes are accepted, closed ports answer with a reset.
+    port_open: bool,
+    /// Protocol of the answer to probes of other protocols, instead of Protocol Unreachable.
+    reply_protocol: Option<IpNextHeaderProtocol>,
+    /// Application answering UDP probes, instead of Port Unreachable.
+    application: Option<Application>,
+    initial_ttl: u8,
+}
+
+impl SimulatedDestination {
+    pub fn new(addr: Ipv4Addr, latency: Duration) -> Self {
+        SimulatedDestination {
+            addr,
+            latency,
+            filtered: false,
+            unreachable: false,
+            prohibited: false,
+            port_open: true,
+            reply_protocol: None,
+            application: None,
+            initial_ttl: 64,
+        }
+    }
+
+    pub fn filtered(mut self) -> Self {
+        self.filtered = true;
+        self
+    }
+
+    pub fn unreachable(mut self) -> Self {
+        self.unreachable = true;
+        self
+    }
+
+    pub fn prohibited(mut self) -> Self {
+        self.prohibited = true;
+        self
+    }
+
+    pub fn with_closed_port(mut self) -> Self {
+        self.port_open = false;
+        self
+    }
+
+    pub fn with_reply_protocol(mut self, protocol: IpNextHeaderProtocol) -> Self {
+        self.reply_protocol = Some(protocol);
+        self
+    }
+
+    pub fn with_application(mut self, application: Application) -> Self {
+        self.application = Some(application);
+        self
+    }
+}
+
+struct PendingPacket {
+    arrival: Instant,
+    icmp: bool,
+    data: Vec<u8>,
+}
+
+/// In-memory network implementing `PacketIo`, runs on a virtual clock so timeouts do not block.
+pub struct SimulatedNetwork {
+    protocol: IpNextHeaderProtocol,
+    source: Ipv4Addr,
+    routers: Vec<SimulatedRouter>,
+    /// Destinations with the indices of the routers on the path towards them.
+    destinations: Vec<(SimulatedDestination, Vec<usize>)>,
+    ttl: u8,
+    clock: Instant,
+    rng: StdRng,
+    pending: Vec<PendingPacket>,
+    /// IP options of every probe, see `RawSocketIo::open`.
+    ip_options: IpOptions,
+}
+
+impl SimulatedNetwork {
+    pub fn new(protocol: TransportChannelType, source: Ipv4Addr) -> Self {
+        SimulatedNetwork {
+            protocol: get_next_level_protocol(protocol),
+            source,
+            routers: Vec::new(),
+            destinations: Vec::new(),
+            ttl: 64,
+            clock: Instant::now(),
+            rng: StdRng::seed_from_u64(0),
+            pending: Vec::new(),
+            ip_options: IpOptions::default(),
+        }
+    }
+
+    /// Adds `ip_options` to every probe, routers forwarding them record their first interface.
+    ///
+    /// Gateways of a source route have to be on the path towards the destination.
+    pub fn with_ip_options(mut self, ip_options: &IpOptions) -> Self {
+        self.ip_options = ip_options.clone();
+        self
+    }
+
+    /// Creates a network with a single path through `routers` towards `destination`.
+    pub fn linear(
+        protocol: TransportChannelType,
+        source: Ipv4Addr,
+        routers: Vec<SimulatedRouter>,
+        destination: SimulatedDestination,
+    ) -> Self {
+        let mut network = SimulatedNetwork::new(protocol, source);
+        let path = routers
+            .into_iter()
+            .map(|router| network.add_router(router))
+            .collect::<Vec<usize>>();
+        network.add_destination(destination, &path);
+
+        network
+    }
+
+    pub fn add_router(&mut self, router: SimulatedRouter) -> usize {
+        self.routers.push(router);
+        self.routers.len() - 1
+    }
+
+    pub fn add_destination(&mut self, destination: SimulatedDestination, path: &[usize]) {
+        self.destinations.push((destination, path.to_vec()));
+    }
+
+    fn schedule(&mut self, latency: Duration, icmp: bool, data: Vec<u8>) {
+        self.pending.push(PendingPacket {
+            arrival: self.clock + latency,
+            icmp,
+            data,
+        });
+    }
+
+    fn expire_at_router(&mut self, router_index: usize, distance: u8, probe: &[u8]) {
+        self.answer_at_router(router_index, distance, probe, IcmpTypes::TimeExceeded, 0, 1);
+    }
+
+    /// Answers the probe from the router with an ICMP error quoting `quoted_ttl`.
+    fn answer_at_router(
+        &mut self,
+        router_index: usize,
+        distance: u8,
+        probe: &[u8],
+        icmp_type: IcmpType,
+        code: u8,
+        quoted_ttl: u8,
+    ) {
+        let now = self.clock;
+        let loss_roll = self.rng.gen::<f64>();
+        let router = &mut self.routers[router_index];
+
+        if loss_roll < router.loss || router.is_rate_limited(now) {
+            return;
+        }
+        router.answered.push(now);
+
+        let interface = router.select_interface(transport_of(probe));
+        let return_hops = router.return_hops.unwrap_or(distance);
+        let reply_ttl = router.initial_ttl - (return_hops - 1);
+        let latency = router.latency;
+
+        let icmp = create_icmp_message(icmp_type, code, [0; 4], &quote(probe, quoted_ttl));
+        let reply = build_ipv4_packet(
+            interface,
+            self.source,
+            reply_ttl,
+            IpNextHeaderProtocols::Icmp,
+            &icmp,
+        );
+        self.schedule(latency, true, reply);
+    }
+
+    fn arrive_at_destination(&mut self, destination_index: usize, distance: u8, probe: &[u8]) {
+        let destination = &self.destinations[destination_index].0;
+        if destination.filtered {
+            return;
+        }
+
+        let addr = destination.addr;
+        let latency = destination.latency;
+        let reply_ttl = destination.initial_ttl - (distance - 1);
+        let remaining_ttl = self.ttl - (distance - 1);
+        let prohibited = destination.prohibited;
+        let port_open = destination.port_open;
+        let reply_protocol = destination.reply_protocol;
+        let application = destination.application;
+        let transport = transport_of(probe);
+
+        let (icmp, reply) = if prohibited {
+            let quoted = quote(probe, remaining_ttl);
+            let reply = create_icmp_message(IcmpTypes::DestinationUnreachable, 13, [0; 4], &quoted);
+            (true, reply)
+        } else if self.protocol == IpNextHeaderProtocols::Icmp {
+            if transport[0] != IcmpTypes::EchoRequest.0 {
+                return;
+            }
+            let rest = [transport[4], transport[5], transport[6], transport[7]];
+            let reply = create_icmp_message(IcmpTypes::EchoReply, 0, rest, &transport[8..]);
+            if self.ip_options.record_route {
+                self.echo_with_options(destination_index, probe, reply_ttl, &reply);
+                return;
+            }
+            (true, reply)
+        } else if self.protocol == IpNextHeaderProtocols::Tcp {
+            match create_tcp_answer(transport, addr, self.source, port_open) {
+                Some(reply) => (false, reply),
+                None => return,
+            }
+        } else if self.protocol == IpNextHeaderProtocols::Dccp {
+            match create_dccp_answer(transport, addr, self.source, port_open) {
+                Some(reply) => (false, reply),
+                None => return,
+            }
+        } else if self.protocol == IpNextHeaderProtocols::Sctp {
+            match create_sctp_answer(transport, port_open) {
+                Some(reply) => (false, reply),
+                None => return,
+            }
+        } else if let (IpNextHeaderProtocols::Udp, Some(application)) = (self.protocol, application)
+        {
+            (
+                false,
+                create_udp_answer(transport, addr, self.source, application),
+            )
+        } else if let Some(reply_protocol) = reply_protocol {
+            let reply = build_ipv4_packet(addr, self.source, reply_ttl, reply_protocol, &[0; 4]);
+            self.schedule(latency, false, reply);
+            return;
+        } else {
+            let code = if self.protocol == IpNextHeaderProtocols::Udp {
+                3 // port unreachable
+            } else {
+                2 // protocol unreachable
+            };
+            let quoted = quote(probe, remaining_ttl);
+            let reply =
+                create_icmp_message(IcmpTypes::DestinationUnreachable, code, [0; 4], &quoted);
+            (true, reply)
+        };
+
+        let protocol = if icmp {
+            IpNextHeaderProtocols::Icmp
+        } else {
+            self.protocol
+        };
+        let reply = build_ipv4_packet(addr, self.source, reply_ttl, protocol, &reply);
+        self.schedule(latency, icmp, reply);
+    }
+
+    /// Sends the echo reply with the Record Route option of the echo request, like Linux does,
+    /// recording the destination and the path back.
+    fn echo_with_options(
+        &mut self,
+        destination_index: usize,
+        probe: &[u8],
+        reply_ttl: u8,
+        reply: &[u8],
+    ) {
+        let (destination, path) = &self.destinations[destination_index];
+        let (start, length) =
+            find_option(&Ipv4Packet::new(probe).unwrap(), OPTION_RECORD_ROUTE).unwrap();
+        let mut reply = build_ipv4_packet_with_options(
+            destination.addr,
+            self.source,
+            reply_ttl,
+            IpNextHeaderProtocols::Icmp,
+            &probe[start..start + length],
+            reply,
+        );
+
+        record_address(&mut reply, destination.addr);
+        for router_index in path.iter().rev() {
+            record_address(&mut reply, self.routers[*router_index].interfaces[0]);
+        }
+        self.schedule(destination.latency, true, reply);
+    }
+
+    fn receive(&mut self, icmp: bool, timeout: Duration) -> Option<ReceivedPacket> {
+        let deadline = self.clock + timeout;
+        let next = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, packet)| packet.icmp == icmp && packet.arrival <= deadline)
+            .min_by_key(|(_, packet)| packet.arrival)
+            .map(|(index, _)| index);
+
+        match next {
+            Some(index) => {
+                let packet = self.pending.remove(index);
+                self.clock = self.clock.max(packet.arrival);
+                Some(ReceivedPacket::new(packet.data, packet.arrival))
+            }
+            None => {
+                self.clock = deadline;
+                None
+            }
+        }
+    }
+}
+
+impl PacketIo for SimulatedNetwork {
+    fn set_ttl(&mut self, ttl: u8) -> io::Result<()> {
+        self.ttl = ttl;
+        Ok(())
+    }
+
+    fn send_to(&mut self, packet: &[u8], dst: IpAddr) -> io::Result<Instant> {
+        let dst = match dst {
+            IpAddr::V4(dst) => dst,
+            IpAddr::V6(_) => return Err(io::Error::from(io::ErrorKind::AddrNotAvailable)),
+        };
+        let mut probe = build_ipv4_packet_with_options(
+            self.source,
+            self.ip_options.first_hop(dst),
+            self.ttl,
+            self.protocol,
+            &self.ip_options.encode(dst),
+            packet,
+        );
+
+        let destination_index = self
+            .destinations
+            .iter()
+            .position(|(destination, _)| destination.addr == dst);
+
+        if let Some(destination_index) = destination_index {
+            let path = self.destinations[destination_index].1.clone();
+            for router_index in path.iter().take(self.ttl as usize - 1) {
+                let interface = self.routers[*router_index].interfaces[0];
+                follow_source_route(&mut probe, interface);
+                record_address(&mut probe, interface);
+            }
+
+            match path.get(self.ttl as usize - 1) {
+                Some(router_index) => self.expire_at_router(*router_index, self.ttl, &probe),
+                None if self.destinations[destination_index].0.unreachable => {
+                    let distance = path.len() as u8;
+                    let quoted_ttl = self.ttl - (distance - 1);
+                    // host unreachable
+                    let unreachable = IcmpTypes::DestinationUnreachable;
+                    self.answer_at_router(
+                        path[path.len() - 1],
+                        distance,
+                        &probe,
+                        unreachable,
+                        1,
+                        quoted_ttl,
+                    )
+                }
+                None => {
+                    let distance = path.len() as u8 + 1;
+                    self.arrive_at_destination(destination_index, distance, &probe)
+                }
+            }
+        }
+
+        Ok(self.clock)
+    }
+
+    fn receive_icmp(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        self.receive(true, timeout)
+    }
+
+    fn receive_protocol(&mut self, timeout: Duration) -> Option<ReceivedPacket> {
+        self.receive(false, timeout)
+    }
+
+    fn now(&self) -> Instant {
+        self.clock
+    }
+}
+
+/// Configuration as parsed from the command line defaults, tracing `host` with `method`.
+pub fn test_config(host: Ipv4Addr, method: Method) -> Config {
+    Config {
+        host: host.to_string(),
+        hops: 30,
+        method,
+        tries: 3,
+        wait: Duration::from_secs(1),
+        port: 33434,
+        first_hop_ttl: 1,
+        gap_limit: 5,
+        resolve_hostnames: false,
+        show_return_hops: false,
+        stats: false,
+        ip_options: IpOptions::default(),
+        pcap_out: None,
+        replay: None,
+        output: Output::Text,
+        targets: None,
+        workers: 1,
+        doubletree: None,
+        stop_set: None,
+        stateless: false,
+        serve_metrics: None,
+        interval_secs: 60,
+        serve_api: None,
+        max_traces: 4,
+        rate_limit: 10,
+        monitor: false,
+        hysteresis: 2,
+        webhook: None,
+        asn_table: None,
+        geo: None,
+        diff: None,
+        rtt_threshold_ms: 10.0,
+        merge: false,
+        max_pps: None,
+        payload: Vec::new(),
+        reply_protocol: None,
+        udp_template: None,
+        local_port: None,
+    }
+}
+
+/// Reply from `addr` after `rtt_ms`, without TTLs and other details.
+pub fn probe_reply(addr: Ipv4Addr, rtt_ms: u64) -> ProbeReply {
+    ProbeReply {
+        addr: IpAddr::V4(addr),
+        rtt: Duration::from_millis(rtt_ms),
+        reply_ttl: None,
+        quoted_ttl: None,
+        geo: None,
+        recorded_route: Vec::new(),
+        unreachable_code: None,
+    }
+}
+
+/// Trace of example.com which reached `destination`, with the probes of `hops` sent with TTLs
+/// from 1 on.
+pub fn trace_of(destination: Ipv4Addr, hops: Vec<Vec<Option<ProbeReply>>>) -> Trace {
+    Trace {
+        host: "example.com".to_string(),
+        destination: IpAddr::V4(destination),
+        hops: hops
+            .into_iter()
+            .enumerate()
+            .map(|(i, probes)| Hop {
+                ttl: i as u8 + 1,
+                probes,
+                stats: None,
+            })
+            .collect(),
+        destination_reached: true,
+        end_reason: None,
+    }
+}
+
+/// In-memory `Write` which can still be read after handing a clone to a writer.
+#[derive(Clone)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        SharedBuffer(Arc::new(Mutex::new(Vec::new())))
+    }
+
+    pub fn get_data(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Transport header and payload of the IPv4 packet `packet`.
+fn transport_of(packet: &[u8]) -> &[u8] {
+    let header_length = Ipv4Packet::new(packet).unwrap().get_header_length() as usize * 4;
+    &packet[header_length..]
+}
+
+/// Records `addr` in the next free slot of the Record Route option of the IPv4 packet `packet`,
+/// as a router forwarding it does, returns false if there is no option or it is full.
+///
+/// The header checksum is not updated, nothing in the simulation checks it.
+pub fn record_address(packet: &mut [u8], addr: Ipv4Addr) -> bool {
+    let (pointer, slot) = match next_slot(packet, OPTION_RECORD_ROUTE) {
+        Some(next_slot) => next_slot,
+        None => return false,
+    };
+
+    packet[slot..slot + 4].copy_from_slice(&addr.octets());
+    packet[pointer] += 4;
+    true
+}
+
+/// Sends the IPv4 packet `packet` on to the next address of its loose source route if it is
+/// destined to the gateway `addr`, which takes the place of that address like RFC 791 requires.
+/// Returns false if the packet is not source routed by `addr`.
+pub fn follow_source_route(packet: &mut [u8], addr: Ipv4Addr) -> bool {
+    if Ipv4Packet::new(packet).map(|header| header.get_destination()) != Some(addr) {
+        return false;
+    }
+    let (pointer, slot) = match next_slot(packet, OPTION_LOOSE_SOURCE_ROUTE) {
+        Some(next_slot) => next_slot,
+        None => return false,
+    };
+
+    let next: [u8; 4] = packet[slot..slot + 4].try_into().unwrap();
+    packet[16..20].copy_from_slice(&next);
+    packet[slot..slot + 4].copy_from_slice(&addr.octets());
+    packet[pointer] += 4;
+    true
+}
+
+/// Positions of the pointer and of the address it points to in the route option `option_type`
+/// of the IPv4 packet `packet`, if there is an address left.
+fn next_slot(packet: &[u8], option_type: u8) -> Option<(usize, usize)> {
+    let (start, length) = find_option(&Ipv4Packet::new(packet)?, option_type)?;
+
+    let pointer = packet[start + 2] as usize;
+    if pointer < FIRST_ADDRESS_POINTER as usize || pointer + 3 > length {
+        return None;
+    }
+    Some((start + 2, start + pointer - 1))
+}
+
+/// The probe as quoted in ICMP error messages, with the TTL it had when it was dropped.
+fn quote(probe: &[u8], ttl: u8) -> Vec<u8> {
+    let mut quoted = probe.to_vec();
+    quoted[8] = ttl;
+    quoted
+}
+
+fn create_icmp_message(icmp_type: IcmpType, code: u8, rest: [u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut message = vec![icmp_type.0, code, 0, 0];
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(payload);
+
+    let checksum = checksum(&message, 1);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    message
+}
+
+fn create_tcp_answer(
+    probe: &[u8],
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    port_open: bool,
+) -> Option<Vec<u8>> {
+    let probe = TcpPacket::new(probe)?;
+    let flags = probe.get_flags();
+
+    let mut buffer = vec![0; 20];
+    let mut packet = MutableTcpPacket::new(&mut buffer).unwrap();
+    packet.set_source(probe.get_destination());
+    packet.set_destination(probe.get_source());
+    packet.set_data_offset(5);
+    packet.set_window(1024);
+    if flags & TcpFlags::SYN != 0 {
+        packet.set_sequence(4242);
+        packet.set_acknowledgement(probe.get_sequence().wrapping_add(1));
+        if port_open {
+            packet.set_flags(TcpFlags::SYN | TcpFlags::ACK);
+        } else {
+            packet.set_flags(TcpFlags::RST | TcpFlags::ACK);
+        }
+    } else if flags == TcpFlags::ACK && port_open {
+        // segments of the connection established by an earlier SYN, e.g. keep-alives
+        let length = probe.payload().len() as u32;
+        packet.set_sequence(4243);
+        packet.set_acknowledgement(probe.get_sequence().wrapping_add(length));
+        packet.set_flags(TcpFlags::ACK);
+    } else {
+        return None;
+    }
+
+    let checksum = ipv4_checksum(&packet.to_immutable(), &src, &dst);
+    packet.set_checksum(checksum);
+
+    Some(buffer)
+}
+
+/// INIT-ACK or ABORT for an SCTP INIT, both carrying the INIT's initiate tag.
+fn create_sctp_answer(probe: &[u8], port_open: bool) -> Option<Vec<u8>> {
+    let header = parse_common_header(probe)?;
+    if *probe.get(12)? != CHUNK_INIT {
+        return None;
+    }
+    let initiate_tag = probe.get(16..20)?;
+    let initiate_tag = u32::from_be_bytes([
+        initiate_tag[0],
+        initiate_tag[1],
+        initiate_tag[2],
+        initiate_tag[3],
+    ]);
+
+    let chunk = if port_open {
+        let mut chunk = vec![CHUNK_INIT_ACK, 0, 0, 20];
+        chunk.extend_from_slice(&0x4242_4242u32.to_be_bytes());
+        chunk.extend_from_slice(&[0, 0, 0xff, 0xff, 0, 1, 0, 1, 0, 0, 0, 1]);
+        chunk
+    } else {
+        vec![CHUNK_ABORT, 0, 0, 4]
+    };
+
+    Some(build_sctp_packet(
+        header.dst_port,
+        header.src_port,
+        initiate_tag,
+        &chunk,
+    ))
+}
+
+/// DCCP-Response or DCCP-Reset acknowledging a DCCP-Request.
+fn create_dccp_answer(
+    probe: &[u8],
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    port_open: bool,
+) -> Option<Vec<u8>> {
+    let request = parse_header(probe)?;
+    if request.packet_type != TYPE_REQUEST {
+        return None;
+    }
+
+    let header = DccpHeader {
+        src_port: request.dst_port,
+        dst_port: request.src_port,
+        packet_type: if port_open { TYPE_RESPONSE } else { TYPE_RESET },
+        sequence: 4242,
+    };
+    let mut rest = vec![0; 2];
+    rest.extend_from_slice(&request.sequence.to_be_bytes()[2..]);
+    if port_open {
+        // service code
+        rest.extend_from_slice(probe.get(16..20)?);
+    } else {
+        // reset code "connection refused" and its data
+        rest.extend_from_slice(&[3, 0, 0, 0]);
+    }
+
+    Some(build_dccp_packet(&header, &rest, src, dst))
+}
+
+/// Datagram answering the UDP `probe` with the application's reply.
+fn create_udp_answer(
+    probe: &[u8],
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    application: Application,
+) -> Vec<u8> {
+    let request = UdpPacket::new(probe).unwrap();
+    let payload = create_application_answer(application, request.payload());
+
+    let mut buffer = vec![0; 8 + payload.len()];
+    let mut packet = MutableUdpPacket::new(&mut buffer).unwrap();
+    packet.set_source(request.get_destination());
+    packet.set_destination(request.get_source());
+    packet.set_length(8 + payload.len() as u16);
+    packet.set_payload(&payload);
+    let checksum = udp::ipv4_checksum(&packet.to_immutable(), &src, &dst);
+    packet.set_checksum(checksum);
+
+    buffer
+}
+
+/// Answer of a server speaking `application` to `request`.
+pub fn create_application_answer(application: Application, request: &[u8]) -> Vec<u8> {
+    match application {
+        Application::Dns => {
+            let mut response = request.to_vec();
+            // response, recursion available
+            response[2] |= 0x80;
+            response[3] |= 0x80;
+            response
+        }
+        Application::Ntp => {
+            let mut response = vec![0; 48];
+            // version 4, server mode, stratum 2
+            response[0] = 0x24;
+            response[1] = 2;
+            response[24..32].copy_from_slice(&request[40..48]);
+            response
+        }
+        Application::Quic => {
+            // Version Negotiation with swapped connection IDs, offering QUIC version 1
+            let destination_id = &request[6..6 + request[5] as usize];
+            let source_start = 7 + destination_id.len();
+            let source_id =
+                &request[source_start..source_start + request[source_start - 1] as usize];
+
+            let mut response = vec![0x80, 0, 0, 0, 0];
+            response.push(source_id.len() as u8);
+            response.extend_from_slice(source_id);
+            response.push(destination_id.len() as u8);
+            response.extend_from_slice(destination_id);
+            response.extend_from_slice(&1u32.to_be_bytes());
+            response
+        }
+    }
+}
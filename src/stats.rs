@@ -0,0 +1,100 @@
+use crate::traceroute::ProbeReply;
+use serde::{Deserialize, Serialize};
+
+/// RTT aggregates of all probes sent with the same TTL, in milliseconds.
+///
+/// The RTTs are unset if no probe was answered.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct HopStats {
+    pub sent: usize,
+    pub received: usize,
+    /// Fraction of probes without reply, from 0 to 1.
+    pub loss: f64,
+    pub min_ms: Option<f64>,
+    pub avg_ms: Option<f64>,
+    pub max_ms: Option<f64>,
+    /// Population standard deviation.
+    pub stddev_ms: Option<f64>,
+    pub median_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+}
+
+impl HopStats {
+    pub fn new(probes: &[Option<ProbeReply>]) -> Self {
+        let mut rtts = probes
+            .iter()
+            .flatten()
+            .map(|reply| reply.rtt.as_secs_f64() * 1000.0)
+            .collect::<Vec<f64>>();
+        rtts.sort_by(f64::total_cmp);
+
+        let avg = (!rtts.is_empty()).then(|| rtts.iter().sum::<f64>() / rtts.len() as f64);
+        let stddev = avg.map(|avg| {
+            let variance =
+                rtts.iter().map(|rtt| (rtt - avg).powi(2)).sum::<f64>() / rtts.len() as f64;
+            variance.sqrt()
+        });
+
+        HopStats {
+            sent: probes.len(),
+            received: rtts.len(),
+            loss: match probes.len() {
+                0 => 0.0,
+                sent => (sent - rtts.len()) as f64 / sent as f64,
+            },
+            min_ms: rtts.first().copied(),
+            avg_ms: avg,
+            max_ms: rtts.last().copied(),
+            stddev_ms: stddev,
+            median_ms: percentile(&rtts, 50.0),
+            p95_ms: percentile(&rtts, 95.0),
+        }
+    }
+}
+
+/// Percentile of the sorted `values`, interpolating linearly between the closest ranks.
+fn percentile(values: &[f64], percent: f64) -> Option<f64> {
+    let last = values.len().checked_sub(1)?;
+    let rank = percent / 100.0 * last as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+
+    Some(values[lower] + (values[upper] - values[lower]) * (rank - lower as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::probe_reply;
+    use std::net::Ipv4Addr;
+
+    fn reply(rtt_ms: u64) -> Option<ProbeReply> {
+        Some(probe_reply(Ipv4Addr::new(192, 0, 2, 1), rtt_ms))
+    }
+
+    #[test]
+    fn aggregates_answered_probes() {
+        let probes = (1..=9).map(reply).chain([None]).collect::<Vec<_>>();
+        let stats = HopStats::new(&probes);
+
+        assert_eq!(stats.sent, 10);
+        assert_eq!(stats.received, 9);
+        assert_eq!(stats.loss, 0.1);
+        assert_eq!(stats.min_ms, Some(1.0));
+        assert_eq!(stats.avg_ms, Some(5.0));
+        assert_eq!(stats.max_ms, Some(9.0));
+        assert!((stats.stddev_ms.unwrap() - (60.0f64 / 9.0).sqrt()).abs() < 1e-9);
+        assert_eq!(stats.median_ms, Some(5.0));
+        assert!((stats.p95_ms.unwrap() - 8.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn silent_hop_has_no_rtts() {
+        let stats = HopStats::new(&[None, None]);
+
+        assert_eq!(stats.loss, 1.0);
+        assert_eq!(stats.min_ms, None);
+        assert_eq!(stats.stddev_ms, None);
+        assert_eq!(stats.p95_ms, None);
+    }
+}
@@ -0,0 +1,297 @@
+//! Kernel timestamps of sent and received packets, which leave out the time spent in user space.
+//!
+//! Receive timestamps come with each packet (`SO_TIMESTAMPNS`), send timestamps are queued on
+//! the socket's error queue once the packet left (`SO_TIMESTAMPING`), keyed by the number of
+//! packets sent before on the socket. Callers fall back to `Instant::now()` wherever the kernel
+//! does not provide them.
+
+use libc::{c_int, c_void};
+use std::io;
+use std::mem;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long to wait for the send timestamp after the packet was handed to the kernel.
+const SEND_TIMESTAMP_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// Asks the kernel to stamp packets received on `fd`, returns whether it supports it.
+pub fn enable_receive_timestamps(fd: c_int) -> bool {
+    set_option(fd, libc::SO_TIMESTAMPNS, 1)
+}
+
+/// Asks the kernel to report when packets sent on `fd` left, returns whether it supports it.
+///
+/// The first packet sent afterwards has key 0, see `read_send_timestamp`.
+pub fn enable_send_timestamps(fd: c_int) -> bool {
+    let flags = libc::SOF_TIMESTAMPING_TX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_SOFTWARE
+        | libc::SOF_TIMESTAMPING_OPT_TSONLY
+        | libc::SOF_TIMESTAMPING_OPT_ID;
+
+    set_option(fd, libc::SO_TIMESTAMPING, flags as c_int)
+}
+
+fn set_option(fd: c_int, option: c_int, value: c_int) -> bool {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &value as *const c_int as *const c_void,
+            mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+
+    result == 0
+}
+
+/// Waits at most `timeout` for `events` on `fd`, returns the events which occurred.
+///
+/// Errors queued are always reported, as `POLLERR`.
+fn wait_for(fd: c_int, events: libc::c_short, timeout: Duration) -> libc::c_short {
+    let mut poll_fd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    // rounded up, so short timeouts still wait
+    let timeout = timeout.as_micros().div_ceil(1000).min(c_int::MAX as u128) as c_int;
+    let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout) };
+
+    if ready > 0 {
+        poll_fd.revents
+    } else {
+        0
+    }
+}
+
+/// Receives the next packet into `buffer`, waiting at most `timeout`.
+///
+/// Returns its length and the time the kernel received it, if stamped. Send timestamps queued
+/// on `fd` in the meantime are dropped, as they came too late for `read_send_timestamp`.
+pub fn receive(
+    fd: c_int,
+    buffer: &mut [u8],
+    timeout: Duration,
+) -> io::Result<Option<(usize, Option<Instant>)>> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let events = wait_for(
+            fd,
+            libc::POLLIN,
+            deadline.saturating_duration_since(Instant::now()),
+        );
+        if events & libc::POLLIN != 0 {
+            let (length, timestamp, _) = receive_message(fd, buffer, libc::MSG_DONTWAIT)?;
+            return Ok(Some((length, timestamp)));
+        }
+        if events & libc::POLLERR == 0 || Instant::now() >= deadline {
+            return Ok(None);
+        }
+        drain_error_queue(fd, |_, _| ());
+    }
+}
+
+/// Time the packet with `key` sent on `fd` left, from the error queue.
+///
+/// Stamps of earlier packets which arrived too late are dropped on the way.
+pub fn read_send_timestamp(fd: c_int, key: u32) -> Option<Instant> {
+    let deadline = Instant::now() + SEND_TIMESTAMP_TIMEOUT;
+
+    loop {
+        let events = wait_for(fd, 0, deadline.saturating_duration_since(Instant::now()));
+        if events & libc::POLLERR == 0 {
+            return None;
+        }
+
+        let mut timestamp = None;
+        drain_error_queue(fd, |stamp, stamp_key| {
+            if stamp_key == Some(key) {
+                timestamp = stamp.or(timestamp);
+            }
+        });
+        if timestamp.is_some() || Instant::now() >= deadline {
+            return timestamp;
+        }
+    }
+}
+
+/// Reads all messages queued on the error queue of `fd`, handing their timestamps and keys to
+/// `handle`.
+fn drain_error_queue<F: FnMut(Option<Instant>, Option<u32>)>(fd: c_int, mut handle: F) {
+    let mut buffer = [0; 64];
+    while let Ok((_, timestamp, key)) =
+        receive_message(fd, &mut buffer, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT)
+    {
+        handle(timestamp, key);
+    }
+}
+
+/// Receives a message with the kernel timestamp and the key of a send timestamp, if present.
+fn receive_message(
+    fd: c_int,
+    buffer: &mut [u8],
+    flags: c_int,
+) -> io::Result<(usize, Option<Instant>, Option<u32>)> {
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut c_void,
+        iov_len: buffer.len(),
+    };
+    // room for the timestamps and the extended error carrying the key of send timestamps
+    let mut control = [0u64; 32];
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut c_void;
+    message.msg_controllen = mem::size_of_val(&control) as _;
+
+    let length = unsafe { libc::recvmsg(fd, &mut message, flags) };
+    if length < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((
+        length as usize,
+        find_timestamp(&message),
+        find_send_key(&message),
+    ))
+}
+
+/// Key of a send timestamp, which comes in an extended error next to the timestamp.
+fn find_send_key(message: &libc::msghdr) -> Option<u32> {
+    let mut header = unsafe { libc::CMSG_FIRSTHDR(message) };
+
+    while !header.is_null() {
+        let cmsg = unsafe { &*header };
+        let extended_error = (cmsg.cmsg_level == libc::IPPROTO_IP
+            && cmsg.cmsg_type == libc::IP_RECVERR)
+            || (cmsg.cmsg_level == libc::IPPROTO_IPV6 && cmsg.cmsg_type == libc::IPV6_RECVERR);
+        if extended_error {
+            let error = unsafe {
+                (libc::CMSG_DATA(header) as *const libc::sock_extended_err).read_unaligned()
+            };
+            if error.ee_origin == libc::SO_EE_ORIGIN_TIMESTAMPING {
+                return Some(error.ee_data);
+            }
+        }
+        header = unsafe { libc::CMSG_NXTHDR(message, header) };
+    }
+
+    None
+}
+
+/// Software timestamp of the control messages, both kinds start with it.
+fn find_timestamp(message: &libc::msghdr) -> Option<Instant> {
+    let mut header = unsafe { libc::CMSG_FIRSTHDR(message) };
+
+    while !header.is_null() {
+        let cmsg = unsafe { &*header };
+        if cmsg.cmsg_level == libc::SOL_SOCKET
+            && (cmsg.cmsg_type == libc::SCM_TIMESTAMPNS || cmsg.cmsg_type == libc::SCM_TIMESTAMPING)
+        {
+            let timespec =
+                unsafe { (libc::CMSG_DATA(header) as *const libc::timespec).read_unaligned() };
+            // hardware only stamps leave the software timestamp zeroed
+            if timespec.tv_sec != 0 || timespec.tv_nsec != 0 {
+                let time =
+                    UNIX_EPOCH + Duration::new(timespec.tv_sec as u64, timespec.tv_nsec as u32);
+                return Some(to_instant(time));
+            }
+        }
+        header = unsafe { libc::CMSG_NXTHDR(message, header) };
+    }
+
+    None
+}
+
+/// Maps a wall clock time of the recent past onto the monotonic clock.
+fn to_instant(time: SystemTime) -> Instant {
+    let now = Instant::now();
+    let age = SystemTime::now().duration_since(time).unwrap_or_default();
+
+    now.checked_sub(age).unwrap_or(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn stamps_received_packets() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(enable_receive_timestamps(receiver.as_raw_fd()));
+
+        // the kernel only starts stamping arriving packets once it ran its deferred work
+        let mut buffer = [0; 16];
+        let timestamp = (0..100)
+            .find_map(|_| {
+                sender
+                    .send_to(b"probe", receiver.local_addr().unwrap())
+                    .unwrap();
+                let (length, timestamp) =
+                    receive(receiver.as_raw_fd(), &mut buffer, Duration::from_secs(1))
+                        .unwrap()
+                        .unwrap();
+                assert_eq!(&buffer[..length], b"probe");
+                timestamp
+            })
+            .unwrap();
+
+        assert!(timestamp <= Instant::now());
+    }
+
+    #[test]
+    fn stamps_sent_packets() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(enable_send_timestamps(sender.as_raw_fd()));
+        let before = Instant::now();
+
+        sender
+            .send_to(b"probe", receiver.local_addr().unwrap())
+            .unwrap();
+        let timestamp = read_send_timestamp(sender.as_raw_fd(), 0).unwrap();
+
+        assert!(timestamp >= before - Duration::from_millis(1));
+        assert!(timestamp <= Instant::now());
+    }
+
+    #[test]
+    fn skips_stale_send_timestamps() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(enable_send_timestamps(sender.as_raw_fd()));
+
+        // the stamp of the first packet is still queued when the second one is sent
+        sender
+            .send_to(b"first", receiver.local_addr().unwrap())
+            .unwrap();
+        wait_for(sender.as_raw_fd(), 0, Duration::from_secs(1));
+        let before = Instant::now();
+        sender
+            .send_to(b"second", receiver.local_addr().unwrap())
+            .unwrap();
+        let timestamp = read_send_timestamp(sender.as_raw_fd(), 1).unwrap();
+
+        assert!(timestamp >= before - Duration::from_millis(1));
+        assert_eq!(read_send_timestamp(sender.as_raw_fd(), 2), None);
+    }
+
+    #[test]
+    fn receive_waits_past_queued_send_timestamps() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(enable_send_timestamps(socket.as_raw_fd()));
+        socket.send_to(b"probe", "127.0.0.1:9").unwrap();
+        wait_for(socket.as_raw_fd(), 0, Duration::from_secs(1));
+        let before = Instant::now();
+
+        let mut buffer = [0; 16];
+        let received = receive(socket.as_raw_fd(), &mut buffer, Duration::from_millis(50)).unwrap();
+
+        assert!(received.is_none());
+        assert!(before.elapsed() >= Duration::from_millis(45));
+    }
+}
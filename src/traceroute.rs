@@ -1,116 +1,1086 @@
-use super::args::Config;
+use super::args::{Config, Output};
 use super::dns::{hostname_to_ip, ip_to_hostname};
+use super::geo::{faster_than_light, Location};
+use super::graph::print_graphs;
+use super::stats::HopStats;
+use super::ttl::{is_asymmetric, return_hops};
 use crate::protocols::protocol::{ReceiveStatus, TracerouteProtocol};
-use log::{error, info};
+use log::{error, info, warn};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
 use std::io;
 use std::io::Write;
-use std::{net::IpAddr, time::Duration};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
 
-pub fn do_traceroute(config: Config, mut protocol: Box<dyn TracerouteProtocol>) {
-    let dst = hostname_to_ip(&config.host);
+/// Answer to a single probe.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ProbeReply {
+    pub addr: IpAddr,
+    #[serde(
+        rename = "rtt_ms",
+        serialize_with = "serialize_millis",
+        deserialize_with = "deserialize_millis"
+    )]
+    pub rtt: Duration,
+    pub reply_ttl: Option<u8>,
+    pub quoted_ttl: Option<u8>,
+    /// Location of the address, only looked up with `--geo`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo: Option<Location>,
+    /// Addresses recorded by routers with `--record-route`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recorded_route: Vec<Ipv4Addr>,
+    /// ICMP code of the Destination Unreachable message which reported the destination
+    /// unreachable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unreachable_code: Option<u8>,
+}
 
-    println!(
-        "traceroute-rust to {} ({}), {} hops max",
-        dst, config.host, config.hops
-    );
+/// All probes sent with the same TTL, `None` marks a probe which timed out.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Hop {
+    pub ttl: u8,
+    pub probes: Vec<Option<ProbeReply>>,
+    /// RTT aggregates of the probes, only computed with `--stats`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<HopStats>,
+}
+
+/// Why probing stopped.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    Reached,
+    Unreachable,
+    GapLimit,
+    MaxHops,
+    SendFailed,
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EndReason::Reached => write!(f, "destination reached"),
+            EndReason::Unreachable => write!(f, "destination unreachable"),
+            EndReason::GapLimit => write!(f, "gap limit reached"),
+            EndReason::MaxHops => write!(f, "max. hops reached"),
+            EndReason::SendFailed => write!(f, "probes could not be sent"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Trace {
+    pub host: String,
+    pub destination: IpAddr,
+    pub hops: Vec<Hop>,
+    pub destination_reached: bool,
+    /// Unset while probing and for traces which stopped otherwise, e.g. on a Doubletree stop set.
+    #[serde(default)]
+    pub end_reason: Option<EndReason>,
+}
+
+/// Traces the configured host, printing the result in the configured output format.
+pub fn do_traceroute(config: Config, protocol: Box<dyn TracerouteProtocol>) -> Trace {
+    match config.output {
+        Output::Text => trace(&config, protocol, &mut io::stdout()),
+        Output::Json | Output::Dot | Output::Graphml => {
+            let trace = trace(&config, protocol, &mut io::sink());
+            print_result(&trace, &config);
+            trace
+        }
+    }
+}
 
-    protocol.open();
+/// Traces the configured host, writing the text output to `out` while probing.
+pub fn trace(config: &Config, protocol: Box<dyn TracerouteProtocol>, out: &mut dyn Write) -> Trace {
+    trace_with_progress(config, protocol, out, &mut |_| {})
+}
+
+/// Like `trace`, calling `progress` with the partial trace after every hop.
+pub fn trace_with_progress(
+    config: &Config,
+    mut protocol: Box<dyn TracerouteProtocol>,
+    out: &mut dyn Write,
+    progress: &mut dyn FnMut(&Trace),
+) -> Trace {
+    let mut trace = Trace::new(config);
+    print_header(out, &trace, config);
 
     let mut current_ttl: u8 = config.first_hop_ttl;
     let mut current_seq: u16 = 0;
-    let mut done = false;
-
-    while !done {
-        protocol.set_ttl(current_ttl);
-        print_ttl(current_ttl);
-
-        let mut prev_reply_addr: Option<IpAddr> = None;
-        for _ in 0..config.tries {
-            let time_send = protocol.send(dst, current_seq);
-            let result = protocol.poll(dst, config.wait_secs);
-
-            match result.status {
-                ReceiveStatus::SuccessContinue | ReceiveStatus::SuccessDestinationFound => {
-                    let metadata = result.metadata.unwrap();
-                    let reply_addr = metadata.addr;
-                    let rtt = metadata.time_receive - time_send;
-
-                    match prev_reply_addr {
-                        None => print_reply_with_ip(reply_addr, rtt, config.resolve_hostnames),
-                        Some(prev_reply_addr) => {
-                            if prev_reply_addr == reply_addr {
-                                print_reply(rtt)
-                            } else {
-                                print_reply_with_ip(reply_addr, rtt, config.resolve_hostnames)
-                            }
-                        }
-                    }
+    let mut silent_hops: u8 = 0;
+
+    loop {
+        let hop = probe_hop(
+            &mut trace,
+            protocol.as_mut(),
+            current_ttl,
+            &mut current_seq,
+            config,
+            out,
+        );
+        silent_hops = next_silent_hops(silent_hops, &hop);
+        trace.hops.push(hop);
+
+        if trace.end_reason.is_none() {
+            trace.end_reason = check_limits(config, current_ttl, silent_hops);
+        }
+        progress(&trace);
+        if trace.end_reason.is_some() {
+            break;
+        }
+        current_ttl += 1;
+    }
+
+    print_end_reason(out, &trace);
+
+    trace
+}
+
+/// Number of hops in a row without any reply, including `hop`.
+pub fn next_silent_hops(silent_hops: u8, hop: &Hop) -> u8 {
+    if hop.probes.iter().all(|probe| probe.is_none()) {
+        silent_hops.saturating_add(1)
+    } else {
+        0
+    }
+}
+
+/// Whether probing has to stop after `ttl`, with `silent_hops` hops in a row without reply.
+pub fn check_limits(config: &Config, ttl: u8, silent_hops: u8) -> Option<EndReason> {
+    if config.gap_limit > 0 && silent_hops >= config.gap_limit {
+        info!("{} hops in a row did not reply, stopping.", silent_hops);
+        Some(EndReason::GapLimit)
+    } else if ttl >= config.hops {
+        info!("Max. hops reached, stopping.");
+        Some(EndReason::MaxHops)
+    } else {
+        None
+    }
+}
+
+impl Trace {
+    /// Empty trace towards the configured host.
+    pub fn new(config: &Config) -> Self {
+        Trace {
+            host: config.host.clone(),
+            destination: hostname_to_ip(&config.host),
+            hops: Vec::new(),
+            destination_reached: false,
+            end_reason: None,
+        }
+    }
+}
+
+/// Sends all probes for `ttl`, marking the trace once the destination answers.
+pub fn probe_hop(
+    trace: &mut Trace,
+    protocol: &mut dyn TracerouteProtocol,
+    ttl: u8,
+    current_seq: &mut u16,
+    config: &Config,
+    out: &mut dyn Write,
+) -> Hop {
+    let dst = trace.destination;
+    protocol.set_ttl(ttl);
+    print_ttl(out, ttl);
+
+    let mut hop = Hop {
+        ttl,
+        probes: Vec::new(),
+        stats: None,
+    };
+    // with statistics, the hop is printed as a whole once all probes are done
+    let mut sink = io::sink();
+    let probe_out: &mut dyn Write = if config.stats { &mut sink } else { out };
+    for _ in 0..config.tries {
+        let time_send = match protocol.send(dst, *current_seq) {
+            Ok(time_send) => time_send,
+            Err(e) => {
+                error!("Could not send probe to {}: {}", dst, e);
+                trace.end_reason = Some(EndReason::SendFailed);
+                break;
+            }
+        };
+        let result = protocol.poll(dst, config.wait);
+
+        let reply = result.metadata.map(|metadata| ProbeReply {
+            addr: metadata.addr,
+            rtt: metadata.time_receive - time_send,
+            reply_ttl: metadata.reply_ttl,
+            quoted_ttl: metadata.quoted_ttl,
+            geo: config
+                .geo
+                .as_ref()
+                .and_then(|geo| geo.lookup(metadata.addr)),
+            recorded_route: metadata.recorded_route,
+            unreachable_code: metadata.unreachable_code,
+        });
+
+        match &reply {
+            Some(reply) => {
+                let prev_reply = hop.probes.iter().flatten().last();
+                print_probe_reply(probe_out, reply, prev_reply, hop.ttl, config);
+                if result.status == ReceiveStatus::SuccessContinue {
+                    check_quoted_ttl(reply);
+                }
+            }
+            // errors carry no usable reply, so the probe is lost just like on a timeout
+            None => print_timeout(probe_out),
+        }
+        hop.probes.push(reply);
+
+        match result.status {
+            ReceiveStatus::SuccessDestinationFound => {
+                trace.destination_reached = true;
+                trace.end_reason = Some(EndReason::Reached);
+            }
+            ReceiveStatus::Unreachable if trace.end_reason.is_none() => {
+                trace.end_reason = Some(EndReason::Unreachable);
+            }
+            _ => {}
+        }
+
+        *current_seq += 1;
+    }
+
+    if config.stats {
+        let stats = HopStats::new(&hop.probes);
+        print_hop_stats(out, &hop, &stats, config);
+        hop.stats = Some(stats);
+    }
+    check_speed_of_light(out, &trace.hops, &hop);
+
+    hop
+}
+
+/// Prints an already completed trace in the text format.
+pub fn print_trace(out: &mut dyn Write, trace: &Trace, config: &Config) {
+    print_header(out, trace, config);
 
-                    prev_reply_addr = Some(reply_addr);
+    for hop in &trace.hops {
+        print_ttl(out, hop.ttl);
 
-                    if result.status == ReceiveStatus::SuccessDestinationFound {
-                        done = true;
+        if config.stats {
+            print_hop_stats(out, hop, &HopStats::new(&hop.probes), config);
+        } else {
+            let mut prev_reply: Option<&ProbeReply> = None;
+            for probe in &hop.probes {
+                match probe {
+                    Some(reply) => {
+                        print_probe_reply(out, reply, prev_reply, hop.ttl, config);
+                        prev_reply = Some(reply);
                     }
+                    None => print_timeout(out),
                 }
-                ReceiveStatus::Timeout => print_timeout(),
-                ReceiveStatus::Error => {}
             }
+        }
+        check_speed_of_light(out, &trace.hops, hop);
+    }
+
+    print_end_reason(out, trace);
+}
 
-            current_seq += 1;
+fn print_end_reason(out: &mut dyn Write, trace: &Trace) {
+    match trace.end_reason {
+        Some(end_reason) => write_output(out, format_args!("\n\nEnd of trace: {}\n", end_reason)),
+        None => write_output(out, format_args!("\n")),
+    }
+}
+
+fn print_header(out: &mut dyn Write, trace: &Trace, config: &Config) {
+    write_output(
+        out,
+        format_args!(
+            "traceroute-rust to {} ({}), {} hops max\n",
+            trace.destination, trace.host, config.hops
+        ),
+    );
+}
+
+/// Prints a completed trace in the configured output format.
+pub fn print_result(trace: &Trace, config: &Config) {
+    match config.output {
+        Output::Text => {
+            let mut output = Vec::new();
+            print_trace(&mut output, trace, config);
+            if io::stdout().lock().write_all(&output).is_err() {
+                error!("Could not write output")
+            }
         }
+        Output::Json => print_json(trace),
+        Output::Dot | Output::Graphml => print_graphs(std::slice::from_ref(trace), config),
+    }
+}
 
-        current_ttl += 1;
+/// Prints the trace as a single line of JSON.
+pub fn print_json(trace: &Trace) {
+    println!("{}", serde_json::to_string(trace).unwrap());
+}
+
+pub fn serialize_millis<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+pub fn deserialize_millis<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    let millis = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(millis / 1000.0).map_err(serde::de::Error::custom)
+}
+
+fn print_probe_reply(
+    out: &mut dyn Write,
+    reply: &ProbeReply,
+    prev_reply: Option<&ProbeReply>,
+    ttl: u8,
+    config: &Config,
+) {
+    let same_as_prev = prev_reply
+        .is_some_and(|prev| prev.addr == reply.addr && prev.recorded_route == reply.recorded_route);
+    if same_as_prev {
+        print_reply(out, reply.rtt)
+    } else {
+        print_reply_with_ip(out, reply, config.resolve_hostnames)
+    }
+    if let Some(code) = reply.unreachable_code {
+        print_unreachable(out, code);
+    }
+
+    if let Some(reply_ttl) = reply.reply_ttl {
+        let return_hops = return_hops(reply_ttl);
+
+        if config.show_return_hops && is_asymmetric(ttl, return_hops) {
+            print_return_hops(out, return_hops);
+        }
+    }
+}
+
+/// Logs Time Exceeded messages quoting a probe TTL above 1, which a hop on the path modified.
+///
+/// Other ICMP errors are sent before the TTL runs out, so they quote higher TTLs anyway.
+fn check_quoted_ttl(reply: &ProbeReply) {
+    if let Some(quoted_ttl) = reply.quoted_ttl.filter(|quoted_ttl| *quoted_ttl > 1) {
+        info!(
+            "{} quoted probe TTL {}, TTL was modified on the path",
+            reply.addr, quoted_ttl
+        );
+    }
+}
 
-        if current_ttl >= config.hops {
-            info!("Max. hops reached, stopping.");
-            done = true
+/// Prints the addresses which answered the hop, followed by its statistics.
+fn print_hop_stats(out: &mut dyn Write, hop: &Hop, stats: &HopStats, config: &Config) {
+    let mut replies: Vec<&ProbeReply> = Vec::new();
+    for reply in hop.probes.iter().flatten() {
+        if !replies.iter().any(|other| other.addr == reply.addr) {
+            replies.push(reply);
         }
     }
+    for reply in replies {
+        write_output(
+            out,
+            format_args!("  {}", format_addr(reply, config.resolve_hostnames)),
+        );
+    }
+
+    match (
+        stats.min_ms,
+        stats.avg_ms,
+        stats.max_ms,
+        stats.stddev_ms,
+        stats.median_ms,
+        stats.p95_ms,
+    ) {
+        (Some(min), Some(avg), Some(max), Some(stddev), Some(median), Some(p95)) => write_output(
+            out,
+            format_args!(
+                "  min/avg/max/stddev {:.3}/{:.3}/{:.3}/{:.3}ms  median {:.3}ms  p95 {:.3}ms",
+                min, avg, max, stddev, median, p95
+            ),
+        ),
+        _ => print_timeout(out),
+    }
+    write_output(out, format_args!("  loss {:.0}%", stats.loss * 100.0));
+}
+
+/// Warns if `hop` is too far from the hop before it for their RTTs, which hints at a wrong
+/// location in the database.
+fn check_speed_of_light(out: &mut dyn Write, hops: &[Hop], hop: &Hop) {
+    let previous = match hops
+        .iter()
+        .find(|previous| previous.ttl.checked_add(1) == Some(hop.ttl))
+    {
+        Some(previous) => previous,
+        None => return,
+    };
+
+    if let Some((distance, rtt_increase)) = faster_than_light(previous, hop) {
+        warn!(
+            "Hop {} is {:.0} km away from hop {}, but its RTT is only {:.3}ms higher, faster than light in fiber",
+            hop.ttl, distance, previous.ttl, rtt_increase
+        );
+        write_output(out, format_args!(" !FTL"));
+    }
+}
+
+/// Prints the annotation of traceroute(8) for the code of a Destination Unreachable message.
+fn print_unreachable(out: &mut dyn Write, code: u8) {
+    match code {
+        0 | 6 | 11 => write_output(out, format_args!(" !N")),
+        1 | 7 | 12 => write_output(out, format_args!(" !H")),
+        2 => write_output(out, format_args!(" !P")),
+        3 => write_output(out, format_args!(" !")),
+        4 => write_output(out, format_args!(" !F")),
+        5 => write_output(out, format_args!(" !S")),
+        9 | 10 | 13 => write_output(out, format_args!(" !X")),
+        14 => write_output(out, format_args!(" !V")),
+        15 => write_output(out, format_args!(" !C")),
+        _ => write_output(out, format_args!(" !<{}>", code)),
+    }
+}
 
-    print!("\n")
+fn print_timeout(out: &mut dyn Write) {
+    write_output(out, format_args!("  *"));
 }
 
-fn print_timeout() {
-    print!("  *");
-    flush_stdout();
+fn print_ttl(out: &mut dyn Write, current_ttl: u8) {
+    write_output(out, format_args!("\n  {}", current_ttl));
 }
 
-fn print_ttl(current_ttl: u8) {
-    print!("\n  {}", current_ttl);
-    flush_stdout();
+fn print_reply_with_ip(out: &mut dyn Write, reply: &ProbeReply, resolve_hostnames: bool) {
+    write_output(
+        out,
+        format_args!(
+            "  {}  {:.3}ms",
+            format_addr(reply, resolve_hostnames),
+            duration_to_readable(reply.rtt)
+        ),
+    );
 }
 
-fn print_reply_with_ip(addr: IpAddr, rtt: Duration, resolve_hostnames: bool) {
-    if resolve_hostnames {
+/// The address of the reply, followed by its hostname if resolving them, its location and the
+/// recorded route.
+fn format_addr(reply: &ProbeReply, resolve_hostnames: bool) -> String {
+    let addr = reply.addr;
+    let mut text = if resolve_hostnames {
         let hostname = match ip_to_hostname(&addr) {
             Some(hostname) => hostname,
             None => addr.to_string(),
         };
-        print!(
-            "  {} ({})  {:.3}ms",
-            addr,
-            hostname,
-            duration_to_readable(rtt)
-        );
+        format!("{} ({})", addr, hostname)
     } else {
-        print!("  {}  {:.3}ms", addr, duration_to_readable(rtt));
+        addr.to_string()
+    };
+
+    if let Some(location) = &reply.geo {
+        text += &format!(" [{}]", location);
+    }
+    if !reply.recorded_route.is_empty() {
+        let route: Vec<String> = reply
+            .recorded_route
+            .iter()
+            .map(Ipv4Addr::to_string)
+            .collect();
+        text += &format!(" <RR: {}>", route.join(", "));
     }
+    text
+}
 
-    flush_stdout();
+fn print_reply(out: &mut dyn Write, rtt: Duration) {
+    write_output(out, format_args!("  {:.3}ms", duration_to_readable(rtt)));
 }
 
-fn print_reply(rtt: Duration) {
-    print!("  {:.3}ms", duration_to_readable(rtt));
-    flush_stdout();
+fn print_return_hops(out: &mut dyn Write, return_hops: u8) {
+    write_output(out, format_args!(" '-{}'", return_hops));
 }
 
 fn duration_to_readable(duration: Duration) -> f32 {
     duration.as_secs_f32() * 1000.0
 }
 
-fn flush_stdout() {
-    match io::stdout().flush() {
-        Err(_) => error!("Could not flush stdout"),
-        Ok(_) => (),
+fn write_output(out: &mut dyn Write, args: fmt::Arguments) {
+    if out.write_fmt(args).and_then(|_| out.flush()).is_err() {
+        error!("Could not write output")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Method;
+    use crate::geo::GeoDatabase;
+    use crate::ip_options::IpOptions;
+    use crate::protocols::application::Application;
+    use crate::protocols::dccp::DccpTraceroute;
+    use crate::protocols::icmp::IcmpTraceroute;
+    use crate::protocols::raw::RawIpTraceroute;
+    use crate::protocols::sctp::SctpTraceroute;
+    use crate::protocols::tcp::TcpTraceroute;
+    use crate::protocols::tcp_connection::{build_segment, TcpConnectionTraceroute};
+    use crate::protocols::udp::UdpTraceroute;
+    use crate::simulation::{
+        test_config, LoadBalancing, SimulatedDestination, SimulatedNetwork, SimulatedRouter,
+    };
+    use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+    use pnet::packet::tcp::TcpFlags;
+    use pnet::transport::TransportChannelType::Layer4;
+    use pnet::transport::TransportProtocol::Ipv4;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(10, 0, 9, 9);
+
+    fn config(method: Method) -> Config {
+        test_config(DESTINATION, method)
+    }
+
+    fn router(last_octet: u8) -> SimulatedRouter {
+        SimulatedRouter::new(
+            Ipv4Addr::new(10, 0, 1, last_octet),
+            Duration::from_millis(last_octet as u64),
+        )
+    }
+
+    fn destination() -> SimulatedDestination {
+        SimulatedDestination::new(DESTINATION, Duration::from_millis(20))
+    }
+
+    fn trace_with(
+        config: Config,
+        mut protocol: Box<dyn TracerouteProtocol>,
+        routers: Vec<SimulatedRouter>,
+        destination: SimulatedDestination,
+    ) -> Trace {
+        let network =
+            SimulatedNetwork::linear(protocol.get_protocol(), SOURCE, routers, destination);
+        protocol.set_io(Box::new(network));
+
+        trace(&config, protocol, &mut io::sink())
+    }
+
+    fn hop_addrs(hop: &Hop) -> Vec<Option<IpAddr>> {
+        hop.probes
+            .iter()
+            .map(|probe| probe.as_ref().map(|reply| reply.addr))
+            .collect()
+    }
+
+    fn assert_reaches_destination(trace: &Trace) {
+        assert!(trace.destination_reached);
+        assert_eq!(trace.hops.len(), 3);
+        assert_eq!(
+            hop_addrs(&trace.hops[0]),
+            vec![Some(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1))); 3]
+        );
+        assert_eq!(
+            trace.hops[2].probes[0].as_ref().unwrap().addr,
+            IpAddr::V4(DESTINATION)
+        );
+    }
+
+    #[test]
+    fn icmp_trace_reaches_destination() {
+        let trace = trace_with(
+            config(Method::Icmp),
+            Box::new(IcmpTraceroute::new()),
+            vec![router(1), router(2)],
+            destination(),
+        );
+
+        assert_reaches_destination(&trace);
+        let first_reply = trace.hops[1].probes[0].as_ref().unwrap();
+        assert_eq!(first_reply.rtt, Duration::from_millis(2));
+        assert_eq!(first_reply.reply_ttl, Some(254));
+        assert_eq!(first_reply.quoted_ttl, Some(1));
+    }
+
+    #[test]
+    fn udp_trace_reaches_destination() {
+        let trace = trace_with(
+            config(Method::Udp),
+            Box::new(UdpTraceroute::with_application(33434, None)),
+            vec![router(1), router(2)],
+            destination(),
+        );
+
+        assert_reaches_destination(&trace);
+    }
+
+    #[test]
+    fn udp_template_trace_reaches_application() {
+        for application in [Application::Dns, Application::Ntp, Application::Quic] {
+            let trace = trace_with(
+                config(Method::Udp),
+                Box::new(UdpTraceroute::with_application(
+                    application.default_port(),
+                    Some(application),
+                )),
+                vec![router(1), router(2)],
+                destination().with_application(application),
+            );
+
+            assert_reaches_destination(&trace);
+            // answered by the application rather than an ICMP error quoting the probe
+            assert_eq!(trace.hops[2].probes[0].as_ref().unwrap().quoted_ttl, None);
+        }
+    }
+
+    #[test]
+    fn tcp_trace_reaches_destination() {
+        let trace = trace_with(
+            config(Method::Tcp),
+            Box::new(TcpTraceroute::new(80)),
+            vec![router(1), router(2)],
+            destination().with_closed_port(),
+        );
+
+        assert_reaches_destination(&trace);
+    }
+
+    #[test]
+    fn tcp_connection_trace_reaches_destination() {
+        let mut protocol = TcpConnectionTraceroute::new(80, Some(50000));
+        let network = SimulatedNetwork::linear(
+            protocol.get_protocol(),
+            SOURCE,
+            vec![router(1), router(2)],
+            destination(),
+        );
+        protocol.set_io(Box::new(network));
+        // the kernel's handshake, whose SYN-ACK reveals the sequence numbers
+        let syn = build_segment(50000, 80, 1000, 0, TcpFlags::SYN, &[], DESTINATION);
+        protocol
+            .get_io()
+            .send_to(&syn, IpAddr::V4(DESTINATION))
+            .unwrap();
+
+        let trace = trace(
+            &config(Method::TcpConnection),
+            Box::new(protocol),
+            &mut io::sink(),
+        );
+
+        assert_reaches_destination(&trace);
+    }
+
+    #[test]
+    fn tcp_connection_ignores_acks_of_other_data() {
+        let mut protocol = TcpConnectionTraceroute::new(80, Some(50000));
+        let slow_router =
+            SimulatedRouter::new(Ipv4Addr::new(10, 0, 1, 1), Duration::from_millis(50));
+        let network = SimulatedNetwork::linear(
+            protocol.get_protocol(),
+            SOURCE,
+            vec![slow_router],
+            destination(),
+        );
+        protocol.set_io(Box::new(network));
+        let dst = IpAddr::V4(DESTINATION);
+        // the handshake, then data of the application which the destination acknowledges
+        let syn = build_segment(50000, 80, 1000, 0, TcpFlags::SYN, &[], DESTINATION);
+        let data = build_segment(
+            50000,
+            80,
+            1001,
+            4243,
+            TcpFlags::ACK,
+            &[1, 2, 3],
+            DESTINATION,
+        );
+        protocol.get_io().send_to(&syn, dst).unwrap();
+        protocol.get_io().send_to(&data, dst).unwrap();
+
+        protocol.set_ttl(1);
+        protocol.send(dst, 0).unwrap();
+        let result = protocol.poll(dst, Duration::from_secs(1));
+
+        assert!(result.status == ReceiveStatus::SuccessContinue);
+        assert_eq!(
+            result.metadata.unwrap().addr,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1))
+        );
+    }
+
+    #[test]
+    fn sctp_trace_reaches_destination() {
+        for destination in [destination(), destination().with_closed_port()] {
+            let trace = trace_with(
+                config(Method::Sctp),
+                Box::new(SctpTraceroute::new(80)),
+                vec![router(1), router(2)],
+                destination,
+            );
+
+            assert_reaches_destination(&trace);
+        }
+    }
+
+    #[test]
+    fn dccp_trace_reaches_destination() {
+        for destination in [destination(), destination().with_closed_port()] {
+            let trace = trace_with(
+                config(Method::Dccp),
+                Box::new(DccpTraceroute::new(5001)),
+                vec![router(1), router(2)],
+                destination,
+            );
+
+            assert_reaches_destination(&trace);
+        }
+    }
+
+    #[test]
+    fn raw_ip_trace_reaches_destination() {
+        let gre = IpNextHeaderProtocol(47);
+        let destinations = [
+            (destination(), None),
+            (destination().with_reply_protocol(gre), Some(47)),
+        ];
+
+        for (destination, reply_protocol) in destinations {
+            let trace = trace_with(
+                config(Method::Raw(47)),
+                Box::new(RawIpTraceroute::new(47, vec![0, 0, 8, 0], reply_protocol)),
+                vec![router(1), router(2)],
+                destination,
+            );
+
+            assert_reaches_destination(&trace);
+        }
+    }
+
+    #[test]
+    fn lossy_router_times_out() {
+        let trace = trace_with(
+            config(Method::Icmp),
+            Box::new(IcmpTraceroute::new()),
+            vec![router(1), router(2).with_loss(1.0)],
+            destination(),
+        );
+
+        assert!(trace.destination_reached);
+        assert_eq!(hop_addrs(&trace.hops[1]), vec![None, None, None]);
+    }
+
+    #[test]
+    fn stats_summarize_each_hop() {
+        let mut config = config(Method::Icmp);
+        config.tries = 10;
+        config.stats = true;
+        let mut protocol: Box<dyn TracerouteProtocol> = Box::new(IcmpTraceroute::new());
+        let network = SimulatedNetwork::linear(
+            protocol.get_protocol(),
+            SOURCE,
+            vec![router(1), router(2).with_loss(1.0)],
+            destination(),
+        );
+        protocol.set_io(Box::new(network));
+
+        let mut output = Vec::new();
+        let trace = trace(&config, protocol, &mut output);
+        let output = String::from_utf8(output).unwrap();
+
+        let stats = trace.hops[0].stats.as_ref().unwrap();
+        assert_eq!((stats.sent, stats.received), (10, 10));
+        assert_eq!(stats.stddev_ms, Some(0.0));
+        assert_eq!(stats.min_ms, stats.p95_ms);
+        assert_eq!(trace.hops[1].stats.as_ref().unwrap().loss, 1.0);
+        // one line per hop, without the single probes
+        assert!(output.contains("\n  1  10.0.1.1  min/avg/max/stddev "));
+        assert!(output.contains("\n  2  *  loss 100%"));
+        assert_eq!(output.matches("10.0.1.1").count(), 1);
+    }
+
+    #[test]
+    fn geo_annotates_hops_and_flags_faster_than_light() {
+        let mut config = config(Method::Icmp);
+        let database = GeoDatabase::parse(include_bytes!("testdata/geo.mmdb").to_vec());
+        config.geo = Some(Arc::new(database.unwrap()));
+        let mut protocol: Box<dyn TracerouteProtocol> = Box::new(IcmpTraceroute::new());
+        // Frankfurt, then New York only a millisecond further away
+        let routers = vec![
+            SimulatedRouter::new(Ipv4Addr::new(192, 0, 2, 1), Duration::from_millis(1)),
+            SimulatedRouter::new(Ipv4Addr::new(198, 51, 100, 1), Duration::from_millis(2)),
+        ];
+        let network =
+            SimulatedNetwork::linear(protocol.get_protocol(), SOURCE, routers, destination());
+        protocol.set_io(Box::new(network));
+
+        let mut output = Vec::new();
+        let trace = trace(&config, protocol, &mut output);
+        let output = String::from_utf8(output).unwrap();
+
+        let location = trace.hops[0].probes[0].as_ref().unwrap().geo.as_ref();
+        assert_eq!(location.unwrap().city.as_deref(), Some("Frankfurt"));
+        assert!(trace.hops[2].probes[0].as_ref().unwrap().geo.is_none());
+        assert!(output.contains("192.0.2.1 [DE, Frankfurt, 50.110, 8.680]  "));
+        assert!(output.contains("198.51.100.1 [US, New York, 40.710, -74.010]  "));
+        assert_eq!(output.matches("!FTL").count(), 1);
+    }
+
+    #[test]
+    fn record_route_shows_forward_and_return_path() {
+        let mut config = config(Method::Icmp);
+        config.ip_options = IpOptions {
+            record_route: true,
+            ..IpOptions::default()
+        };
+        let mut protocol: Box<dyn TracerouteProtocol> = Box::new(IcmpTraceroute::new());
+        let network = SimulatedNetwork::linear(
+            protocol.get_protocol(),
+            SOURCE,
+            vec![router(1), router(2), router(3)],
+            destination(),
+        )
+        .with_ip_options(&config.ip_options);
+        protocol.set_io(Box::new(network));
+
+        let mut output = Vec::new();
+        let trace = trace(&config, protocol, &mut output);
+        let output = String::from_utf8(output).unwrap();
+
+        let route = |ttl: usize| {
+            trace.hops[ttl - 1].probes[0]
+                .as_ref()
+                .unwrap()
+                .recorded_route
+                .clone()
+        };
+        // routers record the probes they forward, not those which expire at them
+        assert!(route(1).is_empty());
+        assert_eq!(
+            route(3),
+            vec![Ipv4Addr::new(10, 0, 1, 1), Ipv4Addr::new(10, 0, 1, 2)]
+        );
+        // the echo reply carries the option on to the way back
+        let mut expected: Vec<Ipv4Addr> = (1..=3)
+            .map(|octet| Ipv4Addr::new(10, 0, 1, octet))
+            .collect();
+        expected.push(DESTINATION);
+        expected.extend((1..=3).rev().map(|octet| Ipv4Addr::new(10, 0, 1, octet)));
+        assert_eq!(route(4), expected);
+        assert_eq!(output.matches("<RR: 10.0.1.1, 10.0.1.2>").count(), 1);
+    }
+
+    #[test]
+    fn loose_source_route_matches_errors_quoting_the_gateway() {
+        let mut config = config(Method::Udp);
+        config.ip_options = IpOptions {
+            loose_source_route: vec![Ipv4Addr::new(10, 0, 1, 2)],
+            ..IpOptions::default()
+        };
+        let mut protocol: Box<dyn TracerouteProtocol> =
+            Box::new(UdpTraceroute::with_application(33434, None));
+        let network = SimulatedNetwork::linear(
+            protocol.get_protocol(),
+            SOURCE,
+            vec![router(1), router(2), router(3)],
+            destination(),
+        )
+        .with_ip_options(&config.ip_options);
+        protocol.set_io(Box::new(network));
+
+        let trace = trace(&config, protocol, &mut Vec::new());
+
+        // the first two hops quote the gateway as destination of the probe
+        assert!(trace.destination_reached);
+        assert_eq!(
+            trace
+                .hops
+                .iter()
+                .map(|hop| hop_addrs(hop)[0])
+                .collect::<Vec<_>>(),
+            vec![
+                Some(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1))),
+                Some(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 2))),
+                Some(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 3))),
+                Some(IpAddr::V4(DESTINATION)),
+            ]
+        );
+    }
+
+    #[test]
+    fn per_packet_load_balancer_shows_all_interfaces() {
+        let interfaces = vec![Ipv4Addr::new(10, 0, 2, 1), Ipv4Addr::new(10, 0, 2, 2)];
+        let trace = trace_with(
+            config(Method::Icmp),
+            Box::new(IcmpTraceroute::new()),
+            vec![
+                router(1),
+                router(2).with_load_balancing(interfaces, LoadBalancing::PerPacket),
+            ],
+            destination(),
+        );
+
+        assert_eq!(
+            hop_addrs(&trace.hops[1]),
+            vec![
+                Some(IpAddr::V4(Ipv4Addr::new(10, 0, 2, 1))),
+                Some(IpAddr::V4(Ipv4Addr::new(10, 0, 2, 2))),
+                Some(IpAddr::V4(Ipv4Addr::new(10, 0, 2, 1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn rate_limited_router_drops_replies() {
+        let trace = trace_with(
+            config(Method::Icmp),
+            Box::new(IcmpTraceroute::new()),
+            vec![router(1).with_rate_limit(1)],
+            destination(),
+        );
+
+        let probes = &trace.hops[0].probes;
+        assert!(probes[0].is_some());
+        assert!(probes[1].is_none());
+    }
+
+    #[test]
+    fn asymmetric_return_path_is_visible_in_reply_ttl() {
+        let trace = trace_with(
+            config(Method::Icmp),
+            Box::new(IcmpTraceroute::new()),
+            vec![
+                router(1),
+                router(2).with_initial_ttl(64).with_return_hops(6),
+            ],
+            destination(),
+        );
+
+        let reply = trace.hops[1].probes[0].as_ref().unwrap();
+        let return_hops = return_hops(reply.reply_ttl.unwrap());
+        assert_eq!(return_hops, 6);
+        assert!(is_asymmetric(trace.hops[1].ttl, return_hops));
+    }
+
+    #[test]
+    fn filtering_destination_stops_at_max_hops() {
+        let mut config = config(Method::Udp);
+        config.hops = 5;
+        config.tries = 1;
+
+        let trace = trace_with(
+            config,
+            Box::new(UdpTraceroute::with_application(33434, None)),
+            vec![router(1)],
+            destination().filtered(),
+        );
+
+        assert!(!trace.destination_reached);
+        assert_eq!(trace.hops.len(), 5);
+        assert_eq!(trace.hops[4].ttl, 5);
+        assert_eq!(trace.end_reason, Some(EndReason::MaxHops));
+    }
+
+    #[test]
+    fn stops_at_gap_limit() {
+        let mut config = config(Method::Icmp);
+        config.gap_limit = 3;
+        config.tries = 1;
+
+        let trace = trace_with(
+            config,
+            Box::new(IcmpTraceroute::new()),
+            vec![router(1)],
+            destination().filtered(),
+        );
+
+        assert_eq!(trace.hops.len(), 4);
+        assert_eq!(trace.end_reason, Some(EndReason::GapLimit));
+    }
+
+    #[test]
+    fn probes_ttl_255_without_overflow() {
+        let mut config = config(Method::Icmp);
+        config.hops = 255;
+        config.first_hop_ttl = 254;
+        config.gap_limit = 0;
+        config.tries = 1;
+
+        let trace = trace_with(
+            config,
+            Box::new(IcmpTraceroute::new()),
+            vec![router(1)],
+            destination().filtered(),
+        );
+
+        let ttls = trace.hops.iter().map(|hop| hop.ttl).collect::<Vec<_>>();
+        assert_eq!(ttls, vec![254, 255]);
+        assert_eq!(trace.end_reason, Some(EndReason::MaxHops));
+    }
+
+    #[test]
+    fn destination_prohibiting_probes_is_not_reached() {
+        let protocols: Vec<(Method, Box<dyn TracerouteProtocol>)> = vec![
+            (
+                Method::Udp,
+                Box::new(UdpTraceroute::with_application(33434, None)),
+            ),
+            (
+                Method::Raw(47),
+                Box::new(RawIpTraceroute::new(47, vec![0, 0, 8, 0], None)),
+            ),
+        ];
+
+        for (method, mut protocol) in protocols {
+            let mut output = Vec::new();
+            let network = SimulatedNetwork::linear(
+                protocol.get_protocol(),
+                SOURCE,
+                vec![router(1)],
+                destination().prohibited(),
+            );
+            protocol.set_io(Box::new(network));
+
+            let trace = trace(&config(method), protocol, &mut output);
+
+            assert!(!trace.destination_reached);
+            assert_eq!(trace.end_reason, Some(EndReason::Unreachable));
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.contains("10.0.9.9  20.000ms !X"), "{}", output);
+        }
+    }
+
+    #[test]
+    fn marks_unreachable_codes_like_traceroute() {
+        let mut output = Vec::new();
+        for code in [0, 1, 2, 3, 13, 42] {
+            print_unreachable(&mut output, code);
+        }
+
+        assert_eq!(String::from_utf8(output).unwrap(), " !N !H !P ! !X !<42>");
+    }
+
+    #[test]
+    fn stops_when_router_reports_unreachable() {
+        let mut output = Vec::new();
+        let network = SimulatedNetwork::linear(
+            Layer4(Ipv4(IpNextHeaderProtocols::Tcp)),
+            SOURCE,
+            vec![router(1), router(2)],
+            destination().unreachable(),
+        );
+        let mut protocol: Box<dyn TracerouteProtocol> = Box::new(TcpTraceroute::new(80));
+        protocol.set_io(Box::new(network));
+
+        let trace = trace(&config(Method::Tcp), protocol, &mut output);
+
+        assert!(!trace.destination_reached);
+        assert_eq!(trace.end_reason, Some(EndReason::Unreachable));
+        assert_eq!(trace.hops.len(), 3);
+        assert_eq!(
+            hop_addrs(&trace.hops[2]),
+            vec![Some(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 2))); 3]
+        );
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("10.0.1.2  2.000ms !H"), "{}", output);
+        assert!(output.ends_with("\n\nEnd of trace: destination unreachable\n"));
     }
 }
@@ -0,0 +1,50 @@
+/// Initial TTL values commonly used by IP stacks when originating packets.
+const INITIAL_TTLS: [u8; 4] = [32, 64, 128, 255];
+
+/// Difference in hops between forward and return path that is still considered symmetric.
+const ASYMMETRY_TOLERANCE: u8 = 1;
+
+/// Infers the initial TTL a router used for its reply, i.e. the smallest common initial TTL
+/// which is equal to or larger than the received TTL.
+pub fn infer_initial_ttl(reply_ttl: u8) -> u8 {
+    *INITIAL_TTLS
+        .iter()
+        .find(|initial_ttl| **initial_ttl >= reply_ttl)
+        .unwrap()
+}
+
+/// Number of hops the reply travelled back to us, including the replying router itself.
+pub fn return_hops(reply_ttl: u8) -> u8 {
+    infer_initial_ttl(reply_ttl) - reply_ttl + 1
+}
+
+/// Whether the return path length differs noticeably from the forward distance of a hop.
+pub fn is_asymmetric(forward_ttl: u8, return_hops: u8) -> bool {
+    forward_ttl.abs_diff(return_hops) > ASYMMETRY_TOLERANCE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infers_common_initial_ttls() {
+        assert_eq!(infer_initial_ttl(30), 32);
+        assert_eq!(infer_initial_ttl(64), 64);
+        assert_eq!(infer_initial_ttl(120), 128);
+        assert_eq!(infer_initial_ttl(250), 255);
+    }
+
+    #[test]
+    fn counts_return_hops_including_replying_router() {
+        assert_eq!(return_hops(255), 1);
+        assert_eq!(return_hops(60), 5);
+    }
+
+    #[test]
+    fn tolerates_small_asymmetries() {
+        assert!(!is_asymmetric(5, 6));
+        assert!(is_asymmetric(5, 7));
+        assert!(is_asymmetric(9, 3));
+    }
+}
@@ -0,0 +1,389 @@
+use crate::args::{Config, Output};
+use crate::dns::try_hostname_to_ip;
+use crate::ip_options::final_destination;
+use crate::packet_io::{get_next_level_protocol, ReceivedPacket};
+use crate::protocols::protocol::TracerouteProtocol;
+use crate::traceroute::serialize_millis;
+use log::{error, warn};
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+/// Smallest prefix length accepted as target, larger prefixes need too much memory.
+const MIN_PREFIX_LENGTH: u8 = 16;
+
+/// Reply to a stateless probe, reconstructed from the reply alone.
+#[derive(Serialize)]
+pub struct StatelessReply {
+    pub target: Ipv4Addr,
+    pub ttl: u8,
+    pub addr: IpAddr,
+    #[serde(rename = "rtt_ms", serialize_with = "serialize_millis")]
+    pub rtt: Duration,
+    pub reply_ttl: Option<u8>,
+    pub destination_reached: bool,
+}
+
+/// Resolves hosts and expands prefixes like 192.0.2.0/24 to all of their addresses.
+pub fn expand_targets(targets: &[String]) -> Vec<Ipv4Addr> {
+    let mut addrs = Vec::new();
+
+    for target in targets {
+        match target.split_once('/') {
+            Some((network, length)) => match (network.parse::<Ipv4Addr>(), length.parse::<u8>()) {
+                (Ok(network), Ok(length)) if (MIN_PREFIX_LENGTH..=32).contains(&length) => {
+                    let mask = u32::MAX.checked_shl(32 - length as u32).unwrap_or(0);
+                    let first = u32::from(network) & mask;
+                    addrs.extend((first..=first | !mask).map(Ipv4Addr::from));
+                }
+                _ => warn!(
+                    "Skipping '{}', only IPv4 prefixes up to /{} are supported",
+                    target, MIN_PREFIX_LENGTH
+                ),
+            },
+            None => match try_hostname_to_ip(target) {
+                Some(IpAddr::V4(addr)) => addrs.push(addr),
+                _ => warn!("Skipping '{}', it is not a resolvable IPv4 host", target),
+            },
+        }
+    }
+
+    addrs
+}
+
+/// Probes every (target, TTL) pair once in random order, Yarrp style.
+///
+/// The probes carry their TTL and send timestamp, so no state is kept per target and each
+/// reply is printed as soon as it arrives. Probes are sent from `source`.
+pub fn trace_stateless(
+    config: &Config,
+    protocol: &mut dyn TracerouteProtocol,
+    source: Ipv4Addr,
+    targets: &[Ipv4Addr],
+) -> Vec<StatelessReply> {
+    let mut probes = targets
+        .iter()
+        .flat_map(|target| (config.first_hop_ttl..=config.hops).map(move |ttl| (*target, ttl)))
+        .collect::<Vec<_>>();
+    probes.shuffle(&mut rand::thread_rng());
+
+    let start = protocol.get_io().now();
+    let mut replies = Vec::new();
+
+    for (target, ttl) in probes {
+        // the timestamp must not include the time waiting for the send rate
+        protocol.get_io().wait_for_send();
+        let timestamp = elapsed_millis(start, protocol.get_io().now());
+        let probe = protocol.create_stateless_probe(source, target, ttl, timestamp);
+
+        protocol.set_ttl(ttl);
+        if let Err(e) = protocol.get_io().send_to(&probe, IpAddr::V4(target)) {
+            error!("Could not send probe to {}: {}", target, e);
+        }
+
+        receive_replies(config, protocol, start, &mut replies);
+    }
+
+    let time_last_probe = protocol.get_io().now();
+    let wait = config.wait;
+    while protocol.get_io().now() - time_last_probe < wait {
+        receive_replies(config, protocol, start, &mut replies);
+    }
+
+    replies
+}
+
+/// Handles all replies which are already waiting.
+fn receive_replies(
+    config: &Config,
+    protocol: &mut dyn TracerouteProtocol,
+    start: Instant,
+    replies: &mut Vec<StatelessReply>,
+) {
+    let has_protocol_replies =
+        get_next_level_protocol(protocol.get_protocol()) != IpNextHeaderProtocols::Icmp;
+
+    loop {
+        let mut received = Vec::new();
+        received.extend(protocol.get_io().receive_icmp(Duration::from_millis(1)));
+        // on ICMP probing the protocol socket only sees copies of the ICMP packets
+        if has_protocol_replies {
+            received.extend(protocol.get_io().receive_protocol(Duration::from_millis(1)));
+        }
+
+        if received.is_empty() {
+            return;
+        }
+
+        for packet in received {
+            if let Some(reply) = decode_reply(protocol, start, &packet) {
+                print_reply(&reply, config);
+                replies.push(reply);
+            }
+        }
+    }
+}
+
+fn decode_reply(
+    protocol: &dyn TracerouteProtocol,
+    start: Instant,
+    received: &ReceivedPacket,
+) -> Option<StatelessReply> {
+    let addr = match received.get_sender()? {
+        IpAddr::V4(addr) => addr,
+        IpAddr::V6(_) => return None,
+    };
+    let probe_protocol = get_next_level_protocol(protocol.get_protocol());
+
+    let icmp_error = received.get_icmp_packet().filter(|packet| {
+        packet.get_icmp_type() == IcmpTypes::TimeExceeded
+            || packet.get_icmp_type() == IcmpTypes::DestinationUnreachable
+    });
+
+    let (target, (ttl, timestamp)) = match icmp_error {
+        Some(_) => {
+            let quoted = received.get_payload().get(8..)?;
+            let quoted_packet = Ipv4Packet::new(quoted)?;
+            let header_length = quoted_packet.get_header_length() as usize * 4;
+            if quoted_packet.get_next_level_protocol() != probe_protocol {
+                return None;
+            }
+
+            let target = final_destination(&quoted_packet);
+            let probe = quoted.get(header_length..)?;
+            (target, protocol.decode_stateless_probe(target, probe)?)
+        }
+        None if received.get_ip_packet()?.get_next_level_protocol() == probe_protocol => {
+            (addr, protocol.decode_stateless_answer(received)?)
+        }
+        None => return None,
+    };
+
+    let rtt = elapsed_millis(start, received.time_receive).wrapping_sub(timestamp);
+
+    Some(StatelessReply {
+        target,
+        ttl,
+        addr: IpAddr::V4(addr),
+        rtt: Duration::from_millis(rtt.into()),
+        reply_ttl: received.get_ttl(),
+        destination_reached: addr == target,
+    })
+}
+
+/// Milliseconds since `start`, wrapping every 65 seconds which is plenty for round trips.
+fn elapsed_millis(start: Instant, time: Instant) -> u16 {
+    time.saturating_duration_since(start).as_millis() as u16
+}
+
+fn print_reply(reply: &StatelessReply, config: &Config) {
+    match config.output {
+        Output::Text => println!(
+            "{}  {}  {}  {}ms",
+            reply.target,
+            reply.ttl,
+            reply.addr,
+            reply.rtt.as_millis()
+        ),
+        // replies are not grouped into traces, so graphs are printed as JSON as well
+        Output::Json | Output::Dot | Output::Graphml => {
+            println!("{}", serde_json::to_string(reply).unwrap())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Method;
+    use crate::packet_io::build_ipv4_packet;
+    use crate::protocols::icmp::IcmpTraceroute;
+    use crate::protocols::tcp::TcpTraceroute;
+    use crate::protocols::udp::UdpTraceroute;
+    use crate::simulation::{test_config, SimulatedDestination, SimulatedNetwork, SimulatedRouter};
+    use pnet::packet::udp::{ipv4_checksum, UdpPacket};
+    use pnet::util::checksum;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn protocols() -> Vec<Box<dyn TracerouteProtocol>> {
+        vec![
+            Box::new(IcmpTraceroute::new()),
+            Box::new(UdpTraceroute::with_application(33434, None)),
+            Box::new(TcpTraceroute::new(80)),
+        ]
+    }
+
+    #[test]
+    fn probes_carry_ttl_and_timestamp() {
+        let dst = Ipv4Addr::new(192, 0, 2, 7);
+
+        for protocol in protocols() {
+            for &(ttl, timestamp) in &[(1, 0), (7, 0x1234), (255, 0xfffe)] {
+                let probe = protocol.create_stateless_probe(SOURCE, dst, ttl, timestamp);
+
+                assert_eq!(
+                    protocol.decode_stateless_probe(dst, &probe),
+                    Some((ttl, timestamp))
+                );
+                // only the first 8 bytes are guaranteed to be quoted
+                assert_eq!(
+                    protocol.decode_stateless_probe(dst, &probe[..8]),
+                    Some((ttl, timestamp))
+                );
+                assert_eq!(
+                    protocol.decode_stateless_probe(Ipv4Addr::new(192, 0, 2, 8), &probe),
+                    None
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn probes_have_valid_checksums() {
+        let dst = Ipv4Addr::new(192, 0, 2, 7);
+
+        let probe = IcmpTraceroute::new().create_stateless_probe(SOURCE, dst, 3, 0x1234);
+        let mut zeroed = probe.clone();
+        zeroed[2..4].copy_from_slice(&[0, 0]);
+        assert_eq!(checksum(&zeroed, 1), 0x1234);
+
+        let probe = UdpTraceroute::with_application(33434, None)
+            .create_stateless_probe(SOURCE, dst, 3, 0x1234);
+        let mut zeroed = probe.clone();
+        zeroed[6..8].copy_from_slice(&[0, 0]);
+        let packet = UdpPacket::new(&zeroed).unwrap();
+        assert_eq!(ipv4_checksum(&packet, &SOURCE, &dst), 0x1234);
+    }
+
+    #[test]
+    fn decodes_timestamp_from_echo_reply() {
+        let dst = Ipv4Addr::new(192, 0, 2, 7);
+        let protocol = IcmpTraceroute::new();
+
+        for &timestamp in &[0, 1, 0x0800, 0xf7ff, 0xf800, 0xfffe] {
+            let mut reply = protocol.create_stateless_probe(SOURCE, dst, 3, timestamp);
+            reply[0] = IcmpTypes::EchoReply.0;
+            reply[2..4].copy_from_slice(&[0, 0]);
+            let reply_checksum = checksum(&reply, 1);
+            reply[2..4].copy_from_slice(&reply_checksum.to_be_bytes());
+
+            let packet = build_ipv4_packet(dst, SOURCE, 60, IpNextHeaderProtocols::Icmp, &reply);
+            let received = ReceivedPacket::new(packet, Instant::now());
+            assert_eq!(
+                protocol.decode_stateless_answer(&received),
+                Some((3, timestamp))
+            );
+        }
+    }
+
+    #[test]
+    fn expands_prefixes() {
+        let targets = vec![
+            "192.0.2.5/30".to_string(),
+            "198.51.100.1".to_string(),
+            "10.0.0.0/8".to_string(),
+        ];
+
+        assert_eq!(
+            expand_targets(&targets),
+            vec![
+                Ipv4Addr::new(192, 0, 2, 4),
+                Ipv4Addr::new(192, 0, 2, 5),
+                Ipv4Addr::new(192, 0, 2, 6),
+                Ipv4Addr::new(192, 0, 2, 7),
+                Ipv4Addr::new(198, 51, 100, 1),
+            ]
+        );
+    }
+
+    fn network(protocol: &dyn TracerouteProtocol) -> SimulatedNetwork {
+        let mut network = SimulatedNetwork::new(protocol.get_protocol(), SOURCE);
+        let first = network.add_router(SimulatedRouter::new(
+            Ipv4Addr::new(10, 0, 1, 1),
+            Duration::from_millis(1),
+        ));
+
+        for i in 1..=2 {
+            let second = network.add_router(SimulatedRouter::new(
+                Ipv4Addr::new(10, 0, 2, i),
+                Duration::from_millis(2),
+            ));
+            let destination =
+                SimulatedDestination::new(Ipv4Addr::new(10, 0, 9, i), Duration::from_millis(5));
+            network.add_destination(destination, &[first, second]);
+        }
+
+        network
+    }
+
+    #[test]
+    fn recovers_hops_from_replies() {
+        let mut config = test_config(Ipv4Addr::UNSPECIFIED, Method::Icmp);
+        // the destinations are exactly at the last TTL probed
+        config.hops = 3;
+        let targets = [Ipv4Addr::new(10, 0, 9, 1), Ipv4Addr::new(10, 0, 9, 2)];
+
+        for mut protocol in protocols() {
+            let network = network(protocol.as_ref());
+            protocol.set_io(Box::new(network));
+
+            let mut replies = trace_stateless(&config, protocol.as_mut(), SOURCE, &targets)
+                .into_iter()
+                .map(|reply| {
+                    (
+                        reply.target,
+                        reply.ttl,
+                        reply.addr,
+                        reply.rtt,
+                        reply.destination_reached,
+                    )
+                })
+                .collect::<Vec<_>>();
+            replies.sort();
+
+            let mut expected = Vec::new();
+            for (i, target) in targets.iter().enumerate() {
+                let hops = [
+                    (Ipv4Addr::new(10, 0, 1, 1), 1),
+                    (Ipv4Addr::new(10, 0, 2, i as u8 + 1), 2),
+                    (*target, 5),
+                ];
+                for (ttl, (addr, rtt)) in hops.iter().enumerate() {
+                    expected.push((
+                        *target,
+                        ttl as u8 + 1,
+                        IpAddr::V4(*addr),
+                        Duration::from_millis(*rtt),
+                        addr == target,
+                    ));
+                }
+            }
+            assert_eq!(replies, expected);
+        }
+    }
+
+    #[test]
+    fn probes_single_ttl_if_first_hop_is_last() {
+        let mut config = test_config(Ipv4Addr::UNSPECIFIED, Method::Icmp);
+        config.first_hop_ttl = 3;
+        config.hops = 3;
+        let mut protocol: Box<dyn TracerouteProtocol> = Box::new(IcmpTraceroute::new());
+        protocol.set_io(Box::new(network(protocol.as_ref())));
+
+        let replies = trace_stateless(
+            &config,
+            protocol.as_mut(),
+            SOURCE,
+            &[Ipv4Addr::new(10, 0, 9, 1)],
+        );
+
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].ttl, 3);
+        assert!(replies[0].destination_reached);
+    }
+}